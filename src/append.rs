@@ -0,0 +1,82 @@
+//! Appending messages to files of concatenated msgpack values.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Spanned, Value};
+
+use crate::into::{self, EncodeOptions};
+use crate::{fs, repair};
+
+/// Encode the input and append it to a file of concatenated messages, after checking that the
+/// file ends on a message boundary. Each element of a list is appended as its own message.
+///
+/// Returns a `{path, bytes, messages}` record of the file, the number of bytes appended, and the
+/// number of messages in the file now.
+pub fn append(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let path: Spanned<String> = call.req(0)?;
+    let opts = EncodeOptions::from_call(call, config)?;
+    let span = call.head;
+
+    // encode everything first, so that a value that fails to encode leaves the file alone
+    let encoded = match input {
+        // --framed already writes each element as a frame of its own
+        Value::List { vals, .. } if !opts.framed => {
+            let mut encoded = vec![];
+            for (i, val) in vals.iter().enumerate() {
+                let message = into::to_msgpack(val, &opts).map_err(|mut e| {
+                    e.msg = format!("In message {i}: {}", e.msg);
+                    e
+                })?;
+                encoded.extend(message);
+            }
+            encoded
+        }
+        input => into::to_msgpack(input, &opts)?,
+    };
+    let added = match input {
+        Value::List { vals, .. } => vals.len(),
+        _ => 1,
+    };
+
+    let resolved = fs::resolve(&path.item);
+    let existing = match std::fs::read(&resolved) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(fs::io_error("read", &path, e)),
+    };
+    let (messages, _, damage) = match opts.framed {
+        true => repair::scan_frames(&existing),
+        false => repair::scan(&existing),
+    };
+    if let Some(damage) = damage {
+        return Err(LabeledError {
+            label: "Damaged file".into(),
+            msg: format!(
+                "The file doesn't end on a message boundary ({}), use msgpack repair --out to \
+                 salvage its complete messages first",
+                damage.reason
+            ),
+            span: Some(path.span),
+        });
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&resolved)
+        .map_err(|e| fs::io_error("open", &path, e))?;
+    file.write_all(&encoded)
+        .map_err(|e| fs::io_error("write", &path, e))?;
+
+    let mut record = Record::new();
+    record.push("path", Value::string(resolved.to_string_lossy(), span));
+    record.push("bytes", Value::filesize(encoded.len() as i64, span));
+    record.push("messages", Value::int((messages + added) as i64, span));
+    Ok(Value::record(record, span))
+}
@@ -0,0 +1,125 @@
+//! Numbers that don't fit in a nu int or float, carried in ext values.
+//!
+//! They're decoded to `{ext_type, kind, value}` records with the number as a string in `value`,
+//! which `to msgpack` encodes back to the ext value they came from.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{PluginSignature, Record, Span, SyntaxShape, Value};
+
+use crate::{ext, flags};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A decimal number written as a UTF-8 string, e.g. `-12.50`.
+    Decimal,
+
+    /// A 16 byte big-endian two's complement integer.
+    Int128,
+
+    /// A 16 byte big-endian unsigned integer.
+    Uint128,
+}
+
+pub const KINDS: &[(&str, Kind)] = &[
+    ("decimal", Kind::Decimal),
+    ("int128", Kind::Int128),
+    ("uint128", Kind::Uint128),
+];
+
+impl Kind {
+    fn name(self) -> &'static str {
+        KINDS
+            .iter()
+            .find(|(_, kind)| *kind == self)
+            .map(|(name, _)| *name)
+            .expect("every kind is in KINDS")
+    }
+
+    fn flag(self) -> String {
+        format!("{}-ext", self.name())
+    }
+
+    /// Convert an ext payload to the string form of the number.
+    fn format(self, data: &[u8]) -> Result<String, String> {
+        let wide = || {
+            <[u8; 16]>::try_from(data).map_err(|_| format!("expected 16 bytes, got {}", data.len()))
+        };
+        Ok(match self {
+            Kind::Decimal => {
+                let decimal = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+                if !ext::is_decimal(decimal) {
+                    return Err(format!("{decimal:?} is not a decimal"));
+                }
+                decimal.to_string()
+            }
+            Kind::Int128 => i128::from_be_bytes(wide()?).to_string(),
+            Kind::Uint128 => u128::from_be_bytes(wide()?).to_string(),
+        })
+    }
+
+    /// Convert the string form of a number to an ext payload, if it's valid for this kind.
+    fn parse(self, value: &str) -> Option<Vec<u8>> {
+        match self {
+            Kind::Decimal => ext::is_decimal(value).then(|| value.as_bytes().to_vec()),
+            Kind::Int128 => value.parse::<i128>().ok().map(|n| n.to_be_bytes().to_vec()),
+            Kind::Uint128 => value.parse::<u128>().ok().map(|n| n.to_be_bytes().to_vec()),
+        }
+    }
+}
+
+/// The ext type ids given with `--decimal-ext`, `--int128-ext` and `--uint128-ext`, or their
+/// defaults in the plugin config.
+pub fn ext_flags(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+) -> Result<Vec<(i8, Kind)>, LabeledError> {
+    let mut ids = vec![];
+    for &(_, kind) in KINDS {
+        if let Some(id) = flags::ext_id_or_config(call, config, &kind.flag())? {
+            ids.push((id, kind));
+        }
+    }
+    Ok(ids)
+}
+
+/// Add the `--<kind>-ext` flags to a signature, with `description` saying what they do.
+pub fn signature_flags(mut sig: PluginSignature, description: &str) -> PluginSignature {
+    for &(name, kind) in KINDS {
+        sig = sig.named(
+            kind.flag(),
+            SyntaxShape::Int,
+            format!("ext type id of {name} numbers, {description}"),
+            None,
+        );
+    }
+    sig
+}
+
+/// Decode an ext payload to a `{ext_type, kind, value}` record.
+pub fn decode(ext_type: i8, kind: Kind, data: &[u8]) -> Result<Value, LabeledError> {
+    let value = kind.format(data).map_err(|msg| LabeledError {
+        label: "Invalid ext value".into(),
+        msg: format!("Failed to decode ext value as {}: {msg}", kind.name()),
+        span: None,
+    })?;
+
+    let span = Span::unknown();
+    let mut record = Record::new();
+    record.push("ext_type", Value::int(ext_type.into(), span));
+    record.push("kind", Value::string(kind.name(), span));
+    record.push("value", Value::string(value, span));
+    Ok(Value::record(record, span))
+}
+
+/// Encode a `{ext_type, kind, value}` record produced by [decode].
+pub fn encode_record(record: &Record) -> Option<(i8, Vec<u8>)> {
+    if record.len() != 3 {
+        return None;
+    }
+
+    let ext_type = i8::try_from(record.get("ext_type")?.as_int().ok()?).ok()?;
+    let kind = record.get("kind")?.as_string().ok()?;
+    let kind = flags::parse_choice("kind", &kind, KINDS).ok()?;
+    let data = kind.parse(&record.get("value")?.as_string().ok()?)?;
+    Some((ext_type, data))
+}
@@ -0,0 +1,206 @@
+//! Bundles: several named parts shipped as a single msgpack document.
+//!
+//! A bundle is a msgpack map with two entries:
+//! - `manifest`: a map with a `version` (currently 1) and a `parts` array, where every part is
+//!   described by a map of its unique `name`, `encoding` (`msgpack` or `raw`) and `size` in
+//!   bytes.
+//! - `parts`: a map from part name to the bin value holding that part.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+
+use crate::{from, into};
+
+const BUNDLE_VERSION: u64 = 1;
+
+/// Pack a record of parts into a bundle.
+///
+/// Binary values are stored as-is, everything else is encoded as msgpack.
+pub fn pack(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let opts = into::EncodeOptions::from_call(call, config)?;
+    let parts = input.as_record()?;
+
+    let mut manifest = vec![];
+    let mut data = vec![];
+    for (name, value) in parts {
+        let (encoding, bytes) = match value {
+            Value::Binary { val, .. } => ("raw", val.clone()),
+            value => ("msgpack", into::to_msgpack(value, &opts)?),
+        };
+
+        manifest.push(rmpv::Value::Map(vec![
+            ("name".into(), name.as_str().into()),
+            ("encoding".into(), encoding.into()),
+            ("size".into(), bytes.len().into()),
+        ]));
+        data.push((name.as_str().into(), rmpv::Value::Binary(bytes)));
+    }
+
+    let bundle = rmpv::Value::Map(vec![
+        (
+            "manifest".into(),
+            rmpv::Value::Map(vec![
+                ("version".into(), BUNDLE_VERSION.into()),
+                ("parts".into(), rmpv::Value::Array(manifest)),
+            ]),
+        ),
+        ("parts".into(), rmpv::Value::Map(data)),
+    ]);
+
+    Ok(Value::binary(into::encode(&bundle), call.head))
+}
+
+/// Unpack a bundle into a record of parts.
+///
+/// Parts encoded as msgpack are decoded, unless `--raw` is passed.
+pub fn unpack(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let opts = from::DecodeOptions::from_call(call, config)?;
+    let raw = call.has_flag("raw")?;
+
+    let mut bin = input.as_binary()?;
+    // apply --max-depth and --max-size to the manifest too, and reject trailing bytes
+    from::check(bin, &opts).map_err(|e| LabeledError {
+        span: e.span.or(Some(input.span())),
+        ..e
+    })?;
+    let bundle = rmpv::decode::read_value_ref(&mut bin).map_err(|e| LabeledError {
+        label: "Invalid msgpack".into(),
+        msg: e.to_string(),
+        span: Some(input.span()),
+    })?;
+
+    let version = get(&bundle, "manifest")
+        .and_then(|manifest| get(manifest, "version"))
+        .and_then(|version| version.as_u64());
+    if version != Some(BUNDLE_VERSION) {
+        return Err(invalid_bundle(match version {
+            Some(v) => format!("unsupported bundle version {v}"),
+            None => "missing manifest version".into(),
+        }));
+    }
+
+    let manifest = get(&bundle, "manifest")
+        .and_then(|manifest| get(manifest, "parts"))
+        .and_then(|parts| parts.as_array())
+        .ok_or_else(|| invalid_bundle("missing manifest parts".into()))?;
+    let parts = get(&bundle, "parts").ok_or_else(|| invalid_bundle("missing parts".into()))?;
+
+    let mut record = Record::new();
+    for part in manifest {
+        let name = get(part, "name")
+            .and_then(as_str)
+            .ok_or_else(|| invalid_bundle("manifest part without a name".into()))?;
+        if record.contains(name) {
+            return Err(invalid_bundle(format!("duplicate part {name:?}")));
+        }
+        let encoding = get(part, "encoding").and_then(as_str);
+        let data = match get(parts, name) {
+            Some(rmpv::ValueRef::Binary(data)) => *data,
+            _ => return Err(invalid_bundle(format!("missing data for part {name:?}"))),
+        };
+
+        let value = match encoding {
+            Some("msgpack") if !raw => from::decode(data, &opts)?,
+            Some("msgpack" | "raw") => Value::binary(data, call.head),
+            e => {
+                return Err(invalid_bundle(format!(
+                    "unknown encoding {e:?} of part {name:?}"
+                )))
+            }
+        };
+        record.push(name, value);
+    }
+
+    Ok(Value::record(record, call.head))
+}
+
+/// Look up a string key in a msgpack map.
+fn get<'a, 'b>(map: &'b rmpv::ValueRef<'a>, key: &str) -> Option<&'b rmpv::ValueRef<'a>> {
+    match map {
+        rmpv::ValueRef::Map(entries) => entries
+            .iter()
+            .find(|(k, _)| as_str(k) == Some(key))
+            .map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+fn as_str<'a>(value: &'a rmpv::ValueRef<'_>) -> Option<&'a str> {
+    match value {
+        rmpv::ValueRef::String(s) => s.as_str(),
+        _ => None,
+    }
+}
+
+fn invalid_bundle(msg: String) -> LabeledError {
+    LabeledError {
+        label: "Invalid bundle".into(),
+        msg,
+        span: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::{Span, Spanned};
+
+    use super::*;
+
+    fn call(named: Vec<(&str, Value)>) -> EvaluatedCall {
+        let span = Span::unknown();
+        EvaluatedCall {
+            head: span,
+            positional: vec![],
+            named: named
+                .into_iter()
+                .map(|(name, value)| {
+                    let name = Spanned {
+                        item: name.to_string(),
+                        span,
+                    };
+                    (name, Some(value))
+                })
+                .collect(),
+        }
+    }
+
+    fn parts() -> Value {
+        let span = Span::unknown();
+        let mut parts = Record::new();
+        parts.push("schema", Value::string("v1", span));
+        parts.push(
+            "data",
+            Value::list(vec![Value::int(1, span), Value::int(2, span)], span),
+        );
+        parts.push("attachment", Value::binary(vec![0xff; 8], span));
+        Value::record(parts, span)
+    }
+
+    #[test]
+    fn round_trip() {
+        let bundle = pack(&call(vec![]), &None, &parts()).unwrap();
+        assert_eq!(unpack(&call(vec![]), &None, &bundle).unwrap(), parts());
+    }
+
+    #[test]
+    fn limits() {
+        let span = Span::unknown();
+        let bundle = pack(&call(vec![]), &None, &parts()).unwrap();
+        let max_size = call(vec![("max-size", Value::filesize(4, span))]);
+        let e = unpack(&max_size, &None, &bundle).unwrap_err();
+        assert_eq!(e.label, "Value too large");
+
+        let mut trailing = bundle.as_binary().unwrap().to_vec();
+        trailing.push(0xc0);
+        let e = unpack(&call(vec![]), &None, &Value::binary(trailing, span)).unwrap_err();
+        assert_eq!(e.label, "Trailing bytes");
+    }
+}
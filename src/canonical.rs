@@ -0,0 +1,163 @@
+//! Checking that msgpack input is in canonical form with `from msgpack --canonical-check`, e.g.
+//! before accepting third-party payloads into a content-addressed store.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Value};
+
+use crate::flags;
+use crate::wire::{Kind, Reader, Role, Walker};
+
+#[derive(Clone, Copy)]
+enum CheckMode {
+    /// Fail the decode if the input isn't canonical.
+    Error,
+
+    /// Return `{value, findings}`.
+    Report,
+}
+
+/// A way in which the input differs from its canonical form.
+pub struct Finding {
+    /// Offset of the value that isn't canonical.
+    offset: usize,
+    path: String,
+    reason: String,
+}
+
+/// Read `--canonical-check`, and if it's given, check `data`.
+///
+/// Returns the findings to add to the output with `report`, and fails with `error` if there are
+/// any.
+pub fn check_flag(
+    call: &EvaluatedCall,
+    data: &[u8],
+    span: Span,
+) -> Result<Option<Vec<Finding>>, LabeledError> {
+    let mode = flags::choice_flag(
+        call,
+        "canonical-check",
+        &[("error", CheckMode::Error), ("report", CheckMode::Report)],
+    )?;
+    let Some(mode) = mode else {
+        return Ok(None);
+    };
+
+    let findings = check(data);
+    match mode {
+        CheckMode::Report => Ok(Some(findings)),
+        CheckMode::Error if findings.is_empty() => Ok(None),
+        CheckMode::Error => {
+            let mut lines: Vec<_> = findings
+                .iter()
+                .take(5)
+                .map(|f| format!("byte {} at {}: {}", f.offset, f.path, f.reason))
+                .collect();
+            if findings.len() > 5 {
+                lines.push(format!("and {} more", findings.len() - 5));
+            }
+            Err(LabeledError {
+                label: "Non-canonical msgpack".into(),
+                msg: lines.join("\n"),
+                span: Some(span),
+            })
+        }
+    }
+}
+
+/// The `{value, findings}` record that `--canonical-check report` returns, with a
+/// `{offset, path, reason}` record per finding.
+pub fn report(value: Value, findings: Vec<Finding>, span: Span) -> Value {
+    let findings = findings
+        .into_iter()
+        .map(|finding| {
+            let mut record = Record::new();
+            record.push("offset", Value::int(finding.offset as i64, span));
+            record.push("path", Value::string(finding.path, span));
+            record.push("reason", Value::string(finding.reason, span));
+            Value::record(record, span)
+        })
+        .collect();
+
+    let mut record = Record::new();
+    record.push("value", value);
+    record.push("findings", Value::list(findings, span));
+    Value::record(record, span)
+}
+
+/// Find the values of `data` that `to msgpack --canonical` would encode differently: headers that
+/// are bigger than needed, map keys that aren't sorted by their encoded bytes or that repeat, and
+/// NaN floats other than the one canonical NaN.
+///
+/// Invalid msgpack ends the check, and is left for the decoder to report.
+pub fn check(data: &[u8]) -> Vec<Finding> {
+    let mut findings = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let mut walker = Walker::new(Reader::at(data, offset));
+        // encoded bytes of the previous key of the map at each depth
+        let mut last_keys: Vec<&[u8]> = vec![];
+        loop {
+            let node = match walker.next() {
+                Some(Ok(node)) => node,
+                Some(Err(_)) => return findings,
+                None => break,
+            };
+            let header = node.header;
+            let mut reasons = vec![];
+
+            if let Some(minimal) = header.minimal_marker() {
+                reasons.push(format!(
+                    "non-minimal encoding: {} used where {minimal} suffices",
+                    header.type_name()
+                ));
+            }
+
+            let float = |len: usize| data.get(header.offset + 1..header.offset + 1 + len);
+            match (header.kind, float(8), float(4)) {
+                (Kind::F64, Some(bits), _) => {
+                    let float = f64::from_be_bytes(bits.try_into().expect("f64 has 8 bytes"));
+                    if float.is_nan() && float.to_bits() != f64::NAN.to_bits() {
+                        reasons.push("NaN isn't encoded as the canonical NaN".into());
+                    }
+                }
+                (Kind::F32, _, Some(bits)) => {
+                    let float = f32::from_be_bytes(bits.try_into().expect("f32 has 4 bytes"));
+                    if float.is_nan() {
+                        reasons.push("NaN isn't encoded as the canonical float64 NaN".into());
+                    }
+                }
+                _ => {}
+            }
+
+            if let Role::Key(i) = node.role {
+                let mut key_reader = Reader::at(data, header.offset);
+                if key_reader.skip_value().is_err() {
+                    return findings;
+                }
+                let key = &data[header.offset..key_reader.pos()];
+                last_keys.resize(last_keys.len().max(node.depth + 1), &[]);
+                let last = std::mem::replace(&mut last_keys[node.depth], key);
+                if i > 0 && key < last {
+                    reasons.push("map keys are not sorted by their encoded bytes".into());
+                } else if i > 0 && key == last {
+                    reasons.push("duplicate map key".into());
+                }
+            }
+
+            if reasons.is_empty() {
+                continue;
+            }
+            let mut path = walker.path().to_string();
+            if let Role::Key(_) = node.role {
+                path = format!("key {} of {path}", node.key_name());
+            }
+            findings.extend(reasons.into_iter().map(|reason| Finding {
+                offset: header.offset,
+                path: path.clone(),
+                reason,
+            }));
+        }
+        offset = walker.reader().pos();
+    }
+    findings
+}
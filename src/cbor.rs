@@ -0,0 +1,601 @@
+//! Transcoding between msgpack and [CBOR](https://www.rfc-editor.org/rfc/rfc8949) at the wire
+//! level, without converting to nu values.
+//!
+//! Both formats have the same basic types, so most values map one to one. The rest map like
+//! this:
+//!
+//! | msgpack                  | CBOR                                                       |
+//! |--------------------------|------------------------------------------------------------|
+//! | timestamp (ext type -1)  | tag 1 (epoch seconds) of an int for whole seconds          |
+//! | timestamp (ext type -1)  | tag 0 (RFC 3339 string) with a fraction of nanoseconds     |
+//! | ext type `t`             | tag `EXT_TAG_BASE + (t as u8)` of a byte string            |
+//! | int                      | tags 2 and 3 (bignums) that fit into 64 bits               |
+//! | timestamp (ext type -1)  | tag 1 (epoch seconds) of a float                           |
+//! | nil                      | undefined                                                  |
+//! | float32                  | half-precision float                                       |
+//!
+//! The last four only apply from CBOR to msgpack. Other tags are dropped, keeping the value
+//! they tag.
+
+use std::io::{self, Read, Write};
+
+use chrono::{Datelike, SecondsFormat, Utc};
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::Value;
+use rmp::encode;
+
+use crate::wire::{Header, Kind, StreamReader};
+use crate::{from, into};
+
+/// First of the 256 tags that msgpack ext types are mapped to. Tags this high are first come,
+/// first served in the IANA registry, and this range isn't registered.
+pub const EXT_TAG_BASE: u64 = 0x6d70_0000;
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+/// Additional information of items of indefinite length, and of the break that ends them.
+const INDEFINITE: u8 = 31;
+
+/// `msgpack to-cbor`: convert msgpack input to CBOR.
+pub fn to_cbor(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let bin = input.as_binary()?;
+    let mut cbor = vec![];
+    msgpack_to_cbor(bin, &mut cbor).map_err(|e| LabeledError {
+        label: "Failed to convert msgpack to CBOR".into(),
+        msg: e.to_string(),
+        span: Some(input.span()),
+    })?;
+    Ok(Value::binary(cbor, call.head))
+}
+
+/// `msgpack from-cbor`: convert CBOR input to msgpack.
+pub fn from_cbor(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let cbor = input.as_binary()?;
+    let mut decoder = CborReader { data: cbor, pos: 0 };
+    let mut msgpack = vec![];
+    decoder
+        .item(&mut msgpack, 0)
+        .and_then(|()| match decoder.pos < cbor.len() {
+            true => Err(format!(
+                "the input has more data after the value that ends at byte {}",
+                decoder.pos
+            )),
+            false => Ok(()),
+        })
+        .map_err(|msg| LabeledError {
+            label: "Failed to convert CBOR to msgpack".into(),
+            msg,
+            span: Some(input.span()),
+        })?;
+    Ok(Value::binary(msgpack, call.head))
+}
+
+/// Write the single msgpack value of the input as CBOR.
+pub fn msgpack_to_cbor<R: Read, W: Write>(r: R, w: &mut W) -> io::Result<()> {
+    let mut r = StreamReader::new(r);
+    let header = r
+        .read_header()?
+        .ok_or_else(|| invalid_data("the input is empty".into()))?;
+    let mut buf = vec![];
+    write_cbor(&mut r, w, header, &mut buf, 0)?;
+
+    let end = r.pos();
+    if r.read_header()?.is_some() {
+        return Err(invalid_data(format!(
+            "the input has more data after the value that ends at byte {end}"
+        )));
+    }
+    Ok(())
+}
+
+fn write_cbor<R: Read, W: Write>(
+    r: &mut StreamReader<R>,
+    w: &mut W,
+    header: Header,
+    buf: &mut Vec<u8>,
+    depth: usize,
+) -> io::Result<()> {
+    if depth > from::DEFAULT_MAX_DEPTH {
+        return Err(invalid_data(format!(
+            "the value at byte {} is nested more than {} levels deep",
+            header.offset,
+            from::DEFAULT_MAX_DEPTH
+        )));
+    }
+
+    match header.kind {
+        Kind::Nil => w.write_all(&[0xf6])?,
+        Kind::Bool(false) => w.write_all(&[0xf4])?,
+        Kind::Bool(true) => w.write_all(&[0xf5])?,
+        Kind::Uint(u) => write_head(w, UNSIGNED, u)?,
+        Kind::Int(i) if i >= 0 => write_head(w, UNSIGNED, i as u64)?,
+        // CBOR stores negative integers as -1 - n.
+        Kind::Int(i) => write_head(w, NEGATIVE, !i as u64)?,
+        Kind::F32 => {
+            w.write_all(&[0xfa])?;
+            w.write_all(&(r.float(&header) as f32).to_be_bytes())?
+        }
+        Kind::F64 => {
+            w.write_all(&[0xfb])?;
+            w.write_all(&r.float(&header).to_be_bytes())?
+        }
+        Kind::Str(len) => {
+            r.read_data(len as usize, buf)?;
+            write_head(w, TEXT, len.into())?;
+            w.write_all(buf)?
+        }
+        Kind::Bin(len) => {
+            r.read_data(len as usize, buf)?;
+            write_head(w, BYTES, len.into())?;
+            w.write_all(buf)?
+        }
+        Kind::Ext(-1, len) => {
+            r.read_data(len as usize, buf)?;
+            let date = from::ext_timestamp_to_nu(buf)
+                .and_then(|date| Ok(date.as_date()?))
+                .map_err(|e| invalid_data(format!("at byte {}: {}", header.offset, e.msg)))?;
+            let date = date.with_timezone(&Utc);
+            match date.timestamp_subsec_nanos() {
+                0 => {
+                    write_head(w, TAG, 1)?;
+                    match date.timestamp() {
+                        seconds if seconds >= 0 => write_head(w, UNSIGNED, seconds as u64)?,
+                        seconds => write_head(w, NEGATIVE, !seconds as u64)?,
+                    }
+                }
+                // a float of epoch seconds would only keep about microseconds
+                _ if (0..=9999).contains(&date.year()) => {
+                    let text = date.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+                    write_head(w, TAG, 0)?;
+                    write_head(w, TEXT, text.len() as u64)?;
+                    w.write_all(text.as_bytes())?
+                }
+                // RFC 3339 only has four digit years
+                nanos => {
+                    let seconds = date.timestamp() as f64 + f64::from(nanos) / 1e9;
+                    write_head(w, TAG, 1)?;
+                    w.write_all(&[0xfb])?;
+                    w.write_all(&seconds.to_be_bytes())?
+                }
+            }
+        }
+        Kind::Ext(ext_type, len) => {
+            r.read_data(len as usize, buf)?;
+            write_head(w, TAG, EXT_TAG_BASE + u64::from(ext_type as u8))?;
+            write_head(w, BYTES, len.into())?;
+            w.write_all(buf)?
+        }
+        Kind::Array(len) => {
+            write_head(w, ARRAY, len.into())?;
+            for _ in 0..len {
+                let element = next_header(r)?;
+                write_cbor(r, w, element, buf, depth + 1)?;
+            }
+        }
+        Kind::Map(len) => {
+            write_head(w, MAP, len.into())?;
+            for _ in 0..u64::from(len) * 2 {
+                let entry = next_header(r)?;
+                write_cbor(r, w, entry, buf, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the head of a CBOR item: its major type and argument, in the smallest form possible.
+fn write_head<W: Write>(w: &mut W, major: u8, argument: u64) -> io::Result<()> {
+    let major = major << 5;
+    match argument {
+        0..=23 => w.write_all(&[major | argument as u8]),
+        24..=0xff => w.write_all(&[major | 24, argument as u8]),
+        0x100..=0xffff => {
+            w.write_all(&[major | 25])?;
+            w.write_all(&(argument as u16).to_be_bytes())
+        }
+        0x1_0000..=0xffff_ffff => {
+            w.write_all(&[major | 26])?;
+            w.write_all(&(argument as u32).to_be_bytes())
+        }
+        _ => {
+            w.write_all(&[major | 27])?;
+            w.write_all(&argument.to_be_bytes())
+        }
+    }
+}
+
+/// Read the header of the next element or entry of a container.
+fn next_header<R: Read>(r: &mut StreamReader<R>) -> io::Result<Header> {
+    let pos = r.pos();
+    r.read_header()?.ok_or_else(|| {
+        invalid_data(format!(
+            "unexpected end of input at byte {pos}, expected more values"
+        ))
+    })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The head of a CBOR item.
+struct Head {
+    /// Byte offset of the item.
+    offset: usize,
+
+    major: u8,
+
+    /// The additional information, the low 5 bits of the first byte.
+    info: u8,
+
+    /// The argument, e.g. the value of an integer or the length of a string. `None` for items
+    /// of indefinite length.
+    argument: Option<u64>,
+}
+
+/// Reads CBOR items from a byte slice and writes them as msgpack.
+struct CborReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CborReader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let data = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| {
+                self.error(format!(
+                    "unexpected end of input, expected {} more bytes",
+                    len.saturating_sub(self.data.len() - self.pos)
+                ))
+            })?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.read(N)?.try_into().expect("slice has correct len"))
+    }
+
+    fn read_head(&mut self) -> Result<Head, String> {
+        let offset = self.pos;
+        let [byte] = self.read_array()?;
+        let (major, info) = (byte >> 5, byte & 0x1f);
+        let argument = match info {
+            0..=23 => Some(info.into()),
+            24 => Some(u8::from_be_bytes(self.read_array()?).into()),
+            25 => Some(u16::from_be_bytes(self.read_array()?).into()),
+            26 => Some(u32::from_be_bytes(self.read_array()?).into()),
+            27 => Some(u64::from_be_bytes(self.read_array()?)),
+            INDEFINITE if matches!(major, BYTES | TEXT | ARRAY | MAP | SIMPLE) => None,
+            _ => {
+                return Err(self.error_at(
+                    offset,
+                    format!("invalid additional information {info} for major type {major}"),
+                ))
+            }
+        };
+        Ok(Head {
+            offset,
+            major,
+            info,
+            argument,
+        })
+    }
+
+    /// Check if the next byte is the break that ends an item of indefinite length, and skip
+    /// it if so.
+    fn at_break(&mut self) -> Result<bool, String> {
+        match self.data.get(self.pos) {
+            Some(0xff) => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(self.error("unexpected end of input, expected a break".into())),
+        }
+    }
+
+    /// Convert the next item to msgpack.
+    fn item(&mut self, w: &mut Vec<u8>, depth: usize) -> Result<(), String> {
+        let head = self.read_head()?;
+        self.item_with_head(head, w, depth)
+    }
+
+    fn item_with_head(&mut self, head: Head, w: &mut Vec<u8>, depth: usize) -> Result<(), String> {
+        if depth > from::DEFAULT_MAX_DEPTH {
+            return Err(self.error_at(
+                head.offset,
+                format!(
+                    "the item is nested more than {} levels deep",
+                    from::DEFAULT_MAX_DEPTH
+                ),
+            ));
+        }
+
+        match (head.major, head.argument) {
+            (UNSIGNED, Some(n)) => {
+                encode::write_uint(w, n).map_err(|e| e.to_string())?;
+            }
+            (NEGATIVE, Some(n)) => {
+                let n = i64::try_from(n).map_err(|_| {
+                    self.error_at(head.offset, format!("-1 - {n} is too small for msgpack"))
+                })?;
+                encode::write_sint(w, -1 - n).map_err(|e| e.to_string())?;
+            }
+            (BYTES, _) => {
+                let bytes = self.string(&head)?;
+                encode::write_bin(w, &bytes).map_err(|e| e.to_string())?
+            }
+            (TEXT, _) => {
+                let bytes = self.string(&head)?;
+                let text = std::str::from_utf8(&bytes).map_err(|e| {
+                    self.error_at(head.offset, format!("the text is not valid UTF-8: {e}"))
+                })?;
+                encode::write_str(w, text).map_err(|e| e.to_string())?
+            }
+            (ARRAY, len) => {
+                let (len, elements) = self.items(len, 1, depth)?;
+                encode::write_array_len(w, len).map_err(|e| e.to_string())?;
+                w.extend_from_slice(&elements);
+            }
+            (MAP, len) => {
+                let (len, entries) = self.items(len, 2, depth)?;
+                encode::write_map_len(w, len).map_err(|e| e.to_string())?;
+                w.extend_from_slice(&entries);
+            }
+            (TAG, Some(tag)) => self.tagged(head.offset, tag, w, depth)?,
+            (SIMPLE, _) => match head.info {
+                20 => encode::write_bool(w, false).map_err(|e| e.to_string())?,
+                21 => encode::write_bool(w, true).map_err(|e| e.to_string())?,
+                // null and undefined
+                22 | 23 => encode::write_nil(w).map_err(|e| e.to_string())?,
+                25 => {
+                    let half = head.argument.expect("read by read_head") as u16;
+                    encode::write_f32(w, f16_to_f32(half)).map_err(|e| e.to_string())?
+                }
+                26 => {
+                    let bits = head.argument.expect("read by read_head") as u32;
+                    encode::write_f32(w, f32::from_bits(bits)).map_err(|e| e.to_string())?
+                }
+                27 => {
+                    let bits = head.argument.expect("read by read_head");
+                    encode::write_f64(w, f64::from_bits(bits)).map_err(|e| e.to_string())?
+                }
+                INDEFINITE => return Err(self.error_at(head.offset, "unexpected break".into())),
+                _ => {
+                    return Err(self.error_at(
+                        head.offset,
+                        format!(
+                            "simple value {} has no msgpack equivalent",
+                            head.argument.unwrap_or_default()
+                        ),
+                    ))
+                }
+            },
+            _ => {
+                unreachable!("read_head only allows indefinite lengths for strings and containers")
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a byte or text string, joining the chunks of one of indefinite length.
+    fn string(&mut self, head: &Head) -> Result<Vec<u8>, String> {
+        if let Some(len) = head.argument {
+            return Ok(self.read(len as usize)?.to_vec());
+        }
+
+        let mut bytes = vec![];
+        while !self.at_break()? {
+            let chunk = self.read_head()?;
+            match (chunk.major, chunk.argument) {
+                (major, Some(len)) if major == head.major => {
+                    bytes.extend_from_slice(self.read(len as usize)?)
+                }
+                _ => {
+                    return Err(self.error_at(
+                        chunk.offset,
+                        "chunks of a string of indefinite length must be definite strings of \
+                         the same type"
+                            .into(),
+                    ))
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Convert the elements of an array, or the keys and values of a map with `per_entry` 2.
+    ///
+    /// Returns the number of entries, which isn't known up front for items of indefinite
+    /// length, and their msgpack.
+    fn items(
+        &mut self,
+        len: Option<u64>,
+        per_entry: u64,
+        depth: usize,
+    ) -> Result<(u32, Vec<u8>), String> {
+        let mut items = vec![];
+        let mut count = 0u64;
+        loop {
+            match len {
+                Some(len) if count == len => break,
+                None if self.at_break()? => break,
+                _ => {}
+            }
+            for _ in 0..per_entry {
+                self.item(&mut items, depth + 1)?;
+            }
+            count += 1;
+        }
+        let count = u32::try_from(count)
+            .map_err(|_| self.error(format!("{count} entries are too many for msgpack")))?;
+        Ok((count, items))
+    }
+
+    /// Convert a tagged item.
+    fn tagged(
+        &mut self,
+        offset: usize,
+        tag: u64,
+        w: &mut Vec<u8>,
+        depth: usize,
+    ) -> Result<(), String> {
+        let head = self.read_head()?;
+        match (tag, head.major, head.argument) {
+            // Epoch-based date/time.
+            (1, UNSIGNED, Some(seconds)) => {
+                let seconds = i64::try_from(seconds)
+                    .map_err(|_| self.error_at(offset, "the date is out of range".into()))?;
+                write_timestamp(w, seconds, 0)
+            }
+            (1, NEGATIVE, Some(n)) => {
+                let n = i64::try_from(n)
+                    .map_err(|_| self.error_at(offset, "the date is out of range".into()))?;
+                write_timestamp(w, -1 - n, 0)
+            }
+            (1, SIMPLE, Some(bits)) if matches!(head.info, 25..=27) => {
+                let seconds = match head.info {
+                    25 => f16_to_f32(bits as u16).into(),
+                    26 => f32::from_bits(bits as u32).into(),
+                    _ => f64::from_bits(bits),
+                };
+                if !seconds.is_finite() {
+                    return Err(self.error_at(offset, "the date is not finite".into()));
+                }
+                let whole = seconds.floor();
+                let nanos = ((seconds - whole) * 1e9).round().min(999_999_999.0) as u32;
+                write_timestamp(w, whole as i64, nanos)
+            }
+            // Standard date/time string.
+            (0, TEXT, _) => {
+                let text = self.string(&head)?;
+                let date = std::str::from_utf8(&text)
+                    .ok()
+                    .and_then(|text| chrono::DateTime::parse_from_rfc3339(text).ok())
+                    .ok_or_else(|| {
+                        self.error_at(offset, "the date is not an RFC 3339 string".into())
+                    })?;
+                write_timestamp(w, date.timestamp(), date.timestamp_subsec_nanos())
+            }
+            // Bignums, which are fine as long as they fit.
+            (2 | 3, BYTES, _) => {
+                let bytes = self.string(&head)?;
+                let digits = bytes.iter().skip_while(|b| **b == 0).count();
+                let n = match digits {
+                    0..=8 => bytes.iter().fold(0u64, |n, b| n << 8 | u64::from(*b)),
+                    _ => {
+                        return Err(
+                            self.error_at(offset, "the bignum is too big for msgpack".into())
+                        )
+                    }
+                };
+                match tag {
+                    2 => encode::write_uint(w, n).map(drop),
+                    _ => match i64::try_from(n) {
+                        Ok(n) => encode::write_sint(w, -1 - n).map(drop),
+                        Err(_) => {
+                            return Err(
+                                self.error_at(offset, "the bignum is too small for msgpack".into())
+                            )
+                        }
+                    },
+                }
+                .map_err(|e| e.to_string())
+            }
+            (tag, BYTES, _) if (EXT_TAG_BASE..EXT_TAG_BASE + 256).contains(&tag) => {
+                let data = self.string(&head)?;
+                let ext_type = (tag - EXT_TAG_BASE) as u8 as i8;
+                encode::write_ext_meta(w, data.len() as u32, ext_type)
+                    .map(drop)
+                    .map_err(|e| e.to_string())?;
+                w.extend_from_slice(&data);
+                Ok(())
+            }
+            (0..=3, ..) => Err(self.error_at(
+                offset,
+                format!("tag {tag} can't tag an item of major type {}", head.major),
+            )),
+            // Other tags don't have a msgpack equivalent, so only keep what they tag.
+            _ => self.item_with_head(head, w, depth + 1),
+        }
+    }
+
+    fn error(&self, msg: String) -> String {
+        self.error_at(self.pos, msg)
+    }
+
+    fn error_at(&self, offset: usize, msg: String) -> String {
+        format!("{msg} (at byte {offset})")
+    }
+}
+
+fn write_timestamp(w: &mut Vec<u8>, seconds: i64, nanos: u32) -> Result<(), String> {
+    let data = into::timestamp_data(seconds, nanos);
+    encode::write_ext_meta(w, data.len() as u32, -1).map_err(|e| e.to_string())?;
+    w.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Convert a half-precision float to single precision.
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((half >> 10) & 0x1f);
+    let mantissa = f32::from(half & 0x3ff);
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_cbor(cbor: &[u8]) -> Result<Vec<u8>, String> {
+        let mut msgpack = vec![];
+        CborReader { data: cbor, pos: 0 }.item(&mut msgpack, 0)?;
+        Ok(msgpack)
+    }
+
+    fn round_trip(msgpack: &[u8]) -> Vec<u8> {
+        let mut cbor = vec![];
+        msgpack_to_cbor(msgpack, &mut cbor).unwrap();
+        from_cbor(&cbor).unwrap()
+    }
+
+    #[test]
+    fn values() {
+        // {"a": [1, -2, 0.5, true, nil, h'ff', ext 5 of h'01']}
+        let msgpack =
+            b"\x81\xa1a\x97\x01\xfe\xcb\x3f\xe0\0\0\0\0\0\0\xc3\xc0\xc4\x01\xff\xd4\x05\x01";
+        assert_eq!(round_trip(msgpack), msgpack);
+    }
+
+    #[test]
+    fn timestamps() {
+        for (seconds, nanos) in [(1_700_000_000, 0), (1_700_000_000, 123_456_789), (-1, 1)] {
+            let msgpack = into::encode(&rmpv::Value::Ext(-1, into::timestamp_data(seconds, nanos)));
+            assert_eq!(round_trip(&msgpack), msgpack, "{seconds}.{nanos:09}");
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        // a byte string claiming u64::MAX bytes
+        let e =
+            from_cbor(&[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]).unwrap_err();
+        assert!(e.starts_with("unexpected end of input"), "{e}");
+    }
+}
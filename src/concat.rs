@@ -0,0 +1,86 @@
+//! Combining msgpack documents into one array without decoding them.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Spanned, Value};
+use rmp::encode;
+
+use crate::wire::{Kind, Reader};
+use crate::{fs, repair};
+
+/// Combine the msgpack binaries of the input, a binary or a list of them, and the files given as
+/// arguments into a single array of all their values.
+///
+/// Only the array header is written, the values are copied byte for byte. A document that holds
+/// several concatenated values contributes all of them, and with `--flatten`, a document that is
+/// a single array contributes its elements.
+pub fn concat(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let flatten = call.has_flag("flatten")?;
+
+    let mut docs: Vec<Spanned<&[u8]>> = vec![];
+    match input {
+        Value::Nothing { .. } => {}
+        Value::List { vals, .. } => {
+            for val in vals {
+                docs.push(Spanned {
+                    item: val.as_binary()?,
+                    span: val.span(),
+                });
+            }
+        }
+        input => docs.push(Spanned {
+            item: input.as_binary()?,
+            span: input.span(),
+        }),
+    }
+    let paths: Vec<Spanned<String>> = call.rest(0)?;
+    let files = paths
+        .iter()
+        .map(|path| {
+            std::fs::read(fs::resolve(&path.item)).map_err(|e| fs::io_error("read", path, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    docs.extend(files.iter().zip(&paths).map(|(file, path)| Spanned {
+        item: file.as_slice(),
+        span: path.span,
+    }));
+
+    // the payload of each document that goes into the array, and how many values it holds
+    let mut parts: Vec<(&[u8], usize)> = vec![];
+    for (i, doc) in docs.iter().enumerate() {
+        let (values, _, damage) = repair::scan(doc.item);
+        if let Some(damage) = damage {
+            return Err(LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: format!("In document {i}: {}", damage.reason),
+                span: Some(doc.span),
+            });
+        }
+
+        let mut reader = Reader::new(doc.item);
+        match reader.read_header() {
+            Ok(header) if flatten && values == 1 => match header.kind {
+                Kind::Array(len) => parts.push((&doc.item[reader.pos()..], len as usize)),
+                _ => parts.push((doc.item, 1)),
+            },
+            _ => parts.push((doc.item, values)),
+        }
+    }
+
+    let len: usize = parts.iter().map(|(_, values)| values).sum();
+    let len = u32::try_from(len).map_err(|_| LabeledError {
+        label: "Too many values".into(),
+        msg: format!(
+            "The documents hold {len} values, but an array holds at most {}",
+            u32::MAX
+        ),
+        span: Some(call.head),
+    })?;
+    let mut combined =
+        Vec::with_capacity(5 + parts.iter().map(|(part, _)| part.len()).sum::<usize>());
+    encode::write_array_len(&mut combined, len).expect("writing to a vec");
+    for (part, _) in parts {
+        combined.extend_from_slice(part);
+    }
+
+    Ok(Value::binary(combined, call.head))
+}
@@ -0,0 +1,84 @@
+//! Plugin configuration, read from `$env.config.plugins.msgpack`.
+//!
+//! Besides `ext_namespaces`, the config holds defaults for flags, under the flag's name with
+//! underscores instead of dashes. Flags given on the command line take precedence.
+
+use nu_plugin::LabeledError;
+use nu_protocol::Value;
+
+use crate::flags;
+
+/// Look up a boolean setting in the plugin config.
+pub fn get_bool(config: &Option<Value>, key: &str) -> Result<Option<bool>, LabeledError> {
+    get(config, key)
+        .map(|v| v.as_bool())
+        .transpose()
+        .map_err(|e| invalid(key, e))
+}
+
+/// Look up a non-negative integer setting in the plugin config.
+pub fn get_usize(config: &Option<Value>, key: &str) -> Result<Option<usize>, LabeledError> {
+    get(config, key)
+        .map(|v| v.as_int())
+        .transpose()
+        .map_err(|e| invalid(key, e))?
+        .map(|i| usize::try_from(i).map_err(|_| invalid(key, format!("{i} is negative"))))
+        .transpose()
+}
+
+/// Look up a filesize setting in the plugin config, in bytes.
+pub fn get_filesize(config: &Option<Value>, key: &str) -> Result<Option<usize>, LabeledError> {
+    get(config, key)
+        .map(|v| v.as_filesize())
+        .transpose()
+        .map(|size| size.map(|size| size.max(0) as usize))
+        .map_err(|e| invalid(key, e))
+}
+
+/// Look up a string setting in the plugin config.
+pub fn get_string(config: &Option<Value>, key: &str) -> Result<Option<String>, LabeledError> {
+    get(config, key)
+        .map(|v| v.as_string())
+        .transpose()
+        .map_err(|e| invalid(key, e))
+}
+
+/// Look up a setting in the plugin config whose value must be one of the names in `choices`.
+pub fn get_choice<T: Copy>(
+    config: &Option<Value>,
+    key: &str,
+    choices: &[(&str, T)],
+) -> Result<Option<T>, LabeledError> {
+    get_string(config, key)?
+        .map(|choice| flags::parse_choice(key, &choice, choices).map_err(|msg| invalid(key, msg)))
+        .transpose()
+}
+
+/// Look up a msgpack ext type id in the plugin config.
+pub fn get_ext_id(config: &Option<Value>, key: &str) -> Result<Option<i8>, LabeledError> {
+    get(config, key)
+        .map(|v| v.as_int())
+        .transpose()
+        .map_err(|e| invalid(key, e))?
+        .map(|id| {
+            i8::try_from(id)
+                .map_err(|_| invalid(key, "must be an ext type id in the range -128..=127"))
+        })
+        .transpose()
+}
+
+/// Look up a setting in the plugin config.
+pub fn get<'a>(config: &'a Option<Value>, key: &str) -> Option<&'a Value> {
+    match config {
+        Some(Value::Record { val, .. }) => val.get(key),
+        _ => None,
+    }
+}
+
+fn invalid(key: &str, e: impl std::fmt::Display) -> LabeledError {
+    LabeledError {
+        label: "Invalid plugin config".into(),
+        msg: format!("$env.config.plugins.msgpack.{key}: {e}"),
+        span: None,
+    }
+}
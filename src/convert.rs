@@ -0,0 +1,208 @@
+//! `msgpack convert`: transcode files between msgpack and other formats, without holding the
+//! data as nu values.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Spanned, Value};
+
+use crate::json::{self, JsonOptions};
+use crate::{flags, fs};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Msgpack,
+    Json,
+}
+
+pub const FORMATS: &[(&str, Format)] = &[("msgpack", Format::Msgpack), ("json", Format::Json)];
+
+impl Format {
+    fn name(self) -> &'static str {
+        FORMATS
+            .iter()
+            .find(|(_, format)| *format == self)
+            .map(|(name, _)| *name)
+            .expect("every format is in FORMATS")
+    }
+
+    /// Guess the format of a file from its extension.
+    fn of_path(path: &str) -> Option<Self> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "msgpack" | "mpk" | "mp" | "msgp" => Some(Format::Msgpack),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The format given with flag `name`, or the one of `path` by its extension.
+fn format_flag(
+    call: &EvaluatedCall,
+    name: &str,
+    path: &Spanned<String>,
+) -> Result<Format, LabeledError> {
+    match flags::choice_flag(call, name, FORMATS)? {
+        Some(format) => Ok(format),
+        None => Format::of_path(&path.item).ok_or_else(|| LabeledError {
+            label: "Unknown format".into(),
+            msg: format!(
+                "Can't tell the format of {} from its extension, pass --{name}",
+                path.item
+            ),
+            span: Some(path.span),
+        }),
+    }
+}
+
+/// Convert the file given as the first argument to the second one.
+///
+/// Returns a `{path, bytes}` record of the output file and its size.
+pub fn convert(call: &EvaluatedCall) -> Result<Value, LabeledError> {
+    let input: Spanned<String> = call.req(0)?;
+    let output: Spanned<String> = call.req(1)?;
+    let from = format_flag(call, "from", &input)?;
+    let to = format_flag(call, "to", &output)?;
+    if from == to {
+        return Err(LabeledError {
+            label: "Nothing to convert".into(),
+            msg: format!("Both files are {}", from.name()),
+            span: Some(call.head),
+        });
+    }
+
+    let resolved_input = fs::resolve(&input.item);
+    let reader = File::open(&resolved_input)
+        .map(BufReader::new)
+        .map_err(|e| fs::io_error("open", &input, e))?;
+    let resolved = fs::resolve(&output.item);
+    // writing the output would truncate the input before it's read
+    if let (Ok(a), Ok(b)) = (resolved_input.canonicalize(), resolved.canonicalize()) {
+        if a == b {
+            return Err(LabeledError {
+                label: "Same file".into(),
+                msg: format!("{} is both the input and the output", input.item),
+                span: Some(output.span),
+            });
+        }
+    }
+
+    // write next to the output and rename it into place, so that a failed conversion doesn't
+    // leave half a file behind or remove a file that was there before
+    let temp = temp_path(&resolved);
+    let mut writer = File::create(&temp)
+        .map(BufWriter::new)
+        .map_err(|e| fs::io_error("create", &output, e))?;
+
+    let result = match (from, to) {
+        (Format::Msgpack, Format::Json) => {
+            json::msgpack_to_json(reader, &mut writer, &JsonOptions::default())
+        }
+        (Format::Json, Format::Msgpack) => json::json_to_msgpack(reader, &mut writer),
+        _ => unreachable!("formats differ"),
+    }
+    .and_then(|()| writer.flush())
+    .map_err(|e| LabeledError {
+        label: format!("Failed to convert {} to {}", from.name(), to.name()),
+        msg: format!("{}: {e}", input.item),
+        span: Some(input.span),
+    })
+    .and_then(|()| {
+        let metadata = writer.get_ref().metadata();
+        metadata
+            .map(|metadata| metadata.len())
+            .map_err(|e| fs::io_error("read", &output, e))
+    });
+    drop(writer);
+    let result = result.and_then(|bytes| {
+        std::fs::rename(&temp, &resolved).map_err(|e| fs::io_error("replace", &output, e))?;
+        Ok(bytes)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    let bytes = result?;
+
+    let span = call.head;
+    let mut record = Record::new();
+    record.push("path", Value::string(resolved.to_string_lossy(), span));
+    record.push("bytes", Value::filesize(bytes as i64, span));
+    Ok(Value::record(record, span))
+}
+
+/// A path for a temporary file in the same directory as `path`, so it can be renamed to it.
+fn temp_path(path: &std::path::Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    fn call(input: &std::path::Path, output: &std::path::Path) -> EvaluatedCall {
+        let span = Span::unknown();
+        let path = |path: &std::path::Path| Value::string(path.to_string_lossy(), span);
+        EvaluatedCall {
+            head: span,
+            positional: vec![path(input), path(output)],
+            named: vec![],
+        }
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nu_plugin_msgpack-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn converts() {
+        let dir = scratch_dir("converts");
+        let (json, msgpack) = (dir.join("data.json"), dir.join("data.msgpack"));
+        std::fs::write(&json, "[1, true]").unwrap();
+        convert(&call(&json, &msgpack)).unwrap();
+        assert_eq!(
+            std::fs::read(&msgpack).unwrap(),
+            [0xdd, 0, 0, 0, 2, 0x01, 0xc3]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_files() {
+        let dir = scratch_dir("keeps-files");
+        let (json, msgpack) = (dir.join("data.json"), dir.join("data.msgpack"));
+
+        // the same file as input and output
+        std::fs::write(&json, "[1]").unwrap();
+        let mut same = call(&json, &json);
+        for (flag, format) in [("from", "json"), ("to", "msgpack")] {
+            let name = Spanned {
+                item: flag.into(),
+                span: Span::unknown(),
+            };
+            same.named
+                .push((name, Some(Value::string(format, Span::unknown()))));
+        }
+        assert_eq!(convert(&same).unwrap_err().label, "Same file");
+        assert_eq!(std::fs::read(&json).unwrap(), b"[1]");
+
+        // a failed conversion leaves the output as it was
+        std::fs::write(&msgpack, [0xc0]).unwrap();
+        std::fs::write(&json, "[1,").unwrap();
+        assert!(convert(&call(&json, &msgpack)).is_err());
+        assert_eq!(std::fs::read(&msgpack).unwrap(), [0xc0]);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
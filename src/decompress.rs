@@ -0,0 +1,199 @@
+//! Detecting and undoing compression of `from msgpack` input, and gzip compression of the
+//! values at `--compress-paths`.
+
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nu_plugin::LabeledError;
+use ruzstd::decoding::errors::{FrameDecoderError, ReadFrameHeaderError};
+use ruzstd::decoding::StreamingDecoder;
+
+use crate::trace;
+
+/// Magic bytes of gzip streams, followed by the deflate method.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b, 0x08];
+
+/// Magic bytes of zstd frames.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Limit on the size of decompressed input when `--max-size` isn't given.
+pub const DEFAULT_MAX_DECOMPRESSED: usize = 1 << 30;
+
+/// If `bin` starts with the magic bytes of gzip or zstd, return it decompressed.
+///
+/// Brotli, and so nushell's msgpackz, has no magic bytes to detect it by and isn't handled.
+///
+/// Msgpack can start with the same bytes, e.g. a fixint 31 followed by a fixmap for gzip, so
+/// input that fails to decompress is returned as it is. Input that decompresses to more than
+/// `max_size` bytes (or [DEFAULT_MAX_DECOMPRESSED]) is an error, so that a small compressed bomb
+/// can't allocate gigabytes before the decoder limits apply. With `debug`, the outcome is traced.
+pub fn auto(
+    bin: Cow<[u8]>,
+    max_size: Option<usize>,
+    debug: bool,
+) -> Result<Cow<[u8]>, LabeledError> {
+    let limit = max_size.unwrap_or(DEFAULT_MAX_DECOMPRESSED);
+    let mut decompressed = vec![];
+    let (format, result) = if bin.starts_with(GZIP_MAGIC) {
+        (
+            "gzip",
+            read_limited(MultiGzDecoder::new(&bin[..]), &mut decompressed, limit),
+        )
+    } else if bin.starts_with(ZSTD_MAGIC) {
+        ("zstd", unzstd(&bin, &mut decompressed, limit))
+    } else {
+        return Ok(bin);
+    };
+
+    match result {
+        Ok(()) if decompressed.len() > limit => Err(LabeledError {
+            label: "Input too large".into(),
+            msg: format!(
+                "The {format} input decompresses to more than {limit} bytes, raise --max-size \
+                 to decode it or use --no-auto-decompress to decode it as it is"
+            ),
+            span: None,
+        }),
+        Ok(()) => {
+            trace::log(debug, "decode", || {
+                format!(
+                    "{format} input decompressed from {} bytes to {}",
+                    bin.len(),
+                    decompressed.len()
+                )
+            });
+            Ok(Cow::Owned(decompressed))
+        }
+        Err(e) => {
+            trace::log(debug, "decode", || {
+                format!("input starts like {format} but isn't, decoding it as is: {e}")
+            });
+            Ok(bin)
+        }
+    }
+}
+
+/// Read `reader` to the end into `out`, stopping once `out` holds more than `limit` bytes.
+fn read_limited(reader: impl Read, out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    let left = (limit + 1).saturating_sub(out.len());
+    reader.take(left as u64).read_to_end(out).map(|_| ())
+}
+
+/// Decompress every zstd frame of `bin` into `out`, skipping skippable frames, like
+/// [MultiGzDecoder] does for gzip members.
+fn unzstd(bin: &[u8], out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    let mut input = bin;
+    while !input.is_empty() && out.len() <= limit {
+        match StreamingDecoder::new(&mut input) {
+            Ok(decoder) => read_limited(decoder, out, limit)?,
+            Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::SkipFrame {
+                length,
+                ..
+            })) => {
+                input = input.get(length as usize..).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated skippable frame")
+                })?;
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+    Ok(())
+}
+
+/// Compress a value encoded for `to msgpack --compress-paths` with gzip.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).expect("writing to a vec");
+    encoder.finish().expect("writing to a vec")
+}
+
+/// Decompress a value at `from msgpack --compress-paths`, if it's gzip at all. Like [auto], it
+/// may not decompress to more than `max_size` bytes, or [DEFAULT_MAX_DECOMPRESSED].
+pub fn gunzip(data: &[u8], max_size: Option<usize>) -> Option<io::Result<Vec<u8>>> {
+    if !data.starts_with(GZIP_MAGIC) {
+        return None;
+    }
+    let limit = max_size.unwrap_or(DEFAULT_MAX_DECOMPRESSED);
+    let mut decompressed = vec![];
+    Some(
+        read_limited(MultiGzDecoder::new(data), &mut decompressed, limit).and_then(|()| {
+            match decompressed.len() > limit {
+                true => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "it decompresses to more than {limit} bytes, the limit set by --max-size"
+                    ),
+                )),
+                false => Ok(decompressed),
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+
+    use super::*;
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        compress_to_vec(data, CompressionLevel::Fastest)
+    }
+
+    #[test]
+    fn round_trip() {
+        let msgpack = [0x92, 0x01, 0xa1, b'x'];
+        assert_eq!(
+            auto(Cow::Owned(gzip(&msgpack)), None, false).unwrap(),
+            &msgpack[..]
+        );
+        assert_eq!(
+            auto(Cow::Owned(zstd(&msgpack)), None, false).unwrap(),
+            &msgpack[..]
+        );
+        // not compressed at all
+        assert_eq!(
+            auto(Cow::Borrowed(&msgpack), None, false).unwrap(),
+            &msgpack[..]
+        );
+        // msgpack that only starts like gzip
+        let lookalike = [0x1f, 0x8b, 0x08];
+        assert_eq!(
+            auto(Cow::Borrowed(&lookalike), None, false).unwrap(),
+            &lookalike[..]
+        );
+    }
+
+    #[test]
+    fn zstd_frames() {
+        let mut bin = zstd(&[0x01]);
+        // a skippable frame of 2 bytes
+        bin.extend([0x50, 0x2a, 0x4d, 0x18, 0x02, 0x00, 0x00, 0x00, 0xaa, 0xbb]);
+        bin.extend(zstd(&[0x02, 0x03]));
+        assert_eq!(
+            auto(Cow::Owned(bin), None, false).unwrap(),
+            &[0x01, 0x02, 0x03][..]
+        );
+    }
+
+    #[test]
+    fn gunzip_limit() {
+        let bin = gzip(&[0; 1024]);
+        assert_eq!(gunzip(&bin, Some(1024)).unwrap().unwrap(), [0; 1024]);
+        assert!(gunzip(&bin, Some(1023)).unwrap().is_err());
+        assert!(gunzip(&[0x01], None).is_none());
+    }
+
+    #[test]
+    fn limit() {
+        let data = vec![0; 1024];
+        for bin in [gzip(&data), zstd(&data)] {
+            assert!(auto(Cow::Borrowed(&bin), Some(1024), false).is_ok());
+            let e = auto(Cow::Borrowed(&bin), Some(1023), false).unwrap_err();
+            assert_eq!(e.label, "Input too large");
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! String deduplication with `--dedup`, for tables that repeat the same keys and values in every
+//! row.
+//!
+//! A deduplicated document is an ext value of the dedup type whose payload is a msgpack array of
+//! the shared strings, followed by the document in which each occurrence of a shared string is
+//! replaced by an ext value of the same type holding its index into the array, as a big-endian
+//! integer of 1, 2 or 4 bytes. Only strings that come out smaller this way are shared.
+//!
+//! Both steps work on the wire, copying everything but the strings byte for byte.
+
+use std::collections::HashMap;
+
+use nu_plugin::LabeledError;
+use rmp::encode;
+
+use crate::decompress::DEFAULT_MAX_DECOMPRESSED;
+use crate::trace;
+use crate::wire::{Kind, Reader, WireError};
+
+/// Replace the repeated strings of a msgpack document with references into a string table.
+///
+/// With `debug`, each shared string and the bytes it saves are traced.
+pub fn dedup(encoded: &[u8], ext_type: i8, debug: bool) -> Result<Vec<u8>, LabeledError> {
+    // occurrences of each string, in the order they were first seen
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    let mut order = vec![];
+    let mut reader = Reader::new(encoded);
+    while !reader.is_empty() {
+        let header = reader.read_header().map_err(internal)?;
+        let data = reader.read_data(header.data_len()).map_err(internal)?;
+        match header.kind {
+            Kind::Str(_) => {
+                let count = counts.entry(data).or_default();
+                if *count == 0 {
+                    order.push(data);
+                }
+                *count += 1;
+            }
+            Kind::Ext(id, _) if id == ext_type => {
+                return Err(LabeledError {
+                    label: "Ext type in use".into(),
+                    msg: format!(
+                        "The value holds ext values of type {id}, which --dedup needs for itself"
+                    ),
+                    span: None,
+                })
+            }
+            _ => {}
+        }
+    }
+
+    // the most frequent strings get the shortest references
+    order.sort_by_key(|s| std::cmp::Reverse(counts[s]));
+    let mut table: Vec<&[u8]> = vec![];
+    let mut index: HashMap<&[u8], u32> = HashMap::new();
+    for s in order {
+        // fixext values have a 2 byte header
+        let reference = 2 + reference(table.len() as u32).len();
+        let string = str_len(s.len());
+        let count = counts[s];
+        // the table holds the string once, and every occurrence saves its size over a reference
+        if count * string > string + count * reference {
+            trace::log(debug, "encode", || {
+                format!(
+                    "sharing {:?}, which occurs {count} times, saves {} bytes",
+                    String::from_utf8_lossy(s),
+                    count * string - string - count * reference
+                )
+            });
+            index.insert(s, table.len() as u32);
+            table.push(s);
+        }
+    }
+
+    let mut payload = vec![];
+    encode::write_array_len(&mut payload, table.len() as u32).expect("writing to a vec");
+    for s in &table {
+        encode::write_str_len(&mut payload, s.len() as u32).expect("writing to a vec");
+        payload.extend_from_slice(s);
+    }
+
+    let mut reader = Reader::new(encoded);
+    while !reader.is_empty() {
+        let start = reader.pos();
+        let header = reader.read_header().map_err(internal)?;
+        let data = reader.read_data(header.data_len()).map_err(internal)?;
+        match index.get(data) {
+            Some(&i) if matches!(header.kind, Kind::Str(_)) => {
+                let reference = reference(i);
+                encode::write_ext_meta(&mut payload, reference.len() as u32, ext_type)
+                    .expect("writing to a vec");
+                payload.extend_from_slice(&reference);
+            }
+            _ => payload.extend_from_slice(&encoded[start..reader.pos()]),
+        }
+    }
+
+    let mut deduped = vec![];
+    encode::write_ext_meta(&mut deduped, payload.len() as u32, ext_type).expect("writing to a vec");
+    deduped.extend_from_slice(&payload);
+    trace::log(debug, "encode", || {
+        format!(
+            "deduplication with a string table of {} shrank {} bytes to {}",
+            table.len(),
+            encoded.len(),
+            deduped.len()
+        )
+    });
+    Ok(deduped)
+}
+
+/// If `bin` starts with a deduplicated document, restore the strings of the document it holds.
+///
+/// Returns the restored document and the length of the deduplicated one in `bin`, or `None` if
+/// `bin` doesn't start with an ext value of the dedup type. As every string can be referenced any
+/// number of times, the restored document may not grow past `max_size` bytes, or
+/// [DEFAULT_MAX_DECOMPRESSED] without it. With `debug`, the sizes are traced.
+pub fn expand(
+    bin: &[u8],
+    ext_type: i8,
+    max_size: Option<usize>,
+    debug: bool,
+) -> Result<Option<(Vec<u8>, usize)>, LabeledError> {
+    let mut reader = Reader::new(bin);
+    let Ok(header) = reader.read_header() else {
+        return Ok(None);
+    };
+    let Kind::Ext(id, len) = header.kind else {
+        return Ok(None);
+    };
+    if id != ext_type {
+        return Ok(None);
+    }
+    let payload = reader.read_data(len as usize).map_err(invalid)?;
+    let consumed = reader.pos();
+
+    let mut reader = Reader::new(payload);
+    let header = reader.read_header().map_err(invalid)?;
+    let Kind::Array(len) = header.kind else {
+        return Err(invalid_table(format!(
+            "expected an array of strings, found {}",
+            header.type_name()
+        )));
+    };
+    let mut table = Vec::with_capacity((len as usize).min(reader.remaining()));
+    for i in 0..len {
+        let header = reader.read_header().map_err(invalid)?;
+        let Kind::Str(len) = header.kind else {
+            return Err(invalid_table(format!(
+                "string {i} of the table is a {}",
+                header.type_name()
+            )));
+        };
+        table.push(reader.read_data(len as usize).map_err(invalid)?);
+    }
+
+    let doc_start = reader.pos();
+    let mut doc = Reader::at(payload, doc_start);
+    doc.skip_value().map_err(invalid)?;
+    if !doc.is_empty() {
+        return Err(invalid_table(format!(
+            "found {} more bytes after the end of the document",
+            doc.remaining()
+        )));
+    }
+
+    let limit = max_size.unwrap_or(DEFAULT_MAX_DECOMPRESSED);
+    let mut expanded = Vec::with_capacity(payload.len().min(limit));
+    while !reader.is_empty() {
+        let start = reader.pos();
+        let header = reader.read_header().map_err(invalid)?;
+        let data = reader.read_data(header.data_len()).map_err(invalid)?;
+        match header.kind {
+            Kind::Ext(id, _) if id == ext_type => {
+                let i = match *data {
+                    [a] => u32::from(a),
+                    [a, b] => u16::from_be_bytes([a, b]).into(),
+                    [a, b, c, d] => u32::from_be_bytes([a, b, c, d]),
+                    _ => {
+                        return Err(invalid_table(format!(
+                            "reference at byte {start} of the document has {} bytes",
+                            data.len()
+                        )))
+                    }
+                };
+                let s = table.get(i as usize).ok_or_else(|| {
+                    invalid_table(format!(
+                        "reference at byte {start} of the document points at string {i}, \
+                         but the table has {} strings",
+                        table.len()
+                    ))
+                })?;
+                check_size(expanded.len() + str_len(s.len()), limit)?;
+                encode::write_str_len(&mut expanded, s.len() as u32).expect("writing to a vec");
+                expanded.extend_from_slice(s);
+            }
+            _ => {
+                check_size(expanded.len() + reader.pos() - start, limit)?;
+                expanded.extend_from_slice(&payload[start..reader.pos()]);
+            }
+        }
+    }
+
+    trace::log(debug, "decode", || {
+        format!(
+            "restoring a string table of {} expanded {consumed} bytes to {}",
+            table.len(),
+            expanded.len()
+        )
+    });
+    Ok(Some((expanded, consumed)))
+}
+
+/// Check the size the restored document is about to grow to against the limit of [expand].
+fn check_size(size: usize, limit: usize) -> Result<(), LabeledError> {
+    if size <= limit {
+        return Ok(());
+    }
+    Err(LabeledError {
+        label: "Value too large".into(),
+        msg: format!(
+            "Restoring the deduplicated strings grows the document past {limit} bytes, the \
+             limit set by --max-size"
+        ),
+        span: None,
+    })
+}
+
+/// The payload of a reference to the string at `index` of the table.
+fn reference(index: u32) -> Vec<u8> {
+    match index {
+        0..=0xff => vec![index as u8],
+        0x100..=0xffff => (index as u16).to_be_bytes().to_vec(),
+        _ => index.to_be_bytes().to_vec(),
+    }
+}
+
+/// Encoded size of a string of `len` bytes.
+fn str_len(len: usize) -> usize {
+    let header = match len {
+        0..=31 => 1,
+        32..=0xff => 2,
+        0x100..=0xffff => 3,
+        _ => 5,
+    };
+    header + len
+}
+
+fn internal(e: WireError) -> LabeledError {
+    LabeledError {
+        label: "Failed to deduplicate strings".into(),
+        msg: format!("The encoded value is invalid: {e}"),
+        span: None,
+    }
+}
+
+fn invalid(e: WireError) -> LabeledError {
+    invalid_table(e.to_string())
+}
+
+fn invalid_table(msg: String) -> LabeledError {
+    LabeledError {
+        label: "Invalid dedup table".into(),
+        msg: format!("Failed to restore deduplicated strings: {msg}"),
+        span: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::{Record, Span, Value};
+
+    use super::*;
+    use crate::from::{self, DecodeOptions};
+    use crate::into::{self, EncodeOptions};
+
+    const EXT_TYPE: i8 = 7;
+
+    fn table(rows: usize) -> Value {
+        let span = Span::unknown();
+        let row = |i| {
+            let mut record = Record::new();
+            record.push("name", Value::string("a repeated name", span));
+            record.push("index", Value::int(i as i64, span));
+            Value::record(record, span)
+        };
+        Value::list((0..rows).map(row).collect(), span)
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = table(10);
+        let encoded = into::to_msgpack(&value, &EncodeOptions::default()).unwrap();
+        let deduped = dedup(&encoded, EXT_TYPE, false).unwrap();
+        assert!(deduped.len() < encoded.len());
+
+        let expanded = expand(&deduped, EXT_TYPE, None, false).unwrap();
+        assert_eq!(expanded, Some((encoded, deduped.len())));
+        let opts = DecodeOptions {
+            dedup: Some(EXT_TYPE),
+            ..DecodeOptions::default()
+        };
+        assert_eq!(from::decode(&deduped, &opts).unwrap(), value);
+        // other documents are left alone
+        assert_eq!(expand(&[0xc0], EXT_TYPE, None, false).unwrap(), None);
+    }
+
+    #[test]
+    fn expanded_size() {
+        let encoded = into::to_msgpack(&table(100), &EncodeOptions::default()).unwrap();
+        let deduped = dedup(&encoded, EXT_TYPE, false).unwrap();
+        assert!(expand(&deduped, EXT_TYPE, Some(encoded.len()), false).is_ok());
+        let e = expand(&deduped, EXT_TYPE, Some(encoded.len() - 1), false).unwrap_err();
+        assert_eq!(e.label, "Value too large");
+    }
+
+    #[test]
+    fn compat() {
+        let opts = EncodeOptions {
+            dedup: Some(EXT_TYPE),
+            compat: true,
+            ..EncodeOptions::default()
+        };
+        let e = into::to_msgpack(&table(2), &opts).unwrap_err();
+        assert_eq!(e.label, "Conflicting flags");
+    }
+}
@@ -0,0 +1,212 @@
+//! Structural comparison of msgpack values.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Spanned, Value};
+
+use crate::from::{self, DecodeOptions};
+use crate::into;
+use crate::path::{Path, PathSegment};
+
+#[derive(Clone, Copy)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A single difference between two values.
+pub struct Difference {
+    pub path: Path,
+    pub change: Change,
+    pub old: Option<rmpv::Value>,
+    pub new: Option<rmpv::Value>,
+}
+
+/// Compare the input msgpack binary with the one given as first argument.
+pub fn compare(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let opts = DecodeOptions::from_call(call, config)?;
+    let other: Spanned<Vec<u8>> = call.req(0)?;
+
+    let read = |mut data: &[u8], span| {
+        // reject what `from msgpack` would with the same flags, e.g. trailing bytes or nesting
+        // deeper than --max-depth, before comparing the wire values
+        from::decode(data, &opts).map_err(|e| LabeledError {
+            span: e.span.or(Some(span)),
+            ..e
+        })?;
+        rmpv::decode::read_value(&mut data).map_err(|e| LabeledError {
+            label: "Invalid msgpack".into(),
+            msg: e.to_string(),
+            span: Some(span),
+        })
+    };
+    let old = read(input.as_binary()?, input.span())?;
+    let new = read(&other.item, other.span)?;
+
+    Ok(to_nu(diff(&old, &new), &opts, call.head))
+}
+
+/// Compare two values, returning the paths at which they differ.
+///
+/// Maps are compared by key, and arrays by index.
+pub fn diff(old: &rmpv::Value, new: &rmpv::Value) -> Vec<Difference> {
+    let mut diffs = vec![];
+    diff_at(&mut Path::default(), old, new, &mut diffs);
+    diffs
+}
+
+fn diff_at(path: &mut Path, old: &rmpv::Value, new: &rmpv::Value, diffs: &mut Vec<Difference>) {
+    match (old, new) {
+        (rmpv::Value::Map(old), rmpv::Value::Map(new)) => {
+            for (k, old_v) in old {
+                path.push(PathSegment::Key(key_segment(k)));
+                match new.iter().find(|(new_k, _)| new_k == k) {
+                    Some((_, new_v)) => diff_at(path, old_v, new_v, diffs),
+                    None => diffs.push(difference(path, Change::Removed, Some(old_v), None)),
+                }
+                path.pop();
+            }
+
+            for (k, new_v) in new {
+                if !old.iter().any(|(old_k, _)| old_k == k) {
+                    path.push(PathSegment::Key(key_segment(k)));
+                    diffs.push(difference(path, Change::Added, None, Some(new_v)));
+                    path.pop();
+                }
+            }
+        }
+        (rmpv::Value::Array(old), rmpv::Value::Array(new)) => {
+            for i in 0..old.len().max(new.len()) {
+                path.push(PathSegment::Index(i));
+                match (old.get(i), new.get(i)) {
+                    (Some(old), Some(new)) => diff_at(path, old, new, diffs),
+                    (Some(old), None) => {
+                        diffs.push(difference(path, Change::Removed, Some(old), None))
+                    }
+                    (None, new) => diffs.push(difference(path, Change::Added, None, new)),
+                }
+                path.pop();
+            }
+        }
+        (old, new) if old != new => {
+            diffs.push(difference(path, Change::Changed, Some(old), Some(new)))
+        }
+        _ => {}
+    }
+}
+
+fn difference(
+    path: &Path,
+    change: Change,
+    old: Option<&rmpv::Value>,
+    new: Option<&rmpv::Value>,
+) -> Difference {
+    Difference {
+        path: path.clone(),
+        change,
+        old: old.cloned(),
+        new: new.cloned(),
+    }
+}
+
+/// Path segment for a map key.
+pub fn key_segment(key: &rmpv::Value) -> String {
+    match key {
+        rmpv::Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        key => key.to_string(),
+    }
+}
+
+/// Convert differences to a nu table of `path`, `change`, `old` and `new` columns.
+///
+/// The old and new values are decoded with `opts`.
+pub fn to_nu(diffs: Vec<Difference>, opts: &DecodeOptions, span: Span) -> Value {
+    let to_nu = |value: Option<rmpv::Value>| match value {
+        None => Value::nothing(span),
+        Some(value) => from::decode(&into::encode(&value), opts)
+            .unwrap_or_else(|_| Value::string(value.to_string(), span)),
+    };
+
+    let rows = diffs
+        .into_iter()
+        .map(|d| {
+            let change = match d.change {
+                Change::Added => "added",
+                Change::Removed => "removed",
+                Change::Changed => "changed",
+            };
+            let mut record = Record::new();
+            record.push("path", Value::string(d.path.to_string(), span));
+            record.push("change", Value::string(change, span));
+            record.push("old", to_nu(d.old));
+            record.push("new", to_nu(d.new));
+            Value::record(record, span)
+        })
+        .collect();
+
+    Value::list(rows, span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(old: &rmpv::Value, new: &rmpv::Value) -> Vec<(String, &'static str)> {
+        diff(old, new)
+            .into_iter()
+            .map(|d| {
+                let change = match d.change {
+                    Change::Added => "added",
+                    Change::Removed => "removed",
+                    Change::Changed => "changed",
+                };
+                (d.path.to_string(), change)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn maps_by_key() {
+        let old = rmpv::Value::Map(vec![
+            ("a".into(), 1.into()),
+            ("b".into(), 2.into()),
+            ("c".into(), rmpv::Value::Map(vec![("d".into(), "x".into())])),
+        ]);
+        let new = rmpv::Value::Map(vec![
+            ("c".into(), rmpv::Value::Map(vec![("d".into(), "y".into())])),
+            ("a".into(), 1.into()),
+            ("e".into(), 3.into()),
+        ]);
+        assert_eq!(
+            changes(&old, &new),
+            [
+                ("b".into(), "removed"),
+                ("c.d".into(), "changed"),
+                ("e".into(), "added")
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_by_index() {
+        let old = rmpv::Value::Array(vec![1.into(), 2.into()]);
+        let new = rmpv::Value::Array(vec![1.into(), 5.into(), 6.into()]);
+        assert_eq!(
+            changes(&old, &new),
+            [("1".into(), "changed"), ("2".into(), "added")]
+        );
+        assert_eq!(changes(&new, &old)[1], ("2".into(), "removed"));
+        assert!(changes(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn types() {
+        // an int and the same number as a string differ
+        let changed = changes(&1.into(), &"1".into());
+        assert_eq!(changed, [("$".into(), "changed")]);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+
+use crate::path::Path;
+use crate::wire::{Kind, Node, Reader, Role, Walker, WireError, WireErrorKind};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+struct Finding {
+    severity: Severity,
+    check: &'static str,
+    offset: usize,
+    path: String,
+    message: String,
+}
+
+/// Run a battery of checks on a msgpack binary and report the findings, most severe first.
+pub fn doctor(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let mut findings = vec![];
+
+    let mut reader = Reader::new(data);
+    let mut values = 0;
+    while !reader.is_empty() {
+        let mut walker = Walker::new(reader.clone());
+
+        // keys seen in the maps currently being walked, indexed by the depth of the keys
+        let mut seen_keys: Vec<HashSet<&[u8]>> = vec![];
+
+        while let Some(node) = walker.next() {
+            match node {
+                Ok(node) => check_node(
+                    &node,
+                    walker.path(),
+                    walker.reader(),
+                    &mut seen_keys,
+                    &mut findings,
+                ),
+                Err(e) => {
+                    check_error(e, walker.path(), data.len(), &mut findings);
+                    break;
+                }
+            }
+        }
+
+        if findings.iter().any(|f| f.severity == Severity::Error) {
+            break;
+        }
+        values += 1;
+        reader = walker.reader().clone();
+    }
+
+    let valid = reader.pos();
+    findings.push(Finding {
+        severity: Severity::Info,
+        check: "summary",
+        offset: 0,
+        path: Path::default().to_string(),
+        message: format!(
+            "{values} complete top-level value(s) in the first {valid} of {} bytes",
+            data.len()
+        ),
+    });
+
+    findings.sort_by_key(|f| (f.severity, f.offset));
+
+    let findings = findings
+        .into_iter()
+        .map(|f| {
+            let severity = match f.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+                Severity::Info => "info",
+            };
+            let mut record = Record::new();
+            record.push("severity", Value::string(severity, call.head));
+            record.push("check", Value::string(f.check, call.head));
+            record.push("offset", Value::int(f.offset as i64, call.head));
+            record.push("path", Value::string(f.path, call.head));
+            record.push("message", Value::string(f.message, call.head));
+            Value::record(record, call.head)
+        })
+        .collect();
+
+    Ok(Value::list(findings, call.head))
+}
+
+fn check_node<'a>(
+    node: &Node<'a>,
+    path: &Path,
+    reader: &Reader<'a>,
+    seen_keys: &mut Vec<HashSet<&'a [u8]>>,
+    findings: &mut Vec<Finding>,
+) {
+    let header = &node.header;
+    let mut finding = |severity, check, message| {
+        findings.push(Finding {
+            severity,
+            check,
+            offset: header.offset,
+            path: path.to_string(),
+            message,
+        })
+    };
+
+    if let Some(minimal) = header.minimal_marker() {
+        finding(
+            Severity::Warning,
+            "non-minimal encoding",
+            format!("{} used where {minimal} suffices", header.type_name()),
+        );
+    }
+
+    if header.child_count() > reader.remaining() as u64 {
+        finding(
+            Severity::Error,
+            "suspicious length",
+            format!(
+                "{} claims {} nested values, but only {} bytes remain",
+                header.type_name(),
+                header.child_count(),
+                reader.remaining()
+            ),
+        );
+    }
+
+    match header.kind {
+        Kind::Str(_) if std::str::from_utf8(node.data).is_err() => finding(
+            Severity::Warning,
+            "invalid utf-8",
+            "string is not valid UTF-8".into(),
+        ),
+        Kind::Ext(-1, len) if ![4, 8, 12].contains(&len) => finding(
+            Severity::Error,
+            "invalid timestamp",
+            format!("timestamp ext value has invalid length {len}"),
+        ),
+        Kind::Ext(-1, _) => {}
+        Kind::Ext(ext_type, _) => finding(
+            Severity::Info,
+            "unknown ext type",
+            format!("ext type {ext_type} has no built-in meaning"),
+        ),
+        Kind::Map(_) => {
+            seen_keys.resize_with(node.depth + 1, HashSet::new);
+            seen_keys.push(HashSet::new());
+        }
+        _ => {}
+    }
+
+    if let Role::Key(_) = node.role {
+        let input = reader.input();
+        let mut key_reader = Reader::at(input, header.offset);
+        if key_reader.skip_value().is_ok() {
+            let key = &input[header.offset..key_reader.pos()];
+            let is_new = seen_keys
+                .get_mut(node.depth)
+                .is_none_or(|seen| seen.insert(key));
+            if !is_new {
+                let key = rmpv::decode::read_value_ref(&mut &key[..])
+                    .map_or_else(|_| "?".into(), |key| key.to_string());
+                finding(
+                    Severity::Warning,
+                    "duplicate key",
+                    format!("map contains the key {key} more than once"),
+                );
+            }
+        }
+    }
+}
+
+fn check_error(e: WireError, path: &Path, input_len: usize, findings: &mut Vec<Finding>) {
+    let mut finding = |check, message| {
+        findings.push(Finding {
+            severity: Severity::Error,
+            check,
+            offset: e.offset,
+            path: path.to_string(),
+            message,
+        })
+    };
+
+    match e.kind {
+        WireErrorKind::Truncated { needed } => {
+            let claimed = input_len - e.offset + needed;
+            if claimed > input_len {
+                finding(
+                    "suspicious length",
+                    format!("value claims {claimed} bytes, more than the whole input"),
+                );
+            }
+            finding(
+                "truncated",
+                format!("input ends at byte {input_len}, {needed} more bytes were expected"),
+            );
+        }
+        WireErrorKind::ReservedMarker => finding("invalid marker", e.to_string()),
+    }
+}
@@ -0,0 +1,83 @@
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Value};
+
+use crate::wire::{self, Kind, Node, Reader, Walker};
+
+/// Number of bytes shown in the `hex` column before the rest is elided.
+const HEX_BYTES: usize = 16;
+
+/// List every value of a msgpack binary with its offset, raw bytes, wire type and decoded
+/// meaning, in document order.
+pub fn dump(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+
+    let mut rows = vec![];
+    let mut reader = Reader::new(data);
+    while !reader.is_empty() {
+        let mut walker = Walker::new(reader);
+        loop {
+            let offset = walker.reader().pos();
+            let Some(node) = walker.next() else {
+                break;
+            };
+            let node = node.map_err(|e| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: format!("{e}\n{}", wire::hex_window(data, offset, 8)),
+                span: Some(input.span()),
+            })?;
+            let bytes = &data[offset..walker.reader().pos()];
+
+            let mut row = Record::new();
+            row.push("offset", Value::int(offset as i64, span));
+            row.push("depth", Value::int(node.depth as i64, span));
+            row.push("hex", Value::string(hex(bytes), span));
+            row.push("type", Value::string(node.header.type_name(), span));
+            row.push("value", meaning(&node, bytes, span));
+            rows.push(Value::record(row, span));
+        }
+        reader = walker.reader().clone();
+    }
+
+    Ok(Value::list(rows, span))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex: Vec<String> = bytes
+        .iter()
+        .take(HEX_BYTES)
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    if bytes.len() > HEX_BYTES {
+        hex.push(format!("… ({} bytes)", bytes.len()));
+    }
+    hex.join(" ")
+}
+
+/// The decoded scalar of a node, or a description of its size for containers, binaries and exts.
+fn meaning(node: &Node, bytes: &[u8], span: Span) -> Value {
+    match node.header.kind {
+        Kind::Nil => Value::nothing(span),
+        Kind::Bool(b) => Value::bool(b, span),
+        Kind::Uint(u) => match i64::try_from(u) {
+            Ok(i) => Value::int(i, span),
+            Err(_) => Value::string(u.to_string(), span),
+        },
+        Kind::Int(i) => Value::int(i, span),
+        Kind::F32 => {
+            let float = f32::from_be_bytes(bytes[1..5].try_into().expect("f32 has 4 bytes"));
+            Value::float(float.into(), span)
+        }
+        Kind::F64 => {
+            let float = f64::from_be_bytes(bytes[1..9].try_into().expect("f64 has 8 bytes"));
+            Value::float(float, span)
+        }
+        Kind::Str(_) => Value::string(String::from_utf8_lossy(node.data), span),
+        Kind::Bin(len) => Value::string(format!("{len} bytes"), span),
+        Kind::Array(len) => Value::string(format!("{len} elements"), span),
+        Kind::Map(len) => Value::string(format!("{len} entries"), span),
+        Kind::Ext(ext_type, len) => {
+            Value::string(format!("ext type {ext_type}, {len} bytes"), span)
+        }
+    }
+}
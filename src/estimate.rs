@@ -0,0 +1,82 @@
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+
+use crate::wire::{Kind, Reader, Role, Walker};
+
+/// Default for `--max-memory`.
+const DEFAULT_MAX_MEMORY: i64 = 1 << 30;
+
+/// Predict how much memory decoding the input with `from msgpack` would take, by scanning the
+/// value headers only.
+pub fn estimate(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let max_memory: i64 = call
+        .get_flag::<Value>("max-memory")?
+        .map(|v| v.as_filesize())
+        .transpose()?
+        .unwrap_or(DEFAULT_MAX_MEMORY);
+
+    let value_size = std::mem::size_of::<Value>();
+    let string_size = std::mem::size_of::<String>();
+
+    let mut values = 0;
+    let (mut strings, mut binaries, mut containers, mut exts) = (0, 0, 0, 0);
+    let mut memory = 0;
+
+    let mut reader = Reader::new(data);
+    while !reader.is_empty() {
+        let mut walker = Walker::new(reader);
+        for node in &mut walker {
+            let node = node.map_err(|e| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: e.to_string(),
+                span: Some(input.span()),
+            })?;
+
+            // map keys become record columns rather than values
+            memory += match node.role {
+                Role::Key(_) => string_size,
+                _ => value_size,
+            };
+            memory += node.data.len();
+
+            match node.header.kind {
+                Kind::Str(_) => strings += 1,
+                Kind::Bin(_) => binaries += 1,
+                Kind::Array(_) | Kind::Map(_) => containers += 1,
+                // unknown ext values become a record of two values
+                Kind::Ext(..) => {
+                    exts += 1;
+                    memory += 2 * (value_size + string_size);
+                }
+                _ => {}
+            }
+        }
+        values += 1;
+        reader = walker.reader().clone();
+    }
+
+    let span = call.head;
+    let memory = memory as i64;
+    let mut record = Record::new();
+    record.push("values", Value::int(values, span));
+    record.push("strings", Value::int(strings, span));
+    record.push("binaries", Value::int(binaries, span));
+    record.push("containers", Value::int(containers, span));
+    record.push("ext", Value::int(exts, span));
+    record.push("encoded_size", Value::filesize(data.len() as i64, span));
+    record.push("estimated_memory", Value::filesize(memory, span));
+    record.push(
+        "warning",
+        if memory > max_memory {
+            Value::string(
+                "decoding would likely need more memory than --max-memory",
+                span,
+            )
+        } else {
+            Value::nothing(span)
+        },
+    );
+
+    Ok(Value::record(record, span))
+}
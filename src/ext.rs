@@ -0,0 +1,212 @@
+//! Built-in interpretations of ext type payloads.
+
+use std::collections::HashMap;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Span, Spanned, Value};
+
+use crate::from::{self, DecodeOptions};
+use crate::{config, flags};
+
+/// A built-in way to decode and encode the payload of an ext value.
+#[derive(Clone, Copy)]
+pub enum ExtCodec {
+    /// The payload is itself a msgpack document.
+    NestedMsgpack,
+
+    /// The payload is a UTF-8 string.
+    Utf8,
+
+    /// The payload is a 16 byte UUID.
+    Uuid,
+
+    /// The payload is a decimal number written as a UTF-8 string, e.g. `-12.50`.
+    Decimal,
+
+    /// Return the payload as binary.
+    Raw,
+}
+
+pub const CODECS: &[(&str, ExtCodec)] = &[
+    ("nested-msgpack", ExtCodec::NestedMsgpack),
+    ("utf8", ExtCodec::Utf8),
+    ("uuid", ExtCodec::Uuid),
+    ("decimal", ExtCodec::Decimal),
+    ("raw", ExtCodec::Raw),
+];
+
+impl ExtCodec {
+    /// Decode an ext payload of a value at the given nesting `depth`.
+    pub fn decode(
+        self,
+        data: &[u8],
+        opts: &DecodeOptions,
+        depth: usize,
+    ) -> Result<Value, LabeledError> {
+        let span = Span::unknown();
+        Ok(match self {
+            ExtCodec::NestedMsgpack => from::decode_nested(data, opts, depth)?,
+            ExtCodec::Utf8 => Value::string(utf8(data, "utf8")?, span),
+            ExtCodec::Uuid => {
+                let uuid = <[u8; 16]>::try_from(data).map_err(|_| {
+                    invalid_ext("uuid", format!("expected 16 bytes, got {}", data.len()))
+                })?;
+                Value::string(format_uuid(uuid), span)
+            }
+            ExtCodec::Decimal => {
+                let decimal = utf8(data, "decimal")?;
+                if !is_decimal(decimal) {
+                    return Err(invalid_ext(
+                        "decimal",
+                        format!("{decimal:?} is not a decimal"),
+                    ));
+                }
+                Value::string(decimal, span)
+            }
+            ExtCodec::Raw => Value::binary(data, span),
+        })
+    }
+
+    /// Encode a value as the payload of an ext value, for all codecs but
+    /// [ExtCodec::NestedMsgpack], which needs the encoder.
+    pub fn encode(self, value: &Value) -> Result<Vec<u8>, LabeledError> {
+        let codec = match self {
+            ExtCodec::NestedMsgpack => unreachable!("nested msgpack is written by the encoder"),
+            ExtCodec::Utf8 => "utf8",
+            ExtCodec::Uuid => "uuid",
+            ExtCodec::Decimal => "decimal",
+            ExtCodec::Raw => "raw",
+        };
+        let payload = match (self, value) {
+            (ExtCodec::Utf8, Value::String { val, .. }) => Some(val.as_bytes().to_vec()),
+            (ExtCodec::Uuid, Value::String { val, .. }) => parse_uuid(val).map(Vec::from),
+            (ExtCodec::Decimal, Value::String { val, .. }) if is_decimal(val) => {
+                Some(val.as_bytes().to_vec())
+            }
+            (ExtCodec::Raw, Value::Binary { val, .. }) => Some(val.clone()),
+            _ => None,
+        };
+        payload.ok_or_else(|| LabeledError {
+            label: "Invalid ext value".into(),
+            msg: format!(
+                "An ext value of {codec} can't hold {}",
+                match value {
+                    Value::String { val, .. } => format!("{val:?}"),
+                    value => value.get_type().to_string(),
+                }
+            ),
+            span: Some(value.span()),
+        })
+    }
+}
+
+/// Format a UUID in its canonical hyphenated form.
+pub fn format_uuid(uuid: [u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Parse a UUID in its canonical hyphenated form.
+pub fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = s.split('-').collect();
+    let lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    if lens != [8, 4, 4, 4, 12] {
+        return None;
+    }
+
+    let hex: String = groups.concat();
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut uuid = [0; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// Check if a string is a decimal number, e.g. `-12.50`.
+pub fn is_decimal(s: &str) -> bool {
+    let unsigned = s.strip_prefix(['-', '+']).unwrap_or(s);
+    match unsigned.split_once('.') {
+        Some((int, frac)) => is_digits(int) && is_digits(frac),
+        None => is_digits(unsigned),
+    }
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn utf8<'a>(data: &'a [u8], codec: &str) -> Result<&'a str, LabeledError> {
+    std::str::from_utf8(data).map_err(|e| invalid_ext(codec, e.to_string()))
+}
+
+fn invalid_ext(codec: &str, msg: String) -> LabeledError {
+    LabeledError {
+        label: "Invalid ext value".into(),
+        msg: format!("Failed to decode ext value as {codec}: {msg}"),
+        span: None,
+    }
+}
+
+/// The ext codecs of the namespace selected by `--namespace`, or by `namespace` in the plugin
+/// config.
+pub fn namespace_from_call(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+) -> Result<HashMap<i8, ExtCodec>, LabeledError> {
+    let namespace = match call.get_flag::<Spanned<String>>("namespace")? {
+        Some(name) => Some(name),
+        None => config::get_string(config, "namespace")?.map(|item| Spanned {
+            item,
+            span: call.head,
+        }),
+    };
+    let Some(name) = namespace else {
+        return Ok(HashMap::new());
+    };
+    let namespace = config::get(config, "ext_namespaces")
+        .and_then(|namespaces| namespaces.as_record().ok()?.get(&name.item))
+        .ok_or_else(|| LabeledError {
+            label: "Unknown ext namespace".into(),
+            msg: format!(
+                "No ext namespace {:?} in $env.config.plugins.msgpack.ext_namespaces",
+                name.item
+            ),
+            span: Some(name.span),
+        })?;
+    parse_namespace(&name.item, namespace)
+}
+
+/// Parse an ext namespace, a record mapping ext type ids to the names of [CODECS].
+pub fn parse_namespace(
+    name: &str,
+    namespace: &Value,
+) -> Result<HashMap<i8, ExtCodec>, LabeledError> {
+    let invalid = |msg: String| LabeledError {
+        label: "Invalid ext namespace".into(),
+        msg: format!("$env.config.plugins.msgpack.ext_namespaces.{name}: {msg}"),
+        span: Some(namespace.span()),
+    };
+
+    let mut codecs = HashMap::new();
+    for (id, codec) in namespace.as_record()? {
+        let id: i8 = id.parse().map_err(|_| {
+            invalid(format!(
+                "{id:?} is not an ext type id in the range -128..=127"
+            ))
+        })?;
+        let codec = flags::parse_choice("codec", &codec.as_string()?, CODECS).map_err(invalid)?;
+        codecs.insert(id, codec);
+    }
+
+    Ok(codecs)
+}
@@ -0,0 +1,96 @@
+//! Helpers for reading flags of an [EvaluatedCall].
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::ast::CellPath;
+use nu_protocol::{FromValue, Spanned, Value};
+
+use crate::config;
+
+/// Read an optional flag holding a msgpack ext type id.
+pub fn ext_id_flag(call: &EvaluatedCall, name: &str) -> Result<Option<i8>, LabeledError> {
+    let Some(id) = call.get_flag::<Spanned<i64>>(name)? else {
+        return Ok(None);
+    };
+
+    i8::try_from(id.item).map(Some).map_err(|_| LabeledError {
+        label: "Invalid ext type".into(),
+        msg: format!("--{name} must be an ext type id in the range -128..=127"),
+        span: Some(id.span),
+    })
+}
+
+/// Read an ext type id flag, falling back to its default in the plugin config.
+pub fn ext_id_or_config(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    name: &str,
+) -> Result<Option<i8>, LabeledError> {
+    match ext_id_flag(call, name)? {
+        Some(id) => Ok(Some(id)),
+        None => config::get_ext_id(config, &name.replace('-', "_")),
+    }
+}
+
+/// Read a choice flag, falling back to its default in the plugin config and then to the
+/// default choice.
+pub fn choice_or_config<T: Copy + Default>(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    name: &str,
+    choices: &[(&str, T)],
+) -> Result<T, LabeledError> {
+    match choice_flag(call, name, choices)? {
+        Some(choice) => Ok(choice),
+        None => {
+            Ok(config::get_choice(config, &name.replace('-', "_"), choices)?.unwrap_or_default())
+        }
+    }
+}
+
+/// Read an optional flag whose value must be one of the names in `choices`.
+pub fn choice_flag<T: Copy>(
+    call: &EvaluatedCall,
+    name: &str,
+    choices: &[(&str, T)],
+) -> Result<Option<T>, LabeledError> {
+    let Some(choice) = call.get_flag::<Spanned<String>>(name)? else {
+        return Ok(None);
+    };
+
+    parse_choice(name, &choice.item, choices)
+        .map(Some)
+        .map_err(|msg| LabeledError {
+            label: "Invalid flag value".into(),
+            msg,
+            span: Some(choice.span),
+        })
+}
+
+/// Look up `value` in `choices`, returning an error message listing the valid names if it's
+/// not there.
+pub fn parse_choice<T: Copy>(name: &str, value: &str, choices: &[(&str, T)]) -> Result<T, String> {
+    choices
+        .iter()
+        .find(|(choice, _)| *choice == value)
+        .map(|(_, t)| *t)
+        .ok_or_else(|| {
+            let names: Vec<_> = choices.iter().map(|(choice, _)| *choice).collect();
+            format!(
+                "Invalid {name} {value:?}, expected one of: {}",
+                names.join(", ")
+            )
+        })
+}
+
+/// Read an optional flag holding a list of cell paths, or a single one.
+pub fn cell_paths_flag(call: &EvaluatedCall, name: &str) -> Result<Vec<CellPath>, LabeledError> {
+    let paths = match call.get_flag::<Value>(name)? {
+        None => vec![],
+        Some(Value::List { vals, .. }) => vals
+            .into_iter()
+            .map(CellPath::from_value)
+            .collect::<Result<_, _>>()?,
+        Some(path) => vec![CellPath::from_value(path)?],
+    };
+    Ok(paths)
+}
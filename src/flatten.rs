@@ -0,0 +1,81 @@
+//! Flattening of nested records into dotted keys, for `from msgpack --flatten`.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+
+pub struct Flatten {
+    /// Joins the keys of nested records.
+    pub separator: String,
+
+    /// Number of levels of nested records to merge into their parents, or `None` for all.
+    pub depth: Option<usize>,
+}
+
+impl Flatten {
+    /// Read `--flatten` and the flags that tune it.
+    pub fn from_call(call: &EvaluatedCall) -> Result<Option<Self>, LabeledError> {
+        let separator = call.get_flag::<String>("separator")?;
+        let depth = call.get_flag::<i64>("flatten-depth")?;
+        if !call.has_flag("flatten")? {
+            if separator.is_some() || depth.is_some() {
+                return Err(LabeledError {
+                    label: "Missing flag".into(),
+                    msg: "--separator and --flatten-depth only apply with --flatten".into(),
+                    span: Some(call.head),
+                });
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(Flatten {
+            separator: separator.unwrap_or_else(|| ".".into()),
+            depth: depth.map(|depth| depth.max(0) as usize),
+        }))
+    }
+
+    /// Flatten the records in a value. Records in lists are flattened on their own, so a list of
+    /// records becomes a flat table.
+    pub fn apply(&self, value: Value) -> Value {
+        match value {
+            Value::Record { val, internal_span } => {
+                let mut flat = Record::with_capacity(val.len());
+                self.merge(&mut flat, None, val, 0);
+                Value::record(flat, internal_span)
+            }
+            Value::List {
+                vals,
+                internal_span,
+            } => Value::list(
+                vals.into_iter().map(|val| self.apply(val)).collect(),
+                internal_span,
+            ),
+            value => value,
+        }
+    }
+
+    /// Add the fields of `record`, nested `depth` levels deep, to `flat` with `prefix` before
+    /// their keys. Later fields replace earlier ones whose flattened key is the same.
+    fn merge(&self, flat: &mut Record, prefix: Option<&str>, record: Record, depth: usize) {
+        for (key, value) in record {
+            let key = match prefix {
+                Some(prefix) => format!("{prefix}{}{key}", self.separator),
+                None => key,
+            };
+            match value {
+                // empty records have no keys to merge, so they stay as they are
+                Value::Record { val, .. }
+                    if !val.is_empty() && self.depth.is_none_or(|max| depth < max) =>
+                {
+                    self.merge(flat, Some(&key), val, depth + 1)
+                }
+                // records below --flatten-depth stay nested
+                value @ Value::Record { .. } => {
+                    flat.insert(key, value);
+                }
+                value => {
+                    flat.insert(key, self.apply(value));
+                }
+            }
+        }
+    }
+}
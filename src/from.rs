@@ -1,54 +1,1567 @@
+use std::collections::{HashMap, HashSet};
+
 use chrono::NaiveDateTime;
-use nu_plugin::LabeledError;
-use nu_protocol::{Record, Span, Value};
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::ast::CellPath;
+use nu_protocol::{PluginSignature, Record, Span, SyntaxShape, Value};
+
+use crate::ext::{self, ExtCodec};
+use crate::flatten::Flatten;
+use crate::into::{self, NonFiniteMode};
+use crate::path::{Path, PathSegment};
+use crate::schema::Schema;
+use crate::wire::{self, Header, Kind, Reader, WireErrorKind};
+use crate::{bignum, config, decompress, dedup, flags, nu_ext, trace, typed_array};
+
+/// Default for `--max-depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 512;
+
+/// How to convert integers that are too big for a nu int.
+#[derive(Clone, Copy, Default)]
+pub enum BigIntMode {
+    #[default]
+    Error,
+    String,
+    Float,
+}
+
+/// How to convert msgpack strings that aren't valid UTF-8.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidUtf8Mode {
+    #[default]
+    Error,
+
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+
+    /// Return the raw bytes as binary.
+    Binary,
+}
+
+/// What to do when a msgpack map contains the same key more than once.
+#[derive(Clone, Copy, Default)]
+pub enum DuplicateKeyMode {
+    /// Keep the first value.
+    First,
+
+    /// Keep the last value.
+    #[default]
+    Last,
+
+    /// Fail the conversion.
+    Error,
+
+    /// Collect all values of the key into a list.
+    Collect,
+}
+
+/// How to turn a map key that isn't a string into a record column, chosen per type of key with
+/// `--key-coercion`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum KeyCoercion {
+    /// Use the key's string form, e.g. `5`, `true` or `null`. Binaries are decoded as UTF-8,
+    /// replacing invalid sequences.
+    String,
+
+    /// Write binaries as lowercase hex.
+    Hex,
+
+    /// Fail the conversion.
+    Error,
+}
+
+/// Types of keys that `--key-coercion` can be set for.
+const KEY_TYPES: &[&str] = &["int", "float", "bool", "nothing", "binary", "date"];
+
+/// Parse `--key-coercion`, a record of key types and how to coerce keys of that type.
+fn key_coercion_flag(call: &EvaluatedCall) -> Result<HashMap<String, KeyCoercion>, LabeledError> {
+    let Some(flag) = call.get_flag::<Value>("key-coercion")? else {
+        return Ok(HashMap::new());
+    };
+    let span = flag.span();
+    let invalid = |msg: String| LabeledError {
+        label: "Invalid flag value".into(),
+        msg,
+        span: Some(span),
+    };
+
+    let mut policies = HashMap::new();
+    for (key_type, policy) in flag.as_record()?.iter() {
+        if !KEY_TYPES.contains(&key_type.as_str()) {
+            return Err(invalid(format!(
+                "Invalid key type {key_type:?}, expected one of: {}",
+                KEY_TYPES.join(", ")
+            )));
+        }
+        let policy = flags::parse_choice(
+            "key-coercion",
+            &policy.as_string()?,
+            &[
+                ("string", KeyCoercion::String),
+                ("hex", KeyCoercion::Hex),
+                ("error", KeyCoercion::Error),
+            ],
+        )
+        .map_err(invalid)?;
+        if policy == KeyCoercion::Hex && key_type != "binary" {
+            return Err(invalid(format!(
+                "hex only applies to binary keys, not {key_type}"
+            )));
+        }
+        policies.insert(key_type.clone(), policy);
+    }
+    Ok(policies)
+}
+
+/// Options for converting msgpack to nu values.
+pub struct DecodeOptions {
+    /// Ext type id of typed arrays, see [typed_array].
+    pub typed_arrays: Option<i8>,
+
+    /// Ext type id of nu types that msgpack doesn't have, see [nu_ext].
+    pub nu_ext: Option<i8>,
+
+    pub big_int: BigIntMode,
+
+    pub invalid_utf8: InvalidUtf8Mode,
+
+    /// Decode maps with non-string keys to a list of `{key, value}` records.
+    pub key_value_pairs: bool,
+
+    /// Decompress bins at these cell paths and decode the msgpack they hold, see
+    /// [Decoder::decompressed].
+    pub compress_paths: Vec<CellPath>,
+
+    /// Decode `{columns, rows}` maps written by `to msgpack --compact-tables` to tables.
+    pub compact_tables: bool,
+
+    pub duplicate_keys: DuplicateKeyMode,
+
+    /// How to turn keys of each type into columns, by nu type name. Types that aren't in it keep
+    /// the default: ints, floats, dates and binaries that are valid UTF-8 become strings, and
+    /// other keys are an error.
+    pub key_coercion: HashMap<String, KeyCoercion>,
+
+    /// How to decode ext types, as selected by `--namespace`.
+    pub ext_codecs: HashMap<i8, ExtCodec>,
+
+    /// Ext types of numbers too big for nu, see [bignum].
+    pub big_numbers: HashMap<i8, bignum::Kind>,
+
+    /// Number of bytes around the failing offset to show in errors about invalid msgpack.
+    pub error_context: usize,
+
+    /// Maximum nesting depth of arrays, maps and nested documents.
+    pub max_depth: usize,
+
+    /// Maximum length in bytes of a single string, binary or ext value.
+    pub max_size: Option<usize>,
+
+    pub non_finite: NonFiniteMode,
+
+    /// Schema that documents must conform to. Only `from msgpack` has the flag.
+    pub schema: Option<Schema>,
+
+    /// How to flatten nested records into dotted keys. Only `from msgpack` has the flags.
+    pub flatten: Option<Flatten>,
+
+    /// Ext type id of the string table of deduplicated documents, see [dedup].
+    pub dedup: Option<i8>,
+
+    /// Keys to decode of the maps in a top-level array, skipping the values of all others. Only
+    /// `from msgpack` has the flag.
+    pub columns: Option<Vec<String>>,
+
+    /// Decode every value to a `{type, value}` record of its wire type and value, see
+    /// [Decoder::tagged]. Only `from msgpack` has the flag.
+    pub typed: bool,
+
+    /// Replace values that fail to decode with error records instead of failing, see
+    /// [error_record]. Only `from msgpack` has the flag.
+    pub ignore_errors: bool,
+
+    /// Trace decoding decisions and timings to stderr, see [trace].
+    pub debug: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            typed_arrays: None,
+            nu_ext: None,
+            big_int: BigIntMode::default(),
+            invalid_utf8: InvalidUtf8Mode::default(),
+            key_value_pairs: false,
+            compact_tables: false,
+            compress_paths: vec![],
+            duplicate_keys: DuplicateKeyMode::default(),
+            key_coercion: HashMap::new(),
+            ext_codecs: HashMap::new(),
+            big_numbers: HashMap::new(),
+            error_context: 8,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_size: None,
+            non_finite: NonFiniteMode::default(),
+            schema: None,
+            flatten: None,
+            dedup: None,
+            columns: None,
+            typed: false,
+            ignore_errors: false,
+            debug: false,
+        }
+    }
+}
+
+impl DecodeOptions {
+    pub fn from_call(call: &EvaluatedCall, config: &Option<Value>) -> Result<Self, LabeledError> {
+        let mut ext_codecs = ext::namespace_from_call(call, config)?;
+        if let Some(id) = flags::ext_id_or_config(call, config, "uuid-ext")? {
+            ext_codecs.insert(id, ExtCodec::Uuid);
+        }
+        let columns: Option<Vec<String>> = call.get_flag("columns")?;
+        let typed = call.has_flag("typed")?;
+        if typed
+            && (call.has_flag("flatten")?
+                || call.get_flag::<Value>("schema")?.is_some()
+                || columns.is_some())
+        {
+            return Err(LabeledError {
+                label: "Conflicting flags".into(),
+                msg: "--typed can't be used with --schema, --flatten or --columns".into(),
+                span: Some(call.head),
+            });
+        }
+
+        Ok(DecodeOptions {
+            typed_arrays: flags::ext_id_or_config(call, config, "typed-arrays")?,
+            nu_ext: flags::ext_id_or_config(call, config, "nu-ext")?,
+            big_int: flags::choice_or_config(
+                call,
+                config,
+                "big-int",
+                &[
+                    ("error", BigIntMode::Error),
+                    ("string", BigIntMode::String),
+                    ("float", BigIntMode::Float),
+                ],
+            )?,
+            invalid_utf8: match (call.has_flag("lossy")?, call.has_flag("lossy-binary")?) {
+                // Raw values of the old spec are strings and binaries alike.
+                (false, false) if call.has_flag("compat")? => InvalidUtf8Mode::Binary,
+                (false, false) => InvalidUtf8Mode::Error,
+                (true, false) => InvalidUtf8Mode::Lossy,
+                (false, true) => InvalidUtf8Mode::Binary,
+                (true, true) => {
+                    return Err(LabeledError {
+                        label: "Conflicting flags".into(),
+                        msg: "--lossy and --lossy-binary can't be used together".into(),
+                        span: Some(call.head),
+                    })
+                }
+            },
+            key_value_pairs: call.has_flag("key-value-pairs")?,
+            compact_tables: call.has_flag("compact-tables")?,
+            compress_paths: flags::cell_paths_flag(call, "compress-paths")?,
+            duplicate_keys: flags::choice_or_config(
+                call,
+                config,
+                "duplicate-keys",
+                &[
+                    ("first", DuplicateKeyMode::First),
+                    ("last", DuplicateKeyMode::Last),
+                    ("error", DuplicateKeyMode::Error),
+                    ("collect", DuplicateKeyMode::Collect),
+                ],
+            )?,
+            key_coercion: key_coercion_flag(call)?,
+            ext_codecs,
+            big_numbers: bignum::ext_flags(call, config)?.into_iter().collect(),
+            error_context: call.get_flag("error-context")?.unwrap_or(8),
+            max_depth: match call.get_flag("max-depth")? {
+                Some(depth) => depth,
+                None => config::get_usize(config, "max_depth")?.unwrap_or(DEFAULT_MAX_DEPTH),
+            },
+            max_size: match call.get_flag::<Value>("max-size")? {
+                Some(size) => Some(size.as_filesize()?.max(0) as usize),
+                None => config::get_filesize(config, "max_size")?,
+            },
+            non_finite: flags::choice_or_config(
+                call,
+                config,
+                "non-finite",
+                into::NON_FINITE_MODES,
+            )?,
+            schema: Schema::from_flag(call)?,
+            flatten: Flatten::from_call(call)?,
+            dedup: flags::ext_id_or_config(call, config, "dedup")?,
+            columns,
+            typed,
+            ignore_errors: call.has_flag("ignore-errors")?,
+            debug: call.has_flag("debug")?,
+        })
+    }
+}
+
+/// Add the flags parsed by [DecodeOptions::from_call] to a signature.
+pub fn decode_flags(signature: PluginSignature) -> PluginSignature {
+    let sig = signature
+        .named(
+            "typed-arrays",
+            SyntaxShape::Int,
+            "ext type id of packed numeric arrays, which are expanded to lists",
+            None,
+        )
+        .named(
+            "big-int",
+            SyntaxShape::String,
+            "how to convert integers bigger than i64::MAX: error (default), string or float",
+            None,
+        )
+        .switch(
+            "lossy",
+            "replace invalid UTF-8 in strings with U+FFFD instead of failing",
+            None,
+        )
+        .switch(
+            "lossy-binary",
+            "return strings that aren't valid UTF-8 as binary instead of failing",
+            None,
+        )
+        .switch(
+            "compat",
+            "the input uses the old msgpack spec, so return raw values that aren't UTF-8 as binary",
+            None,
+        )
+        .named(
+            "compress-paths",
+            SyntaxShape::List(Box::new(SyntaxShape::CellPath)),
+            "decompress the bins at these cell paths written by to msgpack --compress-paths",
+            None,
+        )
+        .switch(
+            "compact-tables",
+            "decode {columns, rows} maps written by to msgpack --compact-tables to tables",
+            None,
+        )
+        .switch(
+            "key-value-pairs",
+            "decode maps with non-string keys to a list of {key, value} records",
+            None,
+        )
+        .named(
+            "duplicate-keys",
+            SyntaxShape::String,
+            "what to do with duplicate map keys: first, last (default), error, or collect \
+             all values into a list",
+            None,
+        )
+        .named(
+            "key-coercion",
+            SyntaxShape::Record(vec![]),
+            "how to turn map keys of each type into columns: string, hex (binary only) or error, \
+             e.g. {int: error, binary: hex}",
+            None,
+        )
+        .named(
+            "namespace",
+            SyntaxShape::String,
+            "name of the ext namespace in the plugin config to decode ext types with",
+            None,
+        )
+        .named(
+            "uuid-ext",
+            SyntaxShape::Int,
+            "ext type id of 16 byte UUIDs, which are decoded to strings",
+            None,
+        )
+        .named(
+            "nu-ext",
+            SyntaxShape::Int,
+            "ext type id of durations, filesizes, cell paths and ranges tagged by `to msgpack --nu-ext`",
+            None,
+        )
+        .named(
+            "dedup",
+            SyntaxShape::Int,
+            "ext type id of the string table of `to msgpack --dedup`, whose strings are restored",
+            None,
+        )
+        .named(
+            "error-context",
+            SyntaxShape::Int,
+            "number of bytes to show around the failing offset of invalid msgpack (default 8)",
+            None,
+        )
+        .named(
+            "max-depth",
+            SyntaxShape::Int,
+            "maximum nesting depth of arrays and maps (default 512)",
+            None,
+        )
+        .named(
+            "max-size",
+            SyntaxShape::Filesize,
+            "maximum size of a single string, binary or ext value, and of decompressed input",
+            None,
+        )
+        .named(
+            "non-finite",
+            SyntaxShape::String,
+            "how to decode NaN and infinite floats: float (default), error, null or string",
+            None,
+        )
+        .switch(
+            "debug",
+            "trace which conversions were made and how long decoding took to stderr",
+            None,
+        );
+    bignum::signature_flags(sig, "which are decoded to {ext_type, kind, value} records")
+}
+
+/// Decode msgpack bytes to a [nu_protocol::Value].
+///
+/// The bytes must hold exactly one msgpack value.
+pub fn decode(bin: &[u8], opts: &DecodeOptions) -> Result<Value, LabeledError> {
+    let value = decode_nested(bin, opts, 0)?;
+    conform(value, opts)
+}
+
+/// Check a decoded document against the schema, if there is one, and flatten it.
+fn conform(value: Value, opts: &DecodeOptions) -> Result<Value, LabeledError> {
+    let value = match &opts.schema {
+        Some(schema) => schema.conform(value)?,
+        None => value,
+    };
+    Ok(match &opts.flatten {
+        Some(flatten) => flatten.apply(value),
+        None => value,
+    })
+}
 
-/// Convert [rmpv::Value] to a [nu_protocol::Value].
-pub fn rmpv_to_nu(value: rmpv::ValueRef<'_>) -> Result<Value, LabeledError> {
+/// Decode msgpack bytes nested in a value at the given depth, e.g. in an ext payload.
+pub fn decode_nested(
+    bin: &[u8],
+    opts: &DecodeOptions,
+    depth: usize,
+) -> Result<Value, LabeledError> {
+    let (value, len) = decode_prefix(bin, opts, depth)?;
+    check_trailing(bin, len)?;
+    Ok(value)
+}
+
+/// Check that `bin` holds a single msgpack value whose headers [decode] would accept, i.e. that
+/// fit the remaining input, `--max-size` and `--max-depth`, without converting it.
+pub fn check(bin: &[u8], opts: &DecodeOptions) -> Result<(), LabeledError> {
+    let mut decoder = Decoder {
+        reader: Reader::new(bin),
+        opts,
+        path: Path::default(),
+        depth: 0,
+        decompressed: false,
+    };
+    decoder.skip_checked()?;
+    check_trailing(bin, decoder.reader.pos())
+}
+
+fn check_trailing(bin: &[u8], len: usize) -> Result<(), LabeledError> {
+    if len < bin.len() {
+        return Err(LabeledError {
+            label: "Trailing bytes".into(),
+            msg: format!(
+                "Found {} more bytes after the end of the msgpack value at byte {len}",
+                bin.len() - len
+            ),
+            span: None,
+        });
+    }
+    Ok(())
+}
+
+/// Decode the first msgpack value of `bin`, returning it and its length in bytes.
+pub fn decode_prefix(
+    bin: &[u8],
+    opts: &DecodeOptions,
+    depth: usize,
+) -> Result<(Value, usize), LabeledError> {
+    if let (0, Some(ext_type)) = (depth, opts.dedup) {
+        if let Some((expanded, len)) = dedup::expand(bin, ext_type, opts.max_size, opts.debug)? {
+            return Ok((decode_nested(&expanded, opts, depth)?, len));
+        }
+    }
+
+    let mut decoder = Decoder {
+        reader: Reader::new(bin),
+        opts,
+        path: Path::default(),
+        depth,
+        decompressed: false,
+    };
+    let value = decoder.value()?;
+    Ok((value, decoder.reader.pos()))
+}
+
+/// Decode the first msgpack value of `bin`, which was found at `path` in a document, so that
+/// [DecodeOptions::compress_paths] match it like they would in the whole document.
+pub fn decode_prefix_at(
+    bin: &[u8],
+    opts: &DecodeOptions,
+    path: Path,
+) -> Result<(Value, usize), LabeledError> {
+    let mut decoder = Decoder {
+        reader: Reader::new(bin),
+        opts,
+        path,
+        depth: 0,
+        decompressed: false,
+    };
+    let value = decoder.value()?;
+    Ok((value, decoder.reader.pos()))
+}
+
+/// Decode the first msgpack value of `bin` to a `{value, rest}` record, where `rest` holds the
+/// bytes after it.
+pub fn decode_with_rest(bin: &[u8], opts: &DecodeOptions) -> Result<Value, LabeledError> {
     let span = Span::unknown();
-    Ok(match value {
-        rmpv::ValueRef::Nil => Value::nothing(span),
-        rmpv::ValueRef::Boolean(b) => Value::bool(b, span),
-        rmpv::ValueRef::Integer(i) => {
-            let i = i.as_i64().ok_or(LabeledError {
-                label: "Integer overflow".into(),
-                msg: "Encountered a msgpack integer bigger than what nushell supports (i64::MAX)."
-                    .into(),
+    let (value, len) = decode_prefix(bin, opts, 0)?;
+    let mut record = Record::new();
+    record.push("value", conform(value, opts)?);
+    record.push("rest", Value::binary(&bin[len..], span));
+    Ok(Value::record(record, span))
+}
+
+/// Decode only the elements `skip..skip + take` of a top-level array to a list, skipping over the
+/// elements before them using their length headers and not reading the ones after them at all.
+///
+/// With [DecodeOptions::columns], the elements must be maps, and only the values of those keys
+/// are decoded.
+pub fn decode_window(
+    bin: &[u8],
+    opts: &DecodeOptions,
+    skip: usize,
+    take: Option<usize>,
+) -> Result<Value, LabeledError> {
+    if let Some(ext_type) = opts.dedup {
+        if let Some((expanded, _)) = dedup::expand(bin, ext_type, opts.max_size, opts.debug)? {
+            return decode_window(&expanded, opts, skip, take);
+        }
+    }
+
+    let mut decoder = Decoder {
+        reader: Reader::new(bin),
+        opts,
+        path: Path::default(),
+        depth: 1,
+        decompressed: false,
+    };
+    let header = decoder
+        .reader
+        .read_header()
+        .map_err(|e| decoder.invalid(0, e.to_string()))?;
+    let Kind::Array(len) = header.kind else {
+        return Err(LabeledError {
+            label: "Not an array".into(),
+            msg: format!(
+                "--skip, --take and --columns need an array at the top level, found {}",
+                header.kind.family()
+            ),
+            span: None,
+        });
+    };
+
+    let len = len as usize;
+    let skip = skip.min(len);
+    for i in 0..skip {
+        let offset = decoder.reader.pos();
+        decoder.reader.skip_value().map_err(|e| {
+            decoder.path.push(PathSegment::Index(i));
+            decoder.invalid(offset, e.to_string())
+        })?;
+    }
+
+    let take = take.unwrap_or(len).min(len - skip);
+    let mut vals = Vec::with_capacity(take.min(decoder.reader.remaining()));
+    for i in skip..skip + take {
+        // with --ignore-errors, a truncated element ends the input
+        if opts.ignore_errors && decoder.reader.is_empty() {
+            break;
+        }
+        decoder.path.push(PathSegment::Index(i));
+        let offset = decoder.reader.pos();
+        let value = match &opts.columns {
+            Some(columns) => match decoder.project(columns) {
+                // the element can't be skipped, so the rest of the input can't be read
+                Err(e) if opts.ignore_errors => {
+                    vals.push(error_record(offset, &e));
+                    break;
+                }
+                value => value?,
+            },
+            None => decoder.value()?,
+        };
+        vals.push(value);
+        decoder.path.pop();
+    }
+    align_columns(&mut vals);
+    conform(Value::list(vals, Span::unknown()), opts)
+}
+
+/// Decode a stream of frames, each a big-endian u32 length followed by a msgpack value of that
+/// many bytes, to a list of the values.
+pub fn decode_frames(bin: &[u8], opts: &DecodeOptions) -> Result<Value, LabeledError> {
+    let mut vals = vec![];
+    let mut offset = 0;
+    while offset < bin.len() {
+        let truncated = |msg: String| LabeledError {
+            label: "Truncated frame".into(),
+            msg: format!("Frame {} at byte {offset} {msg}", vals.len()),
+            span: None,
+        };
+        let frame = match bin.get(offset..offset + 4) {
+            None => {
+                let remaining = bin.len() - offset;
+                Err(truncated(format!(
+                    "has only {remaining} of the 4 bytes of its length"
+                )))
+            }
+            Some(prefix) => {
+                let len = u32::from_be_bytes(prefix.try_into().expect("4 bytes")) as usize;
+                let start = offset + 4;
+                bin.get(start..start + len).ok_or_else(|| {
+                    let remaining = bin.len() - start;
+                    truncated(format!(
+                        "claims {len} bytes, but only {remaining} bytes of input remain"
+                    ))
+                })
+            }
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            // the rest of the input can't be split into frames
+            Err(e) if opts.ignore_errors => {
+                vals.push(error_record(offset, &e));
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let value = decode(frame, opts).map_err(|mut e| {
+            e.msg = format!("In frame {} at byte {offset}: {}", vals.len(), e.msg);
+            e
+        })?;
+        vals.push(value);
+        offset += 4 + frame.len();
+    }
+    align_columns(&mut vals);
+    Ok(Value::list(vals, Span::unknown()))
+}
+
+/// Converts msgpack to nu values while keeping track of where in the input it is, to point out
+/// the offending value in errors.
+struct Decoder<'a, 'o> {
+    reader: Reader<'a>,
+    opts: &'o DecodeOptions,
+    path: Path,
+
+    /// Depth of the document in the value it's nested in.
+    depth: usize,
+
+    /// Whether the document was decompressed from a bin at `--compress-paths`, so that its root
+    /// isn't decompressed again.
+    decompressed: bool,
+}
+
+/// A container whose elements are being converted.
+struct Container {
+    /// Offset of the container header.
+    offset: usize,
+
+    wire_type: &'static str,
+
+    len: usize,
+
+    kind: ContainerKind,
+}
+
+enum ContainerKind {
+    Array(Vec<Value>),
+
+    Map {
+        entries: Vec<Entry>,
+
+        /// The key of the entry whose value is being converted.
+        key: Option<Key>,
+
+        all_string_keys: bool,
+    },
+}
+
+/// A converted map key.
+struct Key {
+    offset: usize,
+    value: Value,
+
+    /// The key as a record column, which fails for keys that can't be strings.
+    name: Result<String, LabeledError>,
+}
+
+struct Entry {
+    key: Key,
+    value: Value,
+}
+
+/// A converted value and where it came from.
+struct Converted {
+    offset: usize,
+    value: Value,
+    is_string: bool,
+
+    /// Wire type to tag the value with for `--typed`, or `None` for values that are already
+    /// tagged or are error records.
+    wire_type: Option<&'static str>,
+}
+
+impl<'a> Decoder<'a, '_> {
+    /// Convert the next msgpack value.
+    ///
+    /// This uses an explicit stack of containers rather than recursion, so that deeply nested
+    /// input fails with an error instead of overflowing the stack.
+    fn value(&mut self) -> Result<Value, LabeledError> {
+        let mut stack: Vec<Container> = vec![];
+        loop {
+            let offset = self.reader.pos();
+            let header = match self.reader.read_header() {
+                Ok(header) => header,
+                Err(e) => {
+                    let value = self.recover(offset, self.invalid(offset, e.to_string()))?;
+                    // a reserved marker is a single byte, so there's a value after it
+                    if let WireErrorKind::ReservedMarker = e.kind {
+                        self.reader.read_data(1).expect("the marker was read");
+                        match self.attach_error(&mut stack, offset, value)? {
+                            Some(value) => return Ok(value),
+                            None => continue,
+                        }
+                    }
+                    return self.close(stack, offset, value);
+                }
+            };
+            let data = match self.check_size(&header).and_then(|()| {
+                self.reader
+                    .read_data(header.data_len())
+                    .map_err(|e| self.invalid(offset, e.to_string()))
+            }) {
+                Ok(data) => data,
+                Err(e) => {
+                    let value = self.recover(offset, e)?;
+                    // values that are only bigger than --max-size can be skipped
+                    if header.child_count() > 0 || self.reader.read_data(header.data_len()).is_err()
+                    {
+                        return self.close(stack, offset, value);
+                    }
+                    match self.attach_error(&mut stack, offset, value)? {
+                        Some(value) => return Ok(value),
+                        None => continue,
+                    }
+                }
+            };
+
+            // lengths may be corrupt, so don't allocate more than the input could hold
+            let capacity = |len: u32| (len as usize).min(self.reader.remaining());
+            let kind = match header.kind {
+                Kind::Array(len) => ContainerKind::Array(Vec::with_capacity(capacity(len))),
+                Kind::Map(len) => ContainerKind::Map {
+                    entries: Vec::with_capacity(capacity(len)),
+                    key: None,
+                    all_string_keys: true,
+                },
+                _ => {
+                    let converted = match self.scalar(offset, header.kind, data, stack.len()) {
+                        Ok(value) => Converted {
+                            offset,
+                            value,
+                            is_string: matches!(header.kind, Kind::Str(_)),
+                            wire_type: Some(header.type_name()),
+                        },
+                        Err(e) => Converted {
+                            offset,
+                            value: self.recover(offset, e)?,
+                            is_string: false,
+                            wire_type: None,
+                        },
+                    };
+                    match self.attach(&mut stack, converted)? {
+                        Some(value) => return Ok(value),
+                        None => continue,
+                    }
+                }
+            };
+
+            if self.depth + stack.len() >= self.opts.max_depth {
+                let value = self.recover(offset, self.too_deep(offset))?;
+                self.reader = Reader::at(self.reader.input(), offset);
+                if self.reader.skip_value().is_err() {
+                    return self.close(stack, offset, value);
+                }
+                match self.attach_error(&mut stack, offset, value)? {
+                    Some(value) => return Ok(value),
+                    None => continue,
+                }
+            }
+
+            let container = Container {
+                offset,
+                wire_type: header.type_name(),
+                len: header.child_count() as usize,
+                kind,
+            };
+            if container.len > 0 {
+                if let ContainerKind::Array(_) = container.kind {
+                    self.path.push(PathSegment::Index(0));
+                }
+                stack.push(container);
+                continue;
+            }
+
+            let value = self.finish(container)?;
+            let converted = Converted {
+                offset,
+                value,
+                is_string: false,
+                wire_type: None,
+            };
+            if let Some(value) = self.attach(&mut stack, converted)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Convert only the values of `columns` of the map at the reader to a record with these
+    /// columns, in that order and nothing for missing keys, skipping over all other entries.
+    fn project(&mut self, columns: &[String]) -> Result<Value, LabeledError> {
+        let offset = self.reader.pos();
+        let header = self
+            .reader
+            .read_header()
+            .map_err(|e| self.invalid(offset, e.to_string()))?;
+        let Kind::Map(len) = header.kind else {
+            return Err(self.at(
+                offset,
+                LabeledError {
+                    label: "Not a map".into(),
+                    msg: format!(
+                        "--columns needs an array of maps, found {}",
+                        header.kind.family()
+                    ),
+                    span: None,
+                },
+            ));
+        };
+
+        let mut found: Vec<Option<Value>> = vec![None; columns.len()];
+        self.depth += 1;
+        for _ in 0..len {
+            let key_offset = self.reader.pos();
+            let key = self
+                .reader
+                .skip_value()
+                .map_err(|e| self.invalid(key_offset, e.to_string()))?;
+            let column = match key.kind {
+                Kind::Str(len) => {
+                    let name =
+                        &self.reader.input()[self.reader.pos() - len as usize..self.reader.pos()];
+                    columns.iter().position(|column| column.as_bytes() == name)
+                }
+                _ => None,
+            };
+            match column {
+                Some(i) => {
+                    self.path.push(PathSegment::Key(columns[i].clone()));
+                    found[i] = Some(self.value()?);
+                    self.path.pop();
+                }
+                None => {
+                    let value_offset = self.reader.pos();
+                    self.reader
+                        .skip_value()
+                        .map_err(|e| self.invalid(value_offset, e.to_string()))?;
+                }
+            }
+        }
+        self.depth -= 1;
+
+        let span = Span::unknown();
+        let mut record = Record::with_capacity(columns.len());
+        for (column, value) in columns.iter().zip(found) {
+            record.push(column, value.unwrap_or_else(|| Value::nothing(span)));
+        }
+        Ok(Value::record(record, span))
+    }
+
+    /// With `--ignore-errors`, the record that takes the place of a value that failed to decode.
+    fn recover(&self, offset: usize, e: LabeledError) -> Result<Value, LabeledError> {
+        match self.opts.ignore_errors {
+            true => Ok(error_record(offset, &e)),
+            false => Err(e),
+        }
+    }
+
+    fn attach_error(
+        &mut self,
+        stack: &mut Vec<Container>,
+        offset: usize,
+        value: Value,
+    ) -> Result<Option<Value>, LabeledError> {
+        let converted = Converted {
+            offset,
+            value,
+            is_string: false,
+            wire_type: None,
+        };
+        self.attach(stack, converted)
+    }
+
+    /// With `--ignore-errors`, finish the containers that the input ended in the middle of,
+    /// with the error record of where it ended as their last value.
+    fn close(
+        &mut self,
+        mut stack: Vec<Container>,
+        offset: usize,
+        mut value: Value,
+    ) -> Result<Value, LabeledError> {
+        // whatever is left can't be read, so don't complain about trailing bytes
+        let input = self.reader.input();
+        self.reader = Reader::at(input, input.len());
+
+        while let Some(mut container) = stack.pop() {
+            match &mut container.kind {
+                ContainerKind::Array(vals) => vals.push(value),
+                ContainerKind::Map { entries, key, .. } => {
+                    // the input ended where a key was expected, so make one up for the error
+                    let key = key.take().unwrap_or_else(|| Key {
+                        offset,
+                        value: Value::string("<error>", Span::unknown()),
+                        name: Ok("<error>".into()),
+                    });
+                    entries.push(Entry { key, value });
+                }
+            }
+            value = self.finish(container)?;
+        }
+        Ok(value)
+    }
+
+    /// Add a converted value to the container it's in, and finish all containers that are
+    /// complete because of it. Returns the top-level value once it's complete.
+    fn attach(
+        &mut self,
+        stack: &mut Vec<Container>,
+        mut converted: Converted,
+    ) -> Result<Option<Value>, LabeledError> {
+        loop {
+            let Some(container) = stack.last_mut() else {
+                return Ok(Some(self.tagged(converted)));
+            };
+
+            let filled = match &mut container.kind {
+                ContainerKind::Array(vals) => {
+                    vals.push(self.tagged(converted));
+                    self.path.pop();
+                    vals.len()
+                }
+                ContainerKind::Map {
+                    entries,
+                    key,
+                    all_string_keys,
+                } => match key.take() {
+                    None => {
+                        let name = match &converted.value {
+                            // record keys must be strings, so don't fall back to binary for keys
+                            Value::Binary { val, .. } if converted.is_string => {
+                                Ok(String::from_utf8_lossy(val).into_owned())
+                            }
+                            k => self.key_name(k),
+                        };
+                        let segment = match &name {
+                            Ok(name) => name.clone(),
+                            Err(_) => format!("<{}>", converted.value.get_type()),
+                        };
+                        self.path.push(PathSegment::Key(segment));
+
+                        *all_string_keys &= converted.is_string;
+                        *key = Some(Key {
+                            offset: converted.offset,
+                            value: self.tagged(converted),
+                            name,
+                        });
+                        return Ok(None);
+                    }
+                    Some(key) => {
+                        entries.push(Entry {
+                            key,
+                            value: self.tagged(converted),
+                        });
+                        self.path.pop();
+                        entries.len() * 2
+                    }
+                },
+            };
+
+            if filled < container.len {
+                if let ContainerKind::Array(vals) = &container.kind {
+                    self.path.push(PathSegment::Index(vals.len()));
+                }
+                return Ok(None);
+            }
+
+            let container = stack.pop().expect("checked by last_mut");
+            converted = Converted {
+                offset: container.offset,
+                value: self.finish(container)?,
+                is_string: false,
+                wire_type: None,
+            };
+        }
+    }
+
+    /// The record column of a map key that isn't a string, following `--key-coercion`.
+    fn key_name(&self, key: &Value) -> Result<String, LabeledError> {
+        let key_type = key.get_type().to_string();
+        match (key, self.opts.key_coercion.get(&key_type)) {
+            (_, None) => Ok(key.as_string()?),
+            (_, Some(KeyCoercion::Error)) => Err(LabeledError {
+                label: "Invalid key".into(),
+                msg: format!("Encountered a msgpack map with a key of type {key_type}, which --key-coercion doesn't allow"),
                 span: None,
-            })?;
-            Value::int(i, span)
-        }
-        rmpv::ValueRef::F32(f) => Value::float(f.into(), span),
-        rmpv::ValueRef::F64(f) => Value::float(f, span),
-        rmpv::ValueRef::String(s) => {
-            let s = s.into_str().ok_or(LabeledError {
-                label: "Invalid UTF-8".into(),
-                msg: "Encountered a msgpack string that was not valid UTF-8".into(),
+            }),
+            (Value::Binary { val, .. }, Some(KeyCoercion::Hex)) => {
+                Ok(val.iter().map(|b| format!("{b:02x}")).collect())
+            }
+            (Value::Binary { val, .. }, Some(_)) => Ok(String::from_utf8_lossy(val).into_owned()),
+            (Value::Bool { val, .. }, Some(_)) => Ok(val.to_string()),
+            (Value::Nothing { .. }, Some(_)) => Ok("null".into()),
+            (key, Some(_)) => Ok(key.as_string()?),
+        }
+    }
+
+    /// With `--typed`, a `{type, value}` record of a converted value and its wire type.
+    fn tagged(&self, converted: Converted) -> Value {
+        match converted.wire_type {
+            Some(wire_type) if self.opts.typed => typed_record(wire_type, converted.value),
+            _ => converted.value,
+        }
+    }
+
+    /// Convert a complete container.
+    fn finish(&self, container: Container) -> Result<Value, LabeledError> {
+        let span = Span::unknown();
+        if self.opts.typed {
+            // keep the keys of maps as tagged values, so that their wire types aren't lost
+            let value = match container.kind {
+                ContainerKind::Array(vals) => Value::list(vals, span),
+                ContainerKind::Map { entries, .. } => {
+                    let pairs = entries
+                        .into_iter()
+                        .map(|entry| {
+                            let mut pair = Record::new();
+                            pair.push("key", entry.key.value);
+                            pair.push("value", entry.value);
+                            Value::record(pair, span)
+                        })
+                        .collect();
+                    Value::list(pairs, span)
+                }
+            };
+            return Ok(typed_record(container.wire_type, value));
+        }
+        match container.kind {
+            ContainerKind::Array(mut vals) => {
+                align_columns(&mut vals);
+                Ok(Value::list(vals, span))
+            }
+            ContainerKind::Map {
+                entries,
+                all_string_keys,
+                ..
+            } => self
+                .map(entries, all_string_keys)
+                .or_else(|e| self.recover(container.offset, e)),
+        }
+    }
+
+    /// Convert a non-container value.
+    fn scalar(
+        &self,
+        offset: usize,
+        kind: Kind,
+        data: &'a [u8],
+        depth: usize,
+    ) -> Result<Value, LabeledError> {
+        let span = Span::unknown();
+        let opts = self.opts;
+        let at = |e: LabeledError| self.at(offset, e);
+
+        Ok(match kind {
+            // typed output is about the wire, so leave ext payloads as they are
+            Kind::Ext(ext_type, _) if opts.typed => unknown_ext_to_nu(ext_type, data),
+            Kind::Nil => Value::nothing(span),
+            Kind::Bool(b) => Value::bool(b, span),
+            Kind::Int(i) => Value::int(i, span),
+            Kind::Uint(u) => match (i64::try_from(u), opts.big_int) {
+                (Ok(i), _) => Value::int(i, span),
+                (Err(_), BigIntMode::String) => {
+                    self.trace(|| format!("integer {u} decoded as a string"));
+                    Value::string(u.to_string(), span)
+                }
+                (Err(_), BigIntMode::Float) => {
+                    self.trace(|| format!("integer {u} decoded as a float"));
+                    Value::float(u as f64, span)
+                }
+                (Err(_), BigIntMode::Error) => {
+                    return Err(at(LabeledError {
+                        label: "Integer overflow".into(),
+                        msg: "Encountered a msgpack integer bigger than what nushell supports \
+                              (i64::MAX). Use --big-int to convert it to a string or float."
+                            .into(),
+                        span: None,
+                    }))
+                }
+            },
+            Kind::F32 => {
+                let bytes = self.header_bytes(offset);
+                let float = f32::from_be_bytes(bytes[1..].try_into().expect("f32 has 4 bytes"));
+                self.float(offset, float.into())?
+            }
+            Kind::F64 => {
+                let bytes = self.header_bytes(offset);
+                let float = f64::from_be_bytes(bytes[1..].try_into().expect("f64 has 8 bytes"));
+                self.float(offset, float)?
+            }
+            Kind::Str(_) => match (std::str::from_utf8(data), opts.invalid_utf8) {
+                (Ok(s), _) => Value::string(s, span),
+                (Err(_), InvalidUtf8Mode::Lossy) => {
+                    self.trace(|| "invalid UTF-8 replaced".into());
+                    Value::string(String::from_utf8_lossy(data), span)
+                }
+                (Err(_), InvalidUtf8Mode::Binary) => {
+                    self.trace(|| "string of invalid UTF-8 decoded as binary".into());
+                    Value::binary(data, span)
+                }
+                (Err(_), InvalidUtf8Mode::Error) => {
+                    return Err(at(LabeledError {
+                        label: "Invalid UTF-8".into(),
+                        msg: "Encountered a msgpack string that was not valid UTF-8. \
+                              Use --lossy or --lossy-binary to decode it anyway."
+                            .into(),
+                        span: None,
+                    }))
+                }
+            },
+            Kind::Bin(_) => match self.decompressed(data, depth) {
+                Some(value) => value.map_err(at)?,
+                None => Value::binary(data, span),
+            },
+            Kind::Ext(ext_type, _) => {
+                let handler = match ext_type {
+                    -1 => "timestamp",
+                    d if Some(d) == opts.typed_arrays => "typed array",
+                    d if Some(d) == opts.nu_ext => "nu type",
+                    d if opts.big_numbers.contains_key(&d) => "big number",
+                    d if opts.ext_codecs.contains_key(&d) => "ext codec",
+                    _ => "no handler, kept as a record",
+                };
+                self.trace(|| format!("ext type {ext_type}: {handler}"));
+                match ext_type {
+                    // timestamp extension type
+                    -1 => ext_timestamp_to_nu(data).map_err(at)?,
+                    d if Some(d) == opts.typed_arrays => typed_array::unpack(data).map_err(at)?,
+                    d if Some(d) == opts.nu_ext => nu_ext::decode(data).map_err(at)?,
+                    d if opts.big_numbers.contains_key(&d) => {
+                        bignum::decode(d, opts.big_numbers[&d], data).map_err(at)?
+                    }
+                    d if opts.ext_codecs.contains_key(&d) => opts.ext_codecs[&d]
+                        .decode(data, opts, self.depth + depth + 1)
+                        .map_err(at)?,
+                    _ => unknown_ext_to_nu(ext_type, data),
+                }
+            }
+            Kind::Array(_) | Kind::Map(_) => unreachable!("containers aren't scalars"),
+        })
+    }
+
+    /// Convert the entries of a map.
+    fn map(&self, entries: Vec<Entry>, all_string_keys: bool) -> Result<Value, LabeledError> {
+        let span = Span::unknown();
+        let opts = self.opts;
+
+        if opts.key_value_pairs && !all_string_keys {
+            let pairs = entries
+                .into_iter()
+                .map(|entry| {
+                    let mut pair = Record::new();
+                    pair.push("key", entry.key.value);
+                    pair.push("value", entry.value);
+                    Value::record(pair, span)
+                })
+                .collect();
+            return Ok(Value::list(pairs, span));
+        }
+
+        let mut record = Record::new();
+
+        // keys whose values have been collected into a list by DuplicateKeyMode::Collect
+        let mut collected = HashSet::new();
+
+        for Entry { key, value: v } in entries {
+            let k = key.name.map_err(|e| self.at(key.offset, e))?;
+            let Some(existing) = record.get_mut(&k) else {
+                record.push(k, v);
+                continue;
+            };
+            match opts.duplicate_keys {
+                DuplicateKeyMode::First => {}
+                DuplicateKeyMode::Last => *existing = v,
+                DuplicateKeyMode::Error => {
+                    return Err(self.at(
+                        key.offset,
+                        LabeledError {
+                            label: "Duplicate key".into(),
+                            msg: format!("Encountered a msgpack map with duplicate key {k:?}"),
+                            span: None,
+                        },
+                    ))
+                }
+                DuplicateKeyMode::Collect => {
+                    if collected.insert(k) {
+                        let first = std::mem::replace(existing, Value::nothing(span));
+                        *existing = Value::list(vec![first], span);
+                    }
+                    let Value::List { vals, .. } = existing else {
+                        unreachable!("collected keys hold lists");
+                    };
+                    vals.push(v);
+                }
+            }
+        }
+
+        match opts.compact_tables {
+            true => Ok(expand_table(record)),
+            false => Ok(Value::record(record, span)),
+        }
+    }
+
+    /// Skip over the next value, checking its headers like [Decoder::value] does but without
+    /// converting anything.
+    fn skip_checked(&mut self) -> Result<(), LabeledError> {
+        // values left to skip in each open container, below the one for the value itself
+        let mut pending: Vec<u64> = vec![1];
+        while let Some(count) = pending.last_mut() {
+            if *count == 0 {
+                pending.pop();
+                continue;
+            }
+            *count -= 1;
+
+            let offset = self.reader.pos();
+            let header = self
+                .reader
+                .read_header()
+                .map_err(|e| self.invalid(offset, e.to_string()))?;
+            self.check_size(&header)?;
+            self.reader
+                .read_data(header.data_len())
+                .map_err(|e| self.invalid(offset, e.to_string()))?;
+            if let Kind::Array(_) | Kind::Map(_) = header.kind {
+                // the first pending count is for the value itself, not a container
+                if self.depth + pending.len() > self.opts.max_depth {
+                    return Err(self.too_deep(offset));
+                }
+                pending.push(header.child_count());
+            }
+        }
+        Ok(())
+    }
+
+    fn too_deep(&self, offset: usize) -> LabeledError {
+        self.at(
+            offset,
+            LabeledError {
+                label: "Nesting too deep".into(),
+                msg: format!(
+                    "Encountered msgpack nested deeper than {} levels. \
+                     Use --max-depth to allow deeper nesting.",
+                    self.opts.max_depth
+                ),
                 span: None,
-            })?;
-            Value::string(s, span)
+            },
+        )
+    }
+
+    /// Check the length claimed by a header that was just read against the remaining input and
+    /// `--max-size`, before anything is allocated for the value.
+    fn check_size(&self, header: &Header) -> Result<(), LabeledError> {
+        let len = header.data_len();
+        if let Some(max_size) = self.opts.max_size.filter(|max| len > *max) {
+            return Err(self.at(
+                header.offset,
+                LabeledError {
+                    label: "Value too large".into(),
+                    msg: format!(
+                        "Encountered a msgpack {} of {len} bytes, which is more than \
+                         --max-size ({max_size} bytes)",
+                        header.type_name()
+                    ),
+                    span: None,
+                },
+            ));
         }
-        rmpv::ValueRef::Binary(b) => Value::binary(b, span),
-        rmpv::ValueRef::Array(vs) => {
-            let vs: Result<_, LabeledError> = vs.into_iter().map(rmpv_to_nu).collect();
-            Value::list(vs?, span)
+
+        // every nested value takes at least one byte
+        let (claimed, unit) = match header.kind {
+            // with --ignore-errors, decode what's there of truncated containers
+            Kind::Array(_) | Kind::Map(_) if self.opts.ignore_errors => return Ok(()),
+            Kind::Array(_) | Kind::Map(_) => (header.child_count(), "values"),
+            _ => (len as u64, "bytes"),
+        };
+        let remaining = self.reader.remaining();
+        if claimed > remaining as u64 {
+            return Err(self.invalid(
+                header.offset,
+                format!(
+                    "{} at byte {} claims {claimed} {unit}, but only {remaining} bytes of input \
+                     remain",
+                    header.type_name(),
+                    header.offset
+                ),
+            ));
         }
-        rmpv::ValueRef::Map(map) => {
-            let mut record = Record::new();
 
-            for (k, v) in map {
-                record.insert(rmpv_to_nu(k)?.as_string()?, rmpv_to_nu(v)?);
+        Ok(())
+    }
+
+    /// The bytes of a header that was just read from `offset`, when there's no payload.
+    fn header_bytes(&self, offset: usize) -> &'a [u8] {
+        &self.reader.input()[offset..self.reader.pos()]
+    }
+
+    /// Error for input that isn't valid msgpack, at the value starting at `offset`.
+    fn invalid(&self, offset: usize, mut msg: String) -> LabeledError {
+        if !self.path.is_root() {
+            msg += &format!(", in the value at {}", self.path);
+        }
+        if self.opts.error_context > 0 {
+            let window = wire::hex_window(self.reader.input(), offset, self.opts.error_context);
+            msg += &format!("\n{window}");
+        }
+
+        LabeledError {
+            label: "Invalid msgpack".into(),
+            msg,
+            span: None,
+        }
+    }
+
+    fn float(&self, offset: usize, float: f64) -> Result<Value, LabeledError> {
+        let span = Span::unknown();
+        Ok(match self.opts.non_finite {
+            _ if float.is_finite() => Value::float(float, span),
+            NonFiniteMode::Float => Value::float(float, span),
+            NonFiniteMode::Null => {
+                self.trace(|| format!("{} decoded as null", into::non_finite_name(float)));
+                Value::nothing(span)
+            }
+            NonFiniteMode::String => {
+                self.trace(|| format!("{} decoded as a string", into::non_finite_name(float)));
+                Value::string(into::non_finite_name(float), span)
             }
+            NonFiniteMode::Error => {
+                return Err(self.at(
+                    offset,
+                    LabeledError {
+                        label: "Non-finite float".into(),
+                        msg: format!(
+                            "Found {}, use --non-finite to convert it",
+                            into::non_finite_name(float)
+                        ),
+                        span: None,
+                    },
+                ))
+            }
+        })
+    }
 
-            Value::record(record, span)
+    /// If the current path is one of the `--compress-paths` and `data` is gzip, decode the
+    /// msgpack that it decompresses to.
+    fn decompressed(&self, data: &[u8], depth: usize) -> Option<Result<Value, LabeledError>> {
+        let compressed = self
+            .opts
+            .compress_paths
+            .iter()
+            .any(|path| self.path.matches(&path.members));
+        if !compressed || (self.decompressed && depth == 0) {
+            return None;
         }
-        rmpv::ValueRef::Ext(discriminant, data) => {
-            match discriminant {
-                // timestamp extension type
-                -1 => ext_timestamp_to_nu(data)?,
-                _ => unknown_ext_to_nu(discriminant, data),
+        let decompressed = match decompress::gunzip(data, self.opts.max_size)? {
+            Ok(decompressed) => decompressed,
+            Err(e) => {
+                return Some(Err(LabeledError {
+                    label: "Invalid compressed value".into(),
+                    msg: format!("Failed to decompress a bin at --compress-paths: {e}"),
+                    span: None,
+                }))
+            }
+        };
+        self.trace(|| {
+            format!(
+                "decompressed {} bytes to {}",
+                data.len(),
+                decompressed.len()
+            )
+        });
+
+        // paths inside the value continue from here, for compressed values nested in it
+        let mut decoder = Decoder {
+            reader: Reader::new(&decompressed),
+            opts: self.opts,
+            path: self.path.clone(),
+            depth: self.depth + depth + 1,
+            decompressed: true,
+        };
+        let value = decoder
+            .value()
+            .and_then(|value| match decoder.reader.remaining() {
+                0 => Ok(value),
+                n => Err(LabeledError {
+                    label: "Trailing bytes".into(),
+                    msg: format!(
+                        "Found {n} more bytes after the end of the compressed msgpack value"
+                    ),
+                    span: None,
+                }),
+            });
+        Some(value)
+    }
+
+    /// Trace a decision about the value being decoded, with `--debug`.
+    fn trace(&self, msg: impl FnOnce() -> String) {
+        trace::log(self.opts.debug, "decode", || {
+            format!("{}: {}", self.path, msg())
+        });
+    }
+
+    /// Add the location of the value starting at `offset` to an error about it.
+    fn at(&self, offset: usize, mut e: LabeledError) -> LabeledError {
+        e.msg += &format!(" (at byte {offset}, in the value at {})", self.path);
+        e
+    }
+}
+
+/// Turn a `{columns, rows}` record written by `to msgpack --compact-tables` back into a table,
+/// or return it as a record if it isn't one.
+fn expand_table(record: Record) -> Value {
+    let span = Span::unknown();
+    if !is_compact_table(&record) {
+        return Value::record(record, span);
+    }
+
+    let (mut columns, mut rows) = (vec![], vec![]);
+    for (key, value) in record {
+        if let Value::List { vals, .. } = value {
+            match key.as_str() {
+                "columns" => columns = vals,
+                _ => rows = vals,
             }
         }
-    })
+    }
+    let columns: Vec<String> = columns
+        .into_iter()
+        .map(|column| column.as_string().expect("checked by is_compact_table"))
+        .collect();
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            let Value::List { vals, .. } = row else {
+                unreachable!("checked by is_compact_table");
+            };
+            Value::record(columns.iter().cloned().zip(vals).collect(), span)
+        })
+        .collect();
+    Value::list(rows, span)
+}
+
+/// Check that a record has exactly a `columns` list of distinct strings and a `rows` list of
+/// lists with a value for each column.
+fn is_compact_table(record: &Record) -> bool {
+    let (Some(Value::List { vals: columns, .. }), Some(Value::List { vals: rows, .. })) =
+        (record.get("columns"), record.get("rows"))
+    else {
+        return false;
+    };
+    let mut seen = HashSet::new();
+    record.len() == 2
+        && columns.iter().all(|column| match column {
+            Value::String { val, .. } => seen.insert(val),
+            _ => false,
+        })
+        && rows.iter().all(|row| match row {
+            Value::List { vals, .. } => vals.len() == columns.len(),
+            _ => false,
+        })
+}
+
+/// If a list holds records that all have the same columns, put the columns of each record in the
+/// order of the first one. Nu only sees a list of records as a table if their columns are in the
+/// same order, but msgpack producers don't always write map keys in a consistent order.
+fn align_columns(vals: &mut [Value]) {
+    let Some(Value::Record { val: first, .. }) = vals.first() else {
+        return;
+    };
+    let same_columns = |val: &Value| match val {
+        Value::Record { val, .. } => {
+            val.len() == first.len() && first.columns().all(|column| val.contains(column))
+        }
+        _ => false,
+    };
+    if vals.len() < 2 || !vals.iter().all(same_columns) {
+        return;
+    }
+
+    let columns: Vec<String> = first.columns().cloned().collect();
+    for val in &mut vals[1..] {
+        let Value::Record { val: record, .. } = val else {
+            unreachable!("checked by same_columns");
+        };
+        if record.columns().eq(columns.iter()) {
+            continue;
+        }
+        let mut aligned = Record::with_capacity(columns.len());
+        for column in &columns {
+            let value = record.remove(column).expect("checked by same_columns");
+            aligned.push(column.clone(), value);
+        }
+        *record = aligned;
+    }
+}
+
+/// The `{error: {label, reason, offset}}` record that takes the place of a value that failed to
+/// decode with `--ignore-errors`.
+fn error_record(offset: usize, e: &LabeledError) -> Value {
+    let span = Span::unknown();
+    let mut error = Record::new();
+    error.push("label", Value::string(&e.label, span));
+    error.push("reason", Value::string(&e.msg, span));
+    error.push("offset", Value::int(offset as i64, span));
+    let mut record = Record::new();
+    record.push("error", Value::record(error, span));
+    Value::record(record, span)
+}
+
+/// A `{type, value}` record for `--typed`.
+fn typed_record(wire_type: &str, value: Value) -> Value {
+    let span = Span::unknown();
+    let mut record = Record::new();
+    record.push("type", Value::string(wire_type, span));
+    record.push("value", value);
+    Value::record(record, span)
 }
 
 /// Convert a msgpack ext value with an unrecognized type to a nu record.
@@ -66,7 +1579,7 @@ fn unknown_ext_to_nu(discriminant: i8, data: &[u8]) -> Value {
 
 /// Convert a msgpack timestamp ext type (-1) to a nu date value.
 /// See [https://github.com/msgpack/msgpack/blob/master/spec.md].
-fn ext_timestamp_to_nu(data: &[u8]) -> Result<Value, LabeledError> {
+pub fn ext_timestamp_to_nu(data: &[u8]) -> Result<Value, LabeledError> {
     let seconds: i64;
     let nanos: u32;
     match data.len() {
@@ -111,3 +1624,96 @@ fn ext_timestamp_to_nu(data: &[u8]) -> Result<Value, LabeledError> {
 
     Ok(Value::date(date.into(), Span::unknown()))
 }
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::{Record, Span, Value};
+
+    use super::*;
+    use crate::into::{self, EncodeOptions};
+
+    fn nested_arrays(depth: usize) -> Vec<u8> {
+        let mut bin = vec![0x91; depth];
+        bin.push(0xc0);
+        bin
+    }
+
+    #[test]
+    fn round_trip() {
+        let span = Span::unknown();
+        let mut record = Record::new();
+        record.push("nothing", Value::nothing(span));
+        record.push("bool", Value::bool(true, span));
+        record.push("small", Value::int(-3, span));
+        record.push("big", Value::int(i64::MIN, span));
+        record.push("float", Value::float(0.5, span));
+        record.push("string", Value::string("zürich", span));
+        record.push("binary", Value::binary(vec![0, 0xff], span));
+        record.push(
+            "list",
+            Value::list(vec![Value::int(1, span), Value::string("x", span)], span),
+        );
+        let value = Value::record(record, span);
+
+        let encoded = into::to_msgpack(&value, &EncodeOptions::default()).unwrap();
+        assert_eq!(decode(&encoded, &DecodeOptions::default()).unwrap(), value);
+    }
+
+    #[test]
+    fn max_depth() {
+        let opts = DecodeOptions::default();
+        assert!(decode(&nested_arrays(DEFAULT_MAX_DEPTH), &opts).is_ok());
+
+        let e = decode(&nested_arrays(DEFAULT_MAX_DEPTH + 1), &opts).unwrap_err();
+        assert_eq!(e.label, "Nesting too deep");
+        // deep enough to overflow the stack of a recursive decoder
+        let e = decode(&nested_arrays(1_000_000), &opts).unwrap_err();
+        assert_eq!(e.label, "Nesting too deep");
+    }
+
+    #[test]
+    fn claimed_lengths() {
+        let opts = DecodeOptions::default();
+        // array32, map32, str32 and bin32 claiming far more than the input holds
+        for marker in [0xdd, 0xdf, 0xdb, 0xc6] {
+            let e = decode(&[marker, 0xff, 0xff, 0xff, 0xff, 0xc0], &opts).unwrap_err();
+            assert_eq!(e.label, "Invalid msgpack", "marker {marker:#x}");
+        }
+
+        let opts = DecodeOptions {
+            max_size: Some(4),
+            ..DecodeOptions::default()
+        };
+        let bin = into::to_msgpack(
+            &Value::binary(vec![0; 8], Span::unknown()),
+            &EncodeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(decode(&bin, &opts).unwrap_err().label, "Value too large");
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let e = decode(&[0x01, 0x02], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(e.label, "Trailing bytes");
+
+        let span = Span::unknown();
+        let mut record = Record::new();
+        record.push("value", Value::int(1, span));
+        record.push("rest", Value::binary(vec![0x02], span));
+        let with_rest = decode_with_rest(&[0x01, 0x02], &DecodeOptions::default()).unwrap();
+        assert_eq!(with_rest, Value::record(record, span));
+    }
+
+    #[test]
+    fn check_headers() {
+        let opts = DecodeOptions::default();
+        assert!(check(&nested_arrays(DEFAULT_MAX_DEPTH), &opts).is_ok());
+        let e = check(&nested_arrays(DEFAULT_MAX_DEPTH + 1), &opts).unwrap_err();
+        assert_eq!(e.label, "Nesting too deep");
+        let e = check(&[0xdd, 0xff, 0xff, 0xff, 0xff, 0xc0], &opts).unwrap_err();
+        assert_eq!(e.label, "Invalid msgpack");
+        let e = check(&[0x01, 0x02], &opts).unwrap_err();
+        assert_eq!(e.label, "Trailing bytes");
+    }
+}
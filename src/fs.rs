@@ -0,0 +1,98 @@
+//! Helpers for commands that access files directly.
+
+use std::fs::File;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+use memmap2::Mmap;
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Span, Spanned, Value};
+
+/// Resolve a path given to a command relative to the current directory of the shell.
+///
+/// The plugin process doesn't follow `cd`, but nushell passes its `PWD` to plugins.
+pub fn resolve(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    match std::env::var_os("PWD") {
+        Some(pwd) if path.is_relative() => PathBuf::from(pwd).join(path),
+        _ => path,
+    }
+}
+
+/// Contents of a file given with `--path`.
+pub enum FileData {
+    Read(Vec<u8>),
+
+    /// The file mapped into memory with `--mmap`.
+    Mapped(Mmap),
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileData::Read(bytes) => bytes,
+            FileData::Mapped(map) => map,
+        }
+    }
+}
+
+/// Read the file given with `--path`, for commands that take it instead of piped input.
+///
+/// With `--mmap`, the file is mapped into memory instead, so that only the pages that are
+/// actually decoded are read, e.g. with `--skip` and `--take`.
+pub fn read_path_flag(
+    call: &EvaluatedCall,
+    input: &Value,
+) -> Result<Option<FileData>, LabeledError> {
+    let mmap = call.has_flag("mmap")?;
+    let Some(path) = call.get_flag::<Spanned<String>>("path")? else {
+        if mmap {
+            return Err(LabeledError {
+                label: "Missing flag".into(),
+                msg: "--mmap only applies with --path".into(),
+                span: Some(call.head),
+            });
+        }
+        return Ok(None);
+    };
+    if !matches!(input, Value::Nothing { .. }) {
+        return Err(LabeledError {
+            label: "Conflicting input".into(),
+            msg: "Pass the input either with --path or through the pipeline, not both".into(),
+            span: Some(path.span),
+        });
+    }
+
+    if !mmap {
+        return std::fs::read(resolve(&path.item))
+            .map(|bytes| Some(FileData::Read(bytes)))
+            .map_err(|e| io_error("read", &path, e));
+    }
+
+    let file = File::open(resolve(&path.item)).map_err(|e| io_error("open", &path, e))?;
+    // SAFETY: the map is only read while decoding. If another process truncates the file in
+    // the meantime, reading the missing pages crashes the plugin, which the
+    // description of --mmap warns about.
+    let map = unsafe { Mmap::map(&file) }.map_err(|e| io_error("map", &path, e))?;
+    Ok(Some(FileData::Mapped(map)))
+}
+
+/// Create an error for a failed file operation.
+pub fn io_error(action: &str, path: &Spanned<String>, e: std::io::Error) -> LabeledError {
+    io_error_at(action, &path.item, Some(path.span), e)
+}
+
+pub fn io_error_at(
+    action: &str,
+    path: &str,
+    span: Option<Span>,
+    e: std::io::Error,
+) -> LabeledError {
+    LabeledError {
+        label: format!("Failed to {action} file"),
+        msg: format!("{path}: {e}"),
+        span,
+    }
+}
@@ -0,0 +1,145 @@
+//! Extract values from a msgpack document by cell path, without decoding the rest of it.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::ast::{CellPath, PathMember};
+use nu_protocol::{Span, Value};
+
+use crate::dedup;
+use crate::from::{self, DecodeOptions, DuplicateKeyMode};
+use crate::path::{Path, PathSegment};
+use crate::wire::{Kind, Reader, WireError};
+
+/// Decode only the value at the cell path given as first argument.
+///
+/// A document deduplicated with `--dedup` is expanded first, since the value may refer to its
+/// string table.
+pub fn get(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let cell_path: CellPath = call.req(0)?;
+    let opts = DecodeOptions::from_call(call, config)?;
+    let expanded = match opts.dedup {
+        Some(ext_type) => dedup::expand(data, ext_type, opts.max_size, opts.debug)?,
+        None => None,
+    };
+    let data = expanded.as_ref().map_or(data, |(expanded, _)| expanded);
+
+    match locate(data, &cell_path.members, &opts)? {
+        Some((offset, path)) => Ok(from::decode_prefix_at(&data[offset..], &opts, path)?.0),
+        None => Ok(Value::nothing(call.head)),
+    }
+}
+
+/// Find the byte offset and path of the value at `members` by skipping over all other values.
+///
+/// Returns `None` if an optional member is missing. Like `from msgpack`, the last of duplicate
+/// map keys wins unless `opts` says to keep the first.
+pub fn locate(
+    data: &[u8],
+    members: &[PathMember],
+    opts: &DecodeOptions,
+) -> Result<Option<(usize, Path)>, LabeledError> {
+    let mut reader = Reader::new(data);
+    let mut path = Path::default();
+    let invalid = |e: WireError, path: &Path| LabeledError {
+        label: "Invalid msgpack".into(),
+        msg: format!("{e}, in the value at {path}"),
+        span: None,
+    };
+
+    for member in members {
+        let header = reader.read_header().map_err(|e| invalid(e, &path))?;
+        let found = match (member, header.kind) {
+            (PathMember::Int { val, .. }, Kind::Array(len)) => {
+                if *val < len as usize {
+                    for _ in 0..*val {
+                        reader.skip_value().map_err(|e| invalid(e, &path))?;
+                    }
+                    Some(reader.pos())
+                } else {
+                    None
+                }
+            }
+            (PathMember::String { val, .. }, Kind::Map(len)) => {
+                let mut found = None;
+                for _ in 0..len {
+                    let is_match =
+                        key_matches(&mut reader, val, opts).map_err(|e| invalid(e, &path))?;
+                    if is_match {
+                        found = Some(reader.pos());
+                        if let DuplicateKeyMode::First = opts.duplicate_keys {
+                            break;
+                        }
+                    }
+                    reader.skip_value().map_err(|e| invalid(e, &path))?;
+                }
+                found
+            }
+            _ => {
+                return Err(LabeledError {
+                    label: "Incompatible cell path".into(),
+                    msg: format!(
+                        "Can't get {} of the {} at {path}",
+                        describe(member),
+                        header.type_name()
+                    ),
+                    span: Some(member_span(member)),
+                })
+            }
+        };
+
+        match found {
+            Some(offset) => reader = Reader::at(data, offset),
+            None if is_optional(member) => return Ok(None),
+            None => {
+                return Err(LabeledError {
+                    label: "Not found".into(),
+                    msg: format!("There's no {} in the value at {path}", describe(member)),
+                    span: Some(member_span(member)),
+                })
+            }
+        }
+
+        path.push(match member {
+            PathMember::Int { val, .. } => PathSegment::Index(*val),
+            PathMember::String { val, .. } => PathSegment::Key(val.clone()),
+        });
+    }
+
+    Ok(Some((reader.pos(), path)))
+}
+
+/// Read a map key and check if `from msgpack` would turn it into `name`.
+fn key_matches(reader: &mut Reader, name: &str, opts: &DecodeOptions) -> Result<bool, WireError> {
+    let start = reader.pos();
+    let key = reader.skip_value()?;
+    let bytes = &reader.input()[start..reader.pos()];
+    Ok(match key.kind {
+        Kind::Str(len) => &bytes[bytes.len() - len as usize..] == name.as_bytes(),
+        _ => from::decode(bytes, opts)
+            .and_then(|key| Ok(key.as_string()?))
+            .is_ok_and(|key| key == name),
+    })
+}
+
+fn describe(member: &PathMember) -> String {
+    match member {
+        PathMember::Int { val, .. } => format!("index {val}"),
+        PathMember::String { val, .. } => format!("key {val:?}"),
+    }
+}
+
+fn is_optional(member: &PathMember) -> bool {
+    match member {
+        PathMember::Int { optional, .. } | PathMember::String { optional, .. } => *optional,
+    }
+}
+
+fn member_span(member: &PathMember) -> Span {
+    match member {
+        PathMember::Int { span, .. } | PathMember::String { span, .. } => *span,
+    }
+}
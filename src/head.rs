@@ -0,0 +1,87 @@
+//! Previews of the start of msgpack binaries.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+use rmp::encode;
+
+use crate::from::{self, DecodeOptions};
+use crate::wire::{Kind, Reader, WireError};
+
+/// Decode only the first `n` values of a msgpack binary, skipping over the rest using their
+/// length headers.
+///
+/// If the binary holds a single array or map, these are its first elements or entries, and
+/// otherwise its first top-level values. Returns them as `head`, with the number of values and
+/// bytes that were left undecoded.
+pub fn head(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+    let n: usize = call.opt(0)?.unwrap_or(10);
+    let opts = DecodeOptions::from_call(call, config)?;
+    let invalid = |e: WireError| LabeledError {
+        label: "Invalid msgpack".into(),
+        msg: e.to_string(),
+        span: Some(input.span()),
+    };
+
+    let mut reader = Reader::new(data);
+    let first = reader.skip_value().map_err(invalid)?;
+    let single = reader.is_empty();
+
+    let (head, remaining, decoded) = match first.kind {
+        Kind::Array(len) if single => {
+            let take = n.min(len as usize);
+            let head = from::decode_window(data, &opts, 0, Some(take))?;
+            let mut reader = Reader::new(data);
+            reader.read_header().map_err(invalid)?;
+            for _ in 0..take {
+                reader.skip_value().map_err(invalid)?;
+            }
+            (head, len as usize - take, reader.pos())
+        }
+        Kind::Map(len) if single => {
+            // decode a map of only the first entries, copied after a header of their number
+            let take = n.min(len as usize);
+            let mut reader = Reader::new(data);
+            reader.read_header().map_err(invalid)?;
+            let start = reader.pos();
+            for _ in 0..take * 2 {
+                reader.skip_value().map_err(invalid)?;
+            }
+            let mut map = vec![];
+            encode::write_map_len(&mut map, take as u32).expect("writing to a vec");
+            map.extend_from_slice(&data[start..reader.pos()]);
+            let head = from::decode(&map, &opts)?;
+            (head, len as usize - take, reader.pos())
+        }
+        _ => {
+            let mut vals = vec![];
+            let mut offset = 0;
+            while vals.len() < n && offset < data.len() {
+                let (value, len) = from::decode_prefix(&data[offset..], &opts, 0)?;
+                vals.push(value);
+                offset += len;
+            }
+            // a damaged tail shouldn't stop the preview, so only count what can be skipped
+            let mut reader = Reader::at(data, offset);
+            let mut remaining = 0;
+            while !reader.is_empty() && reader.skip_value().is_ok() {
+                remaining += 1;
+            }
+            (Value::list(vals, span), remaining, offset)
+        }
+    };
+
+    let mut record = Record::new();
+    record.push("head", head);
+    record.push("remaining", Value::int(remaining as i64, span));
+    record.push(
+        "remaining_bytes",
+        Value::filesize((data.len() - decoded) as i64, span),
+    );
+    Ok(Value::record(record, span))
+}
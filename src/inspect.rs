@@ -0,0 +1,132 @@
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Value};
+
+use crate::wire::{self, Kind, Reader, Walker};
+use crate::{from, preview};
+
+/// A container whose children are still being read.
+struct Open {
+    record: Record,
+    offset: usize,
+    remaining: u64,
+    nodes: Vec<Value>,
+}
+
+/// Parse a msgpack binary into a tree of its wire values: their type, byte offset, encoded length
+/// and children. Bins in a known format, like PNG images, are annotated with it.
+///
+/// Returns the tree of the top-level value, or a list of trees if the input holds several.
+pub fn inspect(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+
+    let mut trees = vec![];
+    let mut reader = Reader::new(data);
+    while !reader.is_empty() {
+        let mut walker = Walker::new(reader);
+        let mut open: Vec<Open> = vec![];
+        let mut tree = None;
+
+        loop {
+            let offset = walker.reader().pos();
+            let Some(node) = walker.next() else {
+                break;
+            };
+            let node = node.map_err(|e| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: format!(
+                    "{e}, in the value at {}\n{}",
+                    walker.path(),
+                    wire::hex_window(data, offset, 8)
+                ),
+                span: Some(input.span()),
+            })?;
+
+            let header = node.header;
+            if node.depth >= from::DEFAULT_MAX_DEPTH
+                && matches!(header.kind, Kind::Array(_) | Kind::Map(_))
+            {
+                return Err(LabeledError {
+                    label: "Nesting too deep".into(),
+                    msg: format!(
+                        "Encountered msgpack nested deeper than {} levels at byte {}, in the value at {}",
+                        from::DEFAULT_MAX_DEPTH,
+                        header.offset,
+                        walker.path()
+                    ),
+                    span: Some(input.span()),
+                });
+            }
+            let mut record = Record::new();
+            record.push("type", Value::string(header.type_name(), span));
+            record.push("offset", Value::int(header.offset as i64, span));
+            let format = match header.kind {
+                Kind::Bin(_) => preview::describe(node.data),
+                _ => None,
+            };
+            record.push(
+                "format",
+                match format {
+                    Some(format) => Value::string(format, span),
+                    None => Value::nothing(span),
+                },
+            );
+
+            let children = header.child_count();
+            let mut finished = if children > 0 {
+                open.push(Open {
+                    record,
+                    offset: header.offset,
+                    remaining: children,
+                    nodes: vec![],
+                });
+                None
+            } else {
+                Some(finish(record, header.offset, vec![], walker.reader(), span))
+            };
+
+            // attach finished nodes to their parents, finishing the parents that are complete
+            while let Some(node) = finished.take() {
+                match open.last_mut() {
+                    None => tree = Some(node),
+                    Some(parent) => {
+                        parent.nodes.push(node);
+                        parent.remaining -= 1;
+                        if parent.remaining == 0 {
+                            let parent = open.pop().expect("checked by last_mut");
+                            finished = Some(finish(
+                                parent.record,
+                                parent.offset,
+                                parent.nodes,
+                                walker.reader(),
+                                span,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        trees.extend(tree);
+        reader = walker.reader().clone();
+    }
+
+    Ok(match trees.len() {
+        1 => trees.pop().expect("checked by len"),
+        _ => Value::list(trees, span),
+    })
+}
+
+/// Complete the record of a node that ends at the current position of `reader`.
+fn finish(
+    mut record: Record,
+    offset: usize,
+    nodes: Vec<Value>,
+    reader: &Reader,
+    span: Span,
+) -> Value {
+    record.push("length", Value::int((reader.pos() - offset) as i64, span));
+    record.push("children", Value::int(nodes.len() as i64, span));
+    record.push("nodes", Value::list(nodes, span));
+    Value::record(record, span)
+}
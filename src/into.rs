@@ -1,74 +1,1377 @@
-use nu_plugin::LabeledError;
-use nu_protocol::Value;
-
-/// Convert [nu_protocol::Value] to a [rmpv::Value].
-pub fn nu_to_rmpv(value: Value) -> Result<rmpv::Value, LabeledError> {
-    Ok(match value {
-        Value::Bool { val, .. } => val.into(),
-        Value::Int { val, .. } => val.into(),
-        Value::Float { val, .. } => val.into(),
-        Value::String { val, .. } => val.into(),
-        Value::Binary { val, .. } => val.into(),
-        Value::Nothing { .. } => rmpv::Value::Nil,
-        Value::List { vals, .. } => {
-            let vals: Result<_, _> = vals.into_iter().map(nu_to_rmpv).collect();
-            rmpv::Value::Array(vals?)
-        }
-
-        // Convert record to map.
-        Value::Record { val: record, .. } => {
-            let pairs: Result<_, LabeledError> = record
-                .into_iter()
-                .map(|(k, v)| Ok((k.into(), nu_to_rmpv(v)?)))
-                .collect();
-
-            rmpv::Value::Map(pairs?)
-        }
-
-        // Convert filesize to number of bytes, like `to json` does.
-        Value::Filesize { val, .. } => val.into(),
-
-        // Convert duration to nanoseconds, like `to json` does.
-        Value::Duration { val, .. } => val.into(),
-
-        // Convert date to msgpack extension type -1
-        // defined in https://github.com/msgpack/msgpack/blob/master/spec.md
-        Value::Date { val, .. } => {
-            let nanos: u32 = val.timestamp_subsec_nanos();
-            let seconds: i64 = val.timestamp();
-
-            let mut data: Vec<u8>;
-
-            // use the smallest datetime representation possible
-            // TODO: implement 8 byte representation
-            if let (Ok(seconds), 0) = (u32::try_from(seconds), nanos) {
-                data = seconds.to_be_bytes().to_vec();
-            } else {
-                data = Vec::with_capacity(12);
-                data.extend_from_slice(&nanos.to_be_bytes());
-                data.extend_from_slice(&seconds.to_be_bytes());
-            }
-            rmpv::Value::Ext(-1, data)
-        }
-        Value::Range { val, .. } => {
-            let vals: Result<_, _> = val.into_range_iter(None)?.map(nu_to_rmpv).collect();
-            rmpv::Value::Array(vals?)
-        }
-
-        Value::CustomValue { val, internal_span } => {
-            let val = val.to_base_value(internal_span)?;
-            nu_to_rmpv(val)?
-        }
-
-        Value::LazyRecord { val, .. } => nu_to_rmpv(val.collect()?)?,
-
-        // Convert anything we can't represent in msgpck to nil
-        // Pretty sure this is how `to json` does it.
-        _ => rmpv::Value::Nil,
-        //Value::Block { val, .. } => todo!(),
-        //Value::Closure { val, .. } => todo!(),
-        //Value::Error { error, .. } => todo!(),
-        //Value::CellPath { val, .. } => todo!(),
-        //Value::MatchPattern { val, .. } => todo!(),
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+
+use miette::Diagnostic;
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::ast::CellPath;
+use nu_protocol::{PluginSignature, Record, ShellError, Span, Spanned, SyntaxShape, Value};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use rmp::encode::{self, ValueWriteError};
+use rmp::Marker;
+
+use crate::ext::ExtCodec;
+use crate::path::{Path, PathSegment};
+use crate::schema::Schema;
+use crate::{bignum, config, decompress, dedup, ext, flags, fs, nu_ext, trace, typed_array};
+
+/// How to encode error values.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Encode errors as nil, like other values that msgpack can't represent.
+    Nil,
+
+    /// Encode errors as a `{error: {msg, label, span, row, column}}` map.
+    #[default]
+    Record,
+}
+
+/// How to convert NaN and infinite floats, which some msgpack implementations reject.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonFiniteMode {
+    /// Keep them as floats.
+    #[default]
+    Float,
+
+    Error,
+
+    /// Convert them to nil, like JSON encoders do.
+    Null,
+
+    /// Convert them to `NaN`, `Infinity` or `-Infinity` strings.
+    String,
+}
+
+pub const NON_FINITE_MODES: &[(&str, NonFiniteMode)] = &[
+    ("float", NonFiniteMode::Float),
+    ("error", NonFiniteMode::Error),
+    ("null", NonFiniteMode::Null),
+    ("string", NonFiniteMode::String),
+];
+
+/// The string form of a NaN or infinite float.
+pub fn non_finite_name(float: f64) -> &'static str {
+    if float.is_nan() {
+        "NaN"
+    } else if float > 0.0 {
+        "Infinity"
+    } else {
+        "-Infinity"
+    }
+}
+
+/// How wide to encode integers.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum IntWidth {
+    /// The smallest encoding that fits the value.
+    #[default]
+    Auto,
+
+    /// Always int32, failing for values that don't fit.
+    Four,
+
+    /// Always int64.
+    Eight,
+}
+
+/// Options for converting nu values to msgpack.
+#[derive(Default)]
+pub struct EncodeOptions {
+    /// Ext type id to pack numeric lists as, see [typed_array].
+    pub typed_arrays: Option<i8>,
+
+    /// Minimum length of lists that are packed as typed arrays.
+    pub typed_array_min: usize,
+
+    /// Error on values that can't be represented in msgpack, instead of converting them to nil.
+    pub strict: bool,
+
+    pub errors: ErrorMode,
+
+    pub non_finite: NonFiniteMode,
+
+    pub int_width: IntWidth,
+
+    /// Encode lists of `{key, value}` records as maps.
+    pub key_value_pairs: bool,
+
+    /// Encode record keys that are integers, like `"42"`, as integer map keys.
+    pub int_keys: bool,
+
+    /// Encode tables as a `{columns, rows}` map, see [Encoder::compact_table].
+    pub compact_tables: bool,
+
+    /// Produce byte-stable output, by sorting map keys and normalizing NaN floats.
+    pub canonical: bool,
+
+    /// Ext type id to encode strings that are UUIDs as.
+    pub uuid_ext: Option<i8>,
+
+    /// How to encode the values of `{ext_type, value}` records whose ext type is in the
+    /// namespace selected by `--namespace`, see [Encoder::namespaced].
+    pub ext_codecs: HashMap<i8, ExtCodec>,
+
+    /// Ext type id to tag nu types that msgpack doesn't have with, see [nu_ext].
+    pub nu_ext: Option<i8>,
+
+    /// Ext type id of the string table that repeated strings are moved into, see [dedup].
+    pub dedup: Option<i8>,
+
+    /// Maximum number of elements to write for a range. Without it, unbounded ranges are an error.
+    pub range_limit: Option<usize>,
+
+    /// Number of threads to encode the elements of a top-level list on, 0 for one per CPU.
+    pub threads: Option<usize>,
+
+    /// Encode all strings as bin.
+    pub strings_as_bin: bool,
+
+    /// Encode strings at these cell paths as bin.
+    pub bin_paths: Vec<CellPath>,
+
+    /// Write the values at these cell paths as bin holding their gzip-compressed encoding.
+    pub compress_paths: Vec<CellPath>,
+
+    /// Write the pre-2013 spec, which has no str8, bin or ext types, see [write_raw].
+    pub compat: bool,
+
+    /// Write length-prefixed frames, see [write_frames]. Only `to msgpack` has the flag.
+    pub framed: bool,
+
+    /// Schema that values must conform to before they're written. Only `to msgpack` has the
+    /// flag.
+    pub schema: Option<Schema>,
+
+    /// Trace encoding decisions and timings to stderr, see [trace].
+    pub debug: bool,
+}
+
+impl EncodeOptions {
+    pub fn from_call(call: &EvaluatedCall, config: &Option<Value>) -> Result<Self, LabeledError> {
+        Ok(EncodeOptions {
+            typed_arrays: flags::ext_id_or_config(call, config, "typed-arrays")?,
+            typed_array_min: call.get_flag("typed-array-min")?.unwrap_or(16),
+            strict: call.has_flag("strict")?
+                || config::get_bool(config, "strict")?.unwrap_or(false),
+            errors: flags::choice_or_config(
+                call,
+                config,
+                "errors",
+                &[("nil", ErrorMode::Nil), ("record", ErrorMode::Record)],
+            )?,
+            non_finite: flags::choice_or_config(call, config, "non-finite", NON_FINITE_MODES)?,
+            int_width: flags::choice_or_config(
+                call,
+                config,
+                "int-width",
+                &[
+                    ("auto", IntWidth::Auto),
+                    ("4", IntWidth::Four),
+                    ("8", IntWidth::Eight),
+                ],
+            )?,
+            key_value_pairs: call.has_flag("key-value-pairs")?,
+            int_keys: call.has_flag("int-keys")?,
+            compact_tables: call.has_flag("compact-tables")?,
+            canonical: call.has_flag("canonical")?
+                || config::get_bool(config, "canonical")?.unwrap_or(false),
+            uuid_ext: flags::ext_id_or_config(call, config, "uuid-ext")?,
+            ext_codecs: ext::namespace_from_call(call, config)?,
+            nu_ext: flags::ext_id_or_config(call, config, "nu-ext")?,
+            dedup: flags::ext_id_or_config(call, config, "dedup")?,
+            range_limit: match call.get_flag("range-limit")? {
+                Some(limit) => Some(limit),
+                None => config::get_usize(config, "range_limit")?,
+            },
+            threads: call.get_flag("threads")?,
+            strings_as_bin: call.has_flag("strings-as-bin")?,
+            bin_paths: flags::cell_paths_flag(call, "bin-paths")?,
+            compress_paths: flags::cell_paths_flag(call, "compress-paths")?,
+            compat: call.has_flag("compat")?,
+            framed: call.has_flag("framed")?,
+            schema: Schema::from_flag(call)?,
+            debug: call.has_flag("debug")?,
+        })
+    }
+}
+
+/// Add the flags parsed by [EncodeOptions::from_call] to a signature.
+pub fn encode_flags(signature: PluginSignature) -> PluginSignature {
+    signature
+        .named(
+            "typed-arrays",
+            SyntaxShape::Int,
+            "ext type id to pack homogeneous numeric lists as",
+            None,
+        )
+        .named(
+            "typed-array-min",
+            SyntaxShape::Int,
+            "minimum length of lists to pack as typed arrays (default: 16)",
+            None,
+        )
+        .switch(
+            "strict",
+            "error on values that can't be represented in msgpack instead of writing nil",
+            None,
+        )
+        .named(
+            "errors",
+            SyntaxShape::String,
+            "how to encode error values: record (default), which includes the message, label \
+             and span and, for table cells, the row and column, or nil",
+            None,
+        )
+        .named(
+            "non-finite",
+            SyntaxShape::String,
+            "how to encode NaN and infinite floats: float (default), error, null or string",
+            None,
+        )
+        .named(
+            "int-width",
+            SyntaxShape::String,
+            "how to encode integers: auto (default, smallest that fits), 4 (int32) or 8 (int64)",
+            None,
+        )
+        .switch(
+            "key-value-pairs",
+            "encode lists of {key, value} records as maps, allowing non-string keys",
+            None,
+        )
+        .switch(
+            "compact-tables",
+            "encode tables as a {columns, rows} map of the column names and a list of the values \
+             of each row, instead of repeating the column names in every row",
+            None,
+        )
+        .switch(
+            "int-keys",
+            "encode record keys that are integers, like \"42\", as integer map keys",
+            None,
+        )
+        .switch(
+            "canonical",
+            "deterministic output: sort map keys and normalize NaN floats",
+            None,
+        )
+        .named(
+            "uuid-ext",
+            SyntaxShape::Int,
+            "ext type id to encode UUID strings as, in their 16 byte form",
+            None,
+        )
+        .named(
+            "nu-ext",
+            SyntaxShape::Int,
+            "ext type id to tag durations, filesizes, cell paths and ranges with, so that \
+             `from msgpack --nu-ext` restores them",
+            None,
+        )
+        .named(
+            "range-limit",
+            SyntaxShape::Int,
+            "write at most this many elements of a range, which unbounded ranges like 1.. need",
+            None,
+        )
+        .named(
+            "namespace",
+            SyntaxShape::String,
+            "name of the ext namespace in the plugin config to encode {ext_type, value} records \
+             with",
+            None,
+        )
+        .named(
+            "dedup",
+            SyntaxShape::Int,
+            "ext type id of a string table to move repeated strings into, e.g. the keys of every \
+             row of a table",
+            None,
+        )
+        .named(
+            "threads",
+            SyntaxShape::Int,
+            "encode the rows of a top-level list on this many threads, 0 for one per CPU",
+            None,
+        )
+        .switch(
+            "strings-as-bin",
+            "encode strings as bin instead of str",
+            None,
+        )
+        .named(
+            "bin-paths",
+            SyntaxShape::List(Box::new(SyntaxShape::CellPath)),
+            "encode strings at these cell paths as bin instead of str",
+            None,
+        )
+        .named(
+            "compress-paths",
+            SyntaxShape::List(Box::new(SyntaxShape::CellPath)),
+            "compress the encoding of the values at these cell paths with gzip, and write it as bin",
+            None,
+        )
+        .switch(
+            "compat",
+            "write the old msgpack spec without str8, bin and ext types, for legacy consumers",
+            None,
+        )
+        .switch(
+            "debug",
+            "trace which conversions were made and how long encoding took to stderr",
+            None,
+        )
+}
+
+/// Serialize a [rmpv::Value] to msgpack bytes.
+pub fn encode(value: &rmpv::Value) -> Vec<u8> {
+    let mut encoded = vec![];
+    rmpv::encode::write_value(&mut encoded, value).expect("encoding to vec can't fail, right?");
+    encoded
+}
+
+/// Convert a [nu_protocol::Value] to msgpack bytes.
+pub fn to_msgpack(value: &Value, opts: &EncodeOptions) -> Result<Vec<u8>, LabeledError> {
+    validate(value, opts)?;
+    let mut encoded = vec![];
+    match write_value(&mut encoded, value, opts) {
+        Ok(()) => Ok(encoded),
+        Err(WriteError::Value(e)) => Err(e),
+        Err(WriteError::Io(e)) => unreachable!("encoding to vec can't fail: {e}"),
+    }
+}
+
+/// Write a nu value as msgpack straight to a file, without buffering all of it in memory.
+///
+/// Returns a `{path, bytes}` record of the file and the number of bytes written.
+pub fn write_file(
+    call: &EvaluatedCall,
+    value: &Value,
+    opts: &EncodeOptions,
+    path: &Spanned<String>,
+) -> Result<Value, LabeledError> {
+    if call.has_flag("base64")? || call.has_flag("hex")? {
+        return Err(LabeledError {
+            label: "Conflicting flags".into(),
+            msg: "--out writes binary, so it can't be used with --base64 or --hex".into(),
+            span: Some(path.span),
+        });
+    }
+    let append = call.has_flag("append")?;
+    let span = call.head;
+    // Before opening, so that a value that doesn't conform leaves the file alone.
+    validate(value, opts)?;
+
+    let resolved = fs::resolve(&path.item);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(&resolved)
+        .map_err(|e| fs::io_error("open", path, e))?;
+
+    let mut writer = CountingWriter {
+        inner: BufWriter::new(file),
+        count: 0,
+    };
+    match write_value(&mut writer, value, opts) {
+        Ok(()) => {}
+        Err(WriteError::Value(e)) => return Err(e),
+        Err(WriteError::Io(e)) => return Err(fs::io_error("write", path, e)),
+    }
+    writer
+        .inner
+        .flush()
+        .map_err(|e| fs::io_error("write", path, e))?;
+
+    let mut record = Record::new();
+    record.push("path", Value::string(resolved.to_string_lossy(), span));
+    record.push("bytes", Value::filesize(writer.count as i64, span));
+    Ok(Value::record(record, span))
+}
+
+/// Counts the bytes written through it.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Error while writing a nu value as msgpack.
+pub enum WriteError {
+    /// The value can't be encoded.
+    Value(LabeledError),
+
+    /// The writer failed.
+    Io(io::Error),
+}
+
+impl From<LabeledError> for WriteError {
+    fn from(e: LabeledError) -> Self {
+        WriteError::Value(e)
+    }
+}
+
+impl From<ShellError> for WriteError {
+    fn from(e: ShellError) -> Self {
+        WriteError::Value(e.into())
+    }
+}
+
+impl From<io::Error> for WriteError {
+    fn from(e: io::Error) -> Self {
+        WriteError::Io(e)
+    }
+}
+
+impl From<ValueWriteError> for WriteError {
+    fn from(e: ValueWriteError) -> Self {
+        match e {
+            ValueWriteError::InvalidMarkerWrite(e) | ValueWriteError::InvalidDataWrite(e) => {
+                WriteError::Io(e)
+            }
+        }
+    }
+}
+
+/// Check the value against the schema, if there is one. With `--framed`, each element of a
+/// list is a document of its own.
+fn validate(value: &Value, opts: &EncodeOptions) -> Result<(), LabeledError> {
+    let Some(schema) = &opts.schema else {
+        return Ok(());
+    };
+    match value {
+        Value::List { vals, .. } if opts.framed => {
+            for (i, value) in vals.iter().enumerate() {
+                schema.validate(value).map_err(|mut e| {
+                    e.msg = format!("In frame {i}: {}", e.msg);
+                    e
+                })?;
+            }
+            Ok(())
+        }
+        value => schema.validate(value),
+    }
+}
+
+/// Write a [nu_protocol::Value] as msgpack, walking it without building an intermediate tree.
+pub fn write_value<W: Write>(
+    w: &mut W,
+    value: &Value,
+    opts: &EncodeOptions,
+) -> Result<(), WriteError> {
+    if opts.compat && opts.dedup.is_some() {
+        return Err(LabeledError {
+            label: "Conflicting flags".into(),
+            msg: "--dedup writes ext values, which old-spec msgpack (--compat) doesn't have".into(),
+            span: None,
+        }
+        .into());
+    }
+    if opts.framed {
+        return trace::time(opts.debug, "encode", "encoding", || {
+            write_frames(w, value, opts)
+        });
+    }
+
+    let mut encoder = Encoder {
+        opts,
+        path: Path::default(),
+    };
+    match opts.dedup {
+        Some(ext_type) => {
+            let mut encoded = vec![];
+            trace::time(opts.debug, "encode", "encoding", || {
+                encoder.encode(&mut encoded, value)
+            })?;
+            let deduped = trace::time(opts.debug, "encode", "deduplication", || {
+                dedup::dedup(&encoded, ext_type, opts.debug)
+            })?;
+            w.write_all(&deduped)?;
+            Ok(())
+        }
+        None => trace::time(opts.debug, "encode", "encoding", || {
+            encoder.encode(w, value)
+        }),
+    }
+}
+
+/// Write each element of a list, or a single value, as a frame of its length as a big-endian u32
+/// followed by its msgpack encoding.
+fn write_frames<W: Write>(
+    w: &mut W,
+    value: &Value,
+    opts: &EncodeOptions,
+) -> Result<(), WriteError> {
+    let (frames, is_list) = match value {
+        Value::List { vals, .. } => (vals.as_slice(), true),
+        value => (std::slice::from_ref(value), false),
+    };
+
+    let mut frame = vec![];
+    for (i, value) in frames.iter().enumerate() {
+        frame.clear();
+        let mut encoder = Encoder {
+            opts,
+            path: Path::default(),
+        };
+        if is_list {
+            encoder.encode_at(&mut frame, PathSegment::Index(i), value)?;
+        } else {
+            encoder.encode(&mut frame, value)?;
+        }
+        if let Some(ext_type) = opts.dedup {
+            frame = dedup::dedup(&frame, ext_type, opts.debug)?;
+        }
+        let len = u32::try_from(frame.len()).map_err(|_| LabeledError {
+            label: "Frame too large".into(),
+            msg: format!(
+                "Frame {i} is {} bytes, more than a u32 length allows",
+                frame.len()
+            ),
+            span: Some(value.span()),
+        })?;
+        w.write_all(&len.to_be_bytes())?;
+        w.write_all(&frame)?;
+    }
+    Ok(())
+}
+
+struct Encoder<'a> {
+    opts: &'a EncodeOptions,
+
+    /// Path to the value currently being encoded.
+    path: Path,
+}
+
+/// A map entry still to be encoded.
+enum Entry<'v> {
+    /// A record column, which is encoded with a string key.
+    Column(&'v str, &'v Value),
+
+    /// The `{key, value}` record at an index of a list encoded with `--key-value-pairs`.
+    Pair(usize, &'v Value, &'v Value),
+}
+
+impl Encoder<'_> {
+    fn encode<W: Write>(&mut self, w: &mut W, value: &Value) -> Result<(), WriteError> {
+        let opts = self.opts;
+        match value {
+            Value::Bool { val, .. } => encode::write_bool(w, *val)?,
+            Value::Int { val, .. } => self.write_int(w, *val, value)?,
+            Value::Float { val, .. }
+                if !val.is_finite() && opts.non_finite != NonFiniteMode::Float =>
+            {
+                match opts.non_finite {
+                    NonFiniteMode::Null => {
+                        self.trace(|| format!("{} written as nil", non_finite_name(*val)));
+                        encode::write_nil(w)?
+                    }
+                    NonFiniteMode::String => {
+                        self.trace(|| format!("{} written as a string", non_finite_name(*val)));
+                        self.write_str(w, non_finite_name(*val))?
+                    }
+                    _ => {
+                        return Err(LabeledError {
+                            label: "Non-finite float".into(),
+                            msg: format!(
+                                "Found {} at {}, use --non-finite to convert it",
+                                non_finite_name(*val),
+                                self.path
+                            ),
+                            span: Some(value.span()),
+                        }
+                        .into())
+                    }
+                }
+            }
+            // All NaNs are equal in nu, so write them the same way.
+            Value::Float { val, .. } if opts.canonical && val.is_nan() => {
+                encode::write_f64(w, f64::NAN)?
+            }
+            Value::Float { val, .. } => encode::write_f64(w, *val)?,
+            // Quoted strings are strings that nu doesn't expand as globs.
+            Value::String { val, .. } | Value::QuotedString { val, .. } if opts.compat => {
+                write_raw(w, val.as_bytes())?
+            }
+            Value::String { val, .. } | Value::QuotedString { val, .. } if self.is_bin_string() => {
+                encode::write_bin(w, val.as_bytes())?
+            }
+            Value::String { val, .. } | Value::QuotedString { val, .. } => {
+                match opts.uuid_ext.zip(ext::parse_uuid(val)) {
+                    Some((ext_type, uuid)) => {
+                        self.trace(|| format!("UUID written as ext type {ext_type}"));
+                        write_ext(w, ext_type, &uuid)?
+                    }
+                    None => self.write_str(w, val)?,
+                }
+            }
+            Value::Binary { val, .. } if opts.compat => write_raw(w, val)?,
+            Value::Binary { val, .. } => encode::write_bin(w, val)?,
+            Value::Nothing { .. } => encode::write_nil(w)?,
+            Value::List { vals, .. } if opts.key_value_pairs && is_key_value_pairs(vals) => {
+                let len = vals.len();
+                let entries = vals.iter().enumerate().map(|(i, pair)| {
+                    let Value::Record { val: pair, .. } = pair else {
+                        unreachable!("checked by is_key_value_pairs");
+                    };
+                    let mut pair = pair.values();
+                    let k = pair.next().expect("checked by is_key_value_pairs");
+                    let v = pair.next().expect("checked by is_key_value_pairs");
+                    Entry::Pair(i, k, v)
+                });
+                self.map(w, len, entries)?
+            }
+            Value::List { vals, .. } if opts.compact_tables && is_table(vals) => {
+                self.compact_table(w, vals)?
+            }
+            Value::List { vals, .. } => {
+                let packed = opts
+                    .typed_arrays
+                    .filter(|_| !opts.compat)
+                    .and_then(|ext| Some((ext, typed_array::pack(vals, opts.typed_array_min)?)));
+                if let Some((ext, data)) = packed {
+                    self.trace(|| {
+                        format!(
+                            "list of {} numbers packed as ext type {ext} in {} bytes",
+                            vals.len(),
+                            data.len()
+                        )
+                    });
+                    return write_ext(w, ext, &data);
+                }
+
+                match opts.threads {
+                    Some(threads) if self.path.is_root() => parallel_array(w, vals, opts, threads)?,
+                    _ => self.array(w, vals)?,
+                }
+            }
+
+            // Convert records like the ones `from msgpack` creates for unknown ext types back
+            // to ext values.
+            Value::Record { val: record, .. } if !opts.compat && as_ext(record).is_some() => {
+                let (ext_type, data) = as_ext(record).expect("checked by guard");
+                self.trace(|| format!("{{ext_type, data}} record written as ext type {ext_type}"));
+                write_ext(w, ext_type, data)?
+            }
+
+            Value::Record { val: record, .. }
+                if !opts.compat && self.namespaced(record).is_some() =>
+            {
+                let (ext_type, codec, value) = self.namespaced(record).expect("checked by guard");
+                let data = match codec {
+                    ExtCodec::NestedMsgpack => {
+                        let mut data = vec![];
+                        self.encode(&mut data, value)?;
+                        data
+                    }
+                    codec => codec.encode(value)?,
+                };
+                self.trace(|| format!("{{ext_type, value}} record written as ext type {ext_type}"));
+                write_ext(w, ext_type, &data)?
+            }
+
+            Value::Record { val: record, .. }
+                if !opts.compat && bignum::encode_record(record).is_some() =>
+            {
+                let (ext_type, data) = bignum::encode_record(record).expect("checked by guard");
+                self.trace(|| format!("big number record written as ext type {ext_type}"));
+                write_ext(w, ext_type, &data)?
+            }
+
+            // Convert record to map.
+            Value::Record { val: record, .. } => {
+                let len = record.len();
+                let entries = record.iter().map(|(k, v)| Entry::Column(k, v));
+                self.map(w, len, entries)?
+            }
+
+            Value::Duration { .. }
+            | Value::Filesize { .. }
+            | Value::CellPath { .. }
+            | Value::Range { .. }
+                if !opts.compat && opts.nu_ext.is_some() && nu_ext::encode(value).is_some() =>
+            {
+                let ext_type = opts.nu_ext.expect("checked by guard");
+                let data = nu_ext::encode(value).expect("checked by guard");
+                self.trace(|| format!("{} written as ext type {ext_type}", value.get_type()));
+                write_ext(w, ext_type, &data)?
+            }
+
+            // Convert filesize to number of bytes, like `to json` does.
+            Value::Filesize { val, .. } => self.write_int(w, *val, value)?,
+
+            // Convert duration to nanoseconds, like `to json` does.
+            Value::Duration { val, .. } => self.write_int(w, *val, value)?,
+
+            // Convert date to msgpack extension type -1
+            // defined in https://github.com/msgpack/msgpack/blob/master/spec.md
+            Value::Date { val, .. } if !opts.compat => {
+                let data = timestamp_data(val.timestamp(), val.timestamp_subsec_nanos());
+                write_ext(w, -1, &data)?
+            }
+            // Convert cell path to its string form, like `into string` does.
+            Value::CellPath { val, .. } => self.write_str(w, &val.to_string())?,
+
+            Value::Range { val, .. } => {
+                let range = val.clone().into_range_iter(None)?;
+                let vals: Vec<_> = match opts.range_limit {
+                    Some(limit) => {
+                        self.trace(|| format!("range written up to {limit} elements"));
+                        range.take(limit).collect()
+                    }
+                    // an unbounded range would be collected until memory runs out
+                    None if val.to.is_nothing() => {
+                        return Err(LabeledError {
+                            label: "Unbounded range".into(),
+                            msg: format!(
+                                "The range at {} has no end, use --range-limit to write only \
+                                 its first elements",
+                                self.path
+                            ),
+                            span: Some(value.span()),
+                        }
+                        .into())
+                    }
+                    None => range.collect(),
+                };
+                self.array(w, &vals)?
+            }
+
+            Value::CustomValue { val, internal_span } => {
+                let val = val.to_base_value(*internal_span)?;
+                self.encode(w, &val)?
+            }
+
+            Value::LazyRecord { val, .. } => self.encode(w, &val.collect()?)?,
+
+            Value::Error { error, .. } if opts.errors == ErrorMode::Record => {
+                // written like a record, so that --canonical sorts its keys
+                let details = self.error_details(error);
+                self.map(w, 1, std::iter::once(Entry::Column("error", &details)))?
+            }
+
+            // Convert anything we can't represent in msgpck to nil, unless we're strict.
+            // Pretty sure this is how `to json` does it.
+            _ if !opts.strict => {
+                self.trace(|| format!("{} written as nil", value.get_type()));
+                encode::write_nil(w)?
+            }
+            //Value::Block { val, .. } => todo!(),
+            //Value::Closure { val, .. } => todo!(),
+            //Value::MatchPattern { val, .. } => todo!(),
+            value => {
+                return Err(LabeledError {
+                    label: "Unsupported value".into(),
+                    msg: format!(
+                        "Can't represent {} at {} in {}msgpack",
+                        value.get_type(),
+                        self.path,
+                        if opts.compat { "old-spec " } else { "" }
+                    ),
+                    span: Some(value.span()),
+                }
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    /// If a record is an `{ext_type, value}` record of an ext type in the namespace, return the
+    /// ext type, its codec and the value to encode with it.
+    fn namespaced<'v>(&self, record: &'v Record) -> Option<(i8, ExtCodec, &'v Value)> {
+        if record.len() != 2 {
+            return None;
+        }
+        let ext_type = i8::try_from(record.get("ext_type")?.as_int().ok()?).ok()?;
+        let codec = *self.opts.ext_codecs.get(&ext_type)?;
+        Some((ext_type, codec, record.get("value")?))
+    }
+
+    /// Trace a decision about the value being encoded, with `--debug`.
+    fn trace(&self, msg: impl FnOnce() -> String) {
+        trace::log(self.opts.debug, "encode", || {
+            format!("{}: {}", self.path, msg())
+        });
+    }
+
+    /// The `{msg, label, span, row, column}` record that an error value is written as with
+    /// `--errors record`, where `row` and `column` locate it in a table.
+    fn error_details(&self, error: &ShellError) -> Value {
+        let span = Span::unknown();
+        // The primary label of the error report, if it has one.
+        let label = error.labels().and_then(|mut labels| labels.next());
+        let mut record = Record::new();
+        record.push("msg", Value::string(error.to_string(), span));
+        record.push(
+            "label",
+            match label.as_ref().and_then(|label| label.label()) {
+                Some(text) => Value::string(text, span),
+                None => Value::nothing(span),
+            },
+        );
+        record.push(
+            "span",
+            match &label {
+                Some(label) => {
+                    let mut offsets = Record::new();
+                    offsets.push("start", Value::int(label.offset() as i64, span));
+                    offsets.push(
+                        "end",
+                        Value::int((label.offset() + label.len()) as i64, span),
+                    );
+                    Value::record(offsets, span)
+                }
+                None => Value::nothing(span),
+            },
+        );
+        let (row, column) = match self.path.table_cell() {
+            Some((row, column)) => (Value::int(row as i64, span), Value::string(column, span)),
+            None => (Value::nothing(span), Value::nothing(span)),
+        };
+        record.push("row", row);
+        record.push("column", column);
+        Value::record(record, span)
+    }
+
+    /// Check if the string being encoded should be written as bin.
+    fn is_bin_string(&self) -> bool {
+        self.opts.strings_as_bin
+            || self
+                .opts
+                .bin_paths
+                .iter()
+                .any(|path| self.path.matches_or_element_of(&path.members))
+    }
+
+    fn write_int<W: Write>(&self, w: &mut W, int: i64, value: &Value) -> Result<(), WriteError> {
+        match self.opts.int_width {
+            IntWidth::Auto => {
+                encode::write_sint(w, int)?;
+            }
+            IntWidth::Eight => encode::write_i64(w, int)?,
+            IntWidth::Four => {
+                let int = i32::try_from(int).map_err(|_| LabeledError {
+                    label: "Integer too wide".into(),
+                    msg: format!("{int} at {} doesn't fit in --int-width 4", self.path),
+                    span: Some(value.span()),
+                })?;
+                encode::write_i32(w, int)?
+            }
+        }
+        Ok(())
+    }
+
+    fn write_str<W: Write>(&self, w: &mut W, s: &str) -> Result<(), WriteError> {
+        match self.opts.compat {
+            true => write_raw(w, s.as_bytes()),
+            false => Ok(encode::write_str(w, s)?),
+        }
+    }
+
+    /// Write the key of a record column, which with `--int-keys` is an integer if it's the
+    /// string form of one. Keys like `"007"` or `"+1"` stay strings, as they wouldn't round-trip.
+    fn write_key<W: Write>(&self, w: &mut W, k: &str, value: &Value) -> Result<(), WriteError> {
+        if self.opts.int_keys {
+            if let Ok(int) = k.parse::<i64>() {
+                if int.to_string() == k {
+                    return self.write_int(w, int, value);
+                }
+            }
+        }
+        self.write_str(w, k)
+    }
+
+    fn array<W: Write>(&mut self, w: &mut W, vals: &[Value]) -> Result<(), WriteError> {
+        encode::write_array_len(w, vals.len() as u32)?;
+        for (i, v) in vals.iter().enumerate() {
+            self.encode_at(w, PathSegment::Index(i), v)?;
+        }
+        Ok(())
+    }
+
+    /// Write a table as a map of its `columns`, as an array of strings, and its `rows`, as an
+    /// array of arrays of the values of each row in column order.
+    ///
+    /// With `--canonical`, `rows` comes first, as its key sorts first.
+    fn compact_table<W: Write>(&mut self, w: &mut W, rows: &[Value]) -> Result<(), WriteError> {
+        let Value::Record { val: first, .. } = &rows[0] else {
+            unreachable!("checked by is_table");
+        };
+        self.trace(|| {
+            format!(
+                "table of {} rows written with {} column names once",
+                rows.len(),
+                first.len()
+            )
+        });
+
+        let columns = |this: &Self, w: &mut W| -> Result<(), WriteError> {
+            this.write_str(w, "columns")?;
+            encode::write_array_len(w, first.len() as u32)?;
+            for column in first.columns() {
+                this.write_str(w, column)?;
+            }
+            Ok(())
+        };
+
+        encode::write_map_len(w, 2)?;
+        if !self.opts.canonical {
+            columns(self, w)?;
+        }
+        self.write_str(w, "rows")?;
+        encode::write_array_len(w, rows.len() as u32)?;
+        for (i, row) in rows.iter().enumerate() {
+            let Value::Record { val: row, .. } = row else {
+                unreachable!("checked by is_table");
+            };
+            self.path.push(PathSegment::Index(i));
+            encode::write_array_len(w, row.len() as u32)?;
+            for (column, value) in row.iter() {
+                self.encode_at(w, PathSegment::Key(column.clone()), value)?;
+            }
+            self.path.pop();
+        }
+        if self.opts.canonical {
+            columns(self, w)?;
+        }
+        Ok(())
+    }
+
+    /// Write a map of `len` entries.
+    ///
+    /// With `--canonical`, the entries are sorted by the encoded bytes of their keys. Together
+    /// with the minimal-width integer and length encoding that [rmp] always uses, this makes equal
+    /// values encode to identical bytes.
+    fn map<'v, W: Write>(
+        &mut self,
+        w: &mut W,
+        len: usize,
+        entries: impl Iterator<Item = Entry<'v>>,
+    ) -> Result<(), WriteError> {
+        encode::write_map_len(w, len as u32)?;
+        if !self.opts.canonical {
+            for entry in entries {
+                self.entry(w, entry)?;
+            }
+            return Ok(());
+        }
+
+        let mut encoded = Vec::with_capacity(len);
+        for entry in entries {
+            let mut key = vec![];
+            let mut value = vec![];
+            match entry {
+                Entry::Column(k, v) => {
+                    self.write_key(&mut key, k, v)?;
+                    self.encode_at(&mut value, PathSegment::Key(k.into()), v)?;
+                }
+                Entry::Pair(i, k, v) => {
+                    self.path.push(PathSegment::Index(i));
+                    self.encode_at(&mut key, PathSegment::Key("key".into()), k)?;
+                    self.encode_at(&mut value, PathSegment::Key("value".into()), v)?;
+                    self.path.pop();
+                }
+            }
+            encoded.push((key, value));
+        }
+        encoded.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (key, value) in encoded {
+            w.write_all(&key)?;
+            w.write_all(&value)?;
+        }
+        Ok(())
+    }
+
+    fn entry<W: Write>(&mut self, w: &mut W, entry: Entry) -> Result<(), WriteError> {
+        match entry {
+            Entry::Column(k, v) => {
+                self.write_key(w, k, v)?;
+                self.encode_at(w, PathSegment::Key(k.into()), v)
+            }
+            Entry::Pair(i, k, v) => {
+                self.path.push(PathSegment::Index(i));
+                self.encode_at(w, PathSegment::Key("key".into()), k)?;
+                self.encode_at(w, PathSegment::Key("value".into()), v)?;
+                self.path.pop();
+                Ok(())
+            }
+        }
+    }
+
+    /// Encode a value nested at `segment` of the current path.
+    ///
+    /// A value at one of the `--compress-paths` is written as bin holding its encoding compressed
+    /// with gzip.
+    fn encode_at<W: Write>(
+        &mut self,
+        w: &mut W,
+        segment: PathSegment,
+        value: &Value,
+    ) -> Result<(), WriteError> {
+        self.path.push(segment);
+        let compress = self
+            .opts
+            .compress_paths
+            .iter()
+            .any(|path| self.path.matches(&path.members));
+        if compress {
+            let mut encoded = vec![];
+            self.encode(&mut encoded, value)?;
+            let compressed = decompress::gzip(&encoded);
+            self.trace(|| format!("compressed {} bytes to {}", encoded.len(), compressed.len()));
+            match self.opts.compat {
+                true => write_raw(w, &compressed)?,
+                false => encode::write_bin(w, &compressed)?,
+            }
+        } else {
+            self.encode(w, value)?;
+        }
+        self.path.pop();
+        Ok(())
+    }
+}
+
+/// Encode the elements of a list in chunks on a thread pool, and write the chunks in order.
+fn parallel_array<W: Write>(
+    w: &mut W,
+    vals: &[Value],
+    opts: &EncodeOptions,
+    threads: usize,
+) -> Result<(), WriteError> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| LabeledError {
+            label: "Failed to start threads".into(),
+            msg: e.to_string(),
+            span: None,
+        })?;
+
+    // A few chunks per thread keeps them busy when some rows are bigger than others.
+    let chunk_len = vals.len().div_ceil(pool.current_num_threads() * 4).max(1);
+    let chunks: Vec<Vec<u8>> = pool.install(|| {
+        vals.par_chunks(chunk_len)
+            .enumerate()
+            .map(|(chunk, vals)| {
+                let mut encoder = Encoder {
+                    opts,
+                    path: Path::default(),
+                };
+                let mut encoded = vec![];
+                for (i, v) in vals.iter().enumerate() {
+                    let index = chunk * chunk_len + i;
+                    encoder.encode_at(&mut encoded, PathSegment::Index(index), v)?;
+                }
+                Ok(encoded)
+            })
+            .collect::<Result<_, WriteError>>()
+    })?;
+
+    encode::write_array_len(w, vals.len() as u32)?;
+    for chunk in chunks {
+        w.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Write a string or binary as a raw value of the old spec, which only has the fixraw, raw16 and
+/// raw32 types that became fixstr, str16 and str32.
+fn write_raw<W: Write>(w: &mut W, data: &[u8]) -> Result<(), WriteError> {
+    let len = data.len();
+    if len < 32 {
+        w.write_all(&[Marker::FixStr(len as u8).to_u8()])?;
+    } else if let Ok(len) = u16::try_from(len) {
+        w.write_all(&[Marker::Str16.to_u8()])?;
+        w.write_all(&len.to_be_bytes())?;
+    } else {
+        w.write_all(&[Marker::Str32.to_u8()])?;
+        w.write_all(&(len as u32).to_be_bytes())?;
+    }
+    w.write_all(data)?;
+    Ok(())
+}
+
+fn write_ext<W: Write>(w: &mut W, ext_type: i8, data: &[u8]) -> Result<(), WriteError> {
+    encode::write_ext_meta(w, data.len() as u32, ext_type)?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// The payload of a msgpack timestamp, ext type -1.
+pub fn timestamp_data(seconds: i64, nanos: u32) -> Vec<u8> {
+    // use the smallest datetime representation possible
+    // TODO: implement 8 byte representation
+    if let (Ok(seconds), 0) = (u32::try_from(seconds), nanos) {
+        seconds.to_be_bytes().to_vec()
+    } else {
+        let mut data = Vec::with_capacity(12);
+        data.extend_from_slice(&nanos.to_be_bytes());
+        data.extend_from_slice(&seconds.to_be_bytes());
+        data
+    }
+}
+
+/// Check if a record has the shape of an unknown ext value decoded by `from msgpack`, i.e.
+/// `{ext_type: <int>, data: <binary>}`, and if so return the ext type and data.
+fn as_ext(record: &Record) -> Option<(i8, &[u8])> {
+    if record.len() != 2 {
+        return None;
+    }
+
+    match (record.get("ext_type")?, record.get("data")?) {
+        (Value::Int { val: id, .. }, Value::Binary { val, .. }) => {
+            Some((i8::try_from(*id).ok()?, val))
+        }
+        _ => None,
+    }
+}
+
+/// Check if a list is a non-empty table: records that all have the same columns in the same
+/// order.
+fn is_table(vals: &[Value]) -> bool {
+    let Some(Value::Record { val: first, .. }) = vals.first() else {
+        return false;
+    };
+    vals.iter().all(|v| match v {
+        Value::Record { val, .. } => val.columns().eq(first.columns()),
+        _ => false,
     })
 }
+
+/// Check if a list is a non-empty list of `{key, value}` records.
+fn is_key_value_pairs(vals: &[Value]) -> bool {
+    !vals.is_empty()
+        && vals.iter().all(|v| match v {
+            Value::Record { val, .. } => {
+                let columns: Vec<_> = val.columns().collect();
+                columns == ["key", "value"]
+            }
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::ast::PathMember;
+    use nu_protocol::{Record, ShellError, Span, Value};
+
+    use super::*;
+    use crate::canonical;
+    use crate::from::{self, DecodeOptions};
+
+    fn record(entries: Vec<(&str, Value)>) -> Value {
+        let record: Record = entries
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        Value::record(record, Span::unknown())
+    }
+
+    fn canonical() -> EncodeOptions {
+        EncodeOptions {
+            canonical: true,
+            ..EncodeOptions::default()
+        }
+    }
+
+    #[test]
+    fn canonical_sorts_keys() {
+        let span = Span::unknown();
+        let a = record(vec![
+            ("b", Value::int(1, span)),
+            (
+                "a",
+                record(vec![
+                    ("y", Value::int(2, span)),
+                    ("x", Value::nothing(span)),
+                ]),
+            ),
+        ]);
+        let b = record(vec![
+            (
+                "a",
+                record(vec![
+                    ("x", Value::nothing(span)),
+                    ("y", Value::int(2, span)),
+                ]),
+            ),
+            ("b", Value::int(1, span)),
+        ]);
+
+        let encoded = to_msgpack(&a, &canonical()).unwrap();
+        assert_eq!(encoded, to_msgpack(&b, &canonical()).unwrap());
+        assert!(canonical::check(&encoded).is_empty());
+        // without --canonical, the keys keep their order
+        assert!(!canonical::check(&to_msgpack(&a, &EncodeOptions::default()).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn canonical_nan() {
+        let nan = f64::from_bits(f64::NAN.to_bits() | 1);
+        let encoded = to_msgpack(&Value::float(nan, Span::unknown()), &canonical()).unwrap();
+        let mut expected = vec![0xcb];
+        expected.extend(f64::NAN.to_be_bytes());
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn typed_arrays_non_finite() {
+        let span = Span::unknown();
+        let floats = |last: f64| {
+            let vals = [1.0, 2.0, last].map(|f| Value::float(f, span));
+            Value::list(vals.to_vec(), span)
+        };
+        let typed = |opts: EncodeOptions| EncodeOptions {
+            typed_arrays: Some(1),
+            typed_array_min: 1,
+            ..opts
+        };
+
+        let packed = to_msgpack(&floats(3.0), &typed(EncodeOptions::default())).unwrap();
+        assert_eq!(packed[0], 0xc7);
+
+        let nan = floats(f64::from_bits(f64::NAN.to_bits() | 1));
+        let opts = typed(EncodeOptions {
+            non_finite: NonFiniteMode::Error,
+            ..EncodeOptions::default()
+        });
+        assert!(to_msgpack(&nan, &opts).is_err());
+
+        let encoded = to_msgpack(&nan, &typed(canonical())).unwrap();
+        assert_eq!(encoded[0], 0x93);
+        assert_eq!(encoded[encoded.len() - 8..], f64::NAN.to_be_bytes());
+    }
+
+    #[test]
+    fn canonical_error_records() {
+        let span = Span::unknown();
+        let error = Value::error(ShellError::NushellFailed { msg: "boom".into() }, span);
+        let table = Value::list(vec![record(vec![("cell", error)])], span);
+        let opts = EncodeOptions {
+            errors: ErrorMode::Record,
+            ..canonical()
+        };
+        assert!(canonical::check(&to_msgpack(&table, &opts).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn error_records() {
+        let span = Span::unknown();
+        let error = Value::error(ShellError::NushellFailed { msg: "boom".into() }, span);
+        let table = Value::list(vec![record(vec![("cell", error)])], span);
+        let encoded = to_msgpack(&table, &EncodeOptions::default()).unwrap();
+        let decoded = rmpv::decode::read_value(&mut &encoded[..]).unwrap();
+        let details = &decoded[0]["cell"]["error"];
+        assert!(details["msg"].as_str().unwrap().contains("boom"));
+        assert_eq!(details["row"].as_u64(), Some(0));
+        assert_eq!(details["column"].as_str(), Some("cell"));
+    }
+
+    #[test]
+    fn namespaced_records() {
+        let span = Span::unknown();
+        let uuid = "123e4567-e89b-12d3-a456-426614174000";
+        let ext_codecs = HashMap::from([(5, ExtCodec::Uuid), (6, ExtCodec::NestedMsgpack)]);
+        let tagged = |ext_type, value| {
+            record(vec![
+                ("ext_type", Value::int(ext_type, span)),
+                ("value", value),
+            ])
+        };
+        let value = Value::list(
+            vec![
+                tagged(5, Value::string(uuid, span)),
+                tagged(6, Value::list(vec![Value::int(1, span)], span)),
+            ],
+            span,
+        );
+        let encode_opts = EncodeOptions {
+            ext_codecs: ext_codecs.clone(),
+            ..EncodeOptions::default()
+        };
+        let encoded = to_msgpack(&value, &encode_opts).unwrap();
+        let mut expected = vec![0x92, 0xd8, 0x05];
+        expected.extend(ext::parse_uuid(uuid).unwrap());
+        expected.extend([0xd5, 0x06, 0x91, 0x01]);
+        assert_eq!(encoded, expected);
+
+        let decode_opts = DecodeOptions {
+            ext_codecs,
+            ..DecodeOptions::default()
+        };
+        let decoded = from::decode(&encoded, &decode_opts).unwrap();
+        let expected = Value::list(
+            vec![
+                Value::string(uuid, span),
+                Value::list(vec![Value::int(1, span)], span),
+            ],
+            span,
+        );
+        assert_eq!(decoded, expected);
+
+        // ext types outside the namespace stay records
+        let other = tagged(9, Value::string(uuid, span));
+        let encoded = to_msgpack(&other, &encode_opts).unwrap();
+        assert_eq!(encoded[0], 0x82);
+    }
+
+    #[test]
+    fn invalid_namespaced_records() {
+        let span = Span::unknown();
+        let opts = EncodeOptions {
+            ext_codecs: HashMap::from([(5, ExtCodec::Uuid)]),
+            ..EncodeOptions::default()
+        };
+        let value = record(vec![
+            ("ext_type", Value::int(5, span)),
+            ("value", Value::string("not a uuid", span)),
+        ]);
+        assert_eq!(
+            to_msgpack(&value, &opts).unwrap_err().label,
+            "Invalid ext value"
+        );
+    }
+
+    #[test]
+    fn compressed_list() {
+        let span = Span::unknown();
+        let value = record(vec![(
+            "a",
+            Value::list(vec![Value::int(1, span), Value::int(2, span)], span),
+        )]);
+        let compress_paths = vec![CellPath {
+            members: vec![PathMember::string("a".into(), false, span)],
+        }];
+        let opts = EncodeOptions {
+            compress_paths: compress_paths.clone(),
+            ..EncodeOptions::default()
+        };
+        let encoded = to_msgpack(&value, &opts).unwrap();
+
+        // only the list is compressed, not also its elements
+        let decoded = rmpv::decode::read_value(&mut &encoded[..]).unwrap();
+        let compressed = decoded["a"].as_slice().unwrap();
+        let list = decompress::gunzip(compressed, None).unwrap().unwrap();
+        assert_eq!(list, [0x92, 0x01, 0x02]);
+
+        let opts = DecodeOptions {
+            compress_paths,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(from::decode(&encoded, &opts).unwrap(), value);
+    }
+}
@@ -0,0 +1,466 @@
+//! Transcoding between msgpack and JSON at the wire level, without converting to nu values.
+//!
+//! Values convert the way `from msgpack | to json` would convert them: bins become arrays of
+//! bytes, timestamps become RFC 3339 strings, other ext values become `{ext_type, data}` objects
+//! and NaN and infinite floats become null. Map keys that are numbers, bools or nil are written
+//! as their JSON text, e.g. `"1"`.
+
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use base64::Engine;
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::Value;
+use rmp::encode::{self, ValueWriteError};
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+
+use crate::wire::{Header, Kind, StreamReader};
+use crate::{flags, from, text};
+
+/// How to write bins, which JSON has no type for.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum BinMode {
+    /// An array of the bytes, like `to json` writes binary.
+    #[default]
+    Array,
+
+    /// A base64 string.
+    Base64,
+}
+
+/// Options for writing msgpack as JSON.
+#[derive(Default)]
+pub struct JsonOptions {
+    /// Number of spaces to indent nested values with, or `None` to write everything on one line.
+    pub indent: Option<usize>,
+
+    pub bins: BinMode,
+}
+
+/// Write the single msgpack value of the input as JSON.
+pub fn msgpack_to_json<R: Read, W: Write>(r: R, w: &mut W, opts: &JsonOptions) -> io::Result<()> {
+    let mut writer = JsonWriter {
+        r: StreamReader::new(r),
+        w,
+        opts,
+        buf: vec![],
+    };
+    let header = writer
+        .r
+        .read_header()?
+        .ok_or_else(|| invalid_data("the input is empty".into()))?;
+    writer.value(header, 0)?;
+
+    let end = writer.r.pos();
+    if writer.r.read_header()?.is_some() {
+        return Err(invalid_data(format!(
+            "the input has more data after the value that ends at byte {end}"
+        )));
+    }
+    Ok(())
+}
+
+/// `msgpack to-json`: write msgpack input as JSON text.
+pub fn to_json(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let opts = JsonOptions {
+        indent: match (call.has_flag("raw")?, call.get_flag::<usize>("indent")?) {
+            (true, None) => None,
+            (false, indent) => Some(indent.unwrap_or(2)),
+            (true, Some(_)) => {
+                return Err(LabeledError {
+                    label: "Conflicting flags".into(),
+                    msg: "--raw and --indent can't be used together".into(),
+                    span: Some(call.head),
+                })
+            }
+        },
+        bins: flags::choice_flag(
+            call,
+            "bins",
+            &[("array", BinMode::Array), ("base64", BinMode::Base64)],
+        )?
+        .unwrap_or_default(),
+    };
+
+    let bin = text::input_binary(call, input)?;
+    let mut json = vec![];
+    msgpack_to_json(&*bin, &mut json, &opts).map_err(|e| LabeledError {
+        label: "Failed to convert msgpack to JSON".into(),
+        msg: e.to_string(),
+        span: Some(input.span()),
+    })?;
+    let json = String::from_utf8(json).expect("JSON is written as UTF-8");
+    Ok(Value::string(json, call.head))
+}
+
+/// Writes msgpack values as JSON while reading them.
+struct JsonWriter<'a, R, W> {
+    r: StreamReader<R>,
+    w: &'a mut W,
+    opts: &'a JsonOptions,
+
+    /// Payload of the current str, bin or ext value.
+    buf: Vec<u8>,
+}
+
+impl<R: Read, W: Write> JsonWriter<'_, R, W> {
+    fn value(&mut self, header: Header, depth: usize) -> io::Result<()> {
+        if depth > from::DEFAULT_MAX_DEPTH {
+            return Err(invalid_data(format!(
+                "the value at byte {} is nested more than {} levels deep",
+                header.offset,
+                from::DEFAULT_MAX_DEPTH
+            )));
+        }
+
+        match header.kind {
+            Kind::Nil => self.w.write_all(b"null")?,
+            Kind::Bool(b) => write!(self.w, "{b}")?,
+            Kind::Uint(u) => write!(self.w, "{u}")?,
+            Kind::Int(i) => write!(self.w, "{i}")?,
+            // serde_json writes NaN and infinities as null.
+            Kind::F32 | Kind::F64 => serde_json::to_writer(&mut *self.w, &self.r.float(&header))?,
+            Kind::Str(len) => {
+                self.r.read_data(len as usize, &mut self.buf)?;
+                let s = std::str::from_utf8(&self.buf).map_err(|e| {
+                    invalid_data(format!(
+                        "the string at byte {} is not valid UTF-8: {e}",
+                        header.offset
+                    ))
+                })?;
+                serde_json::to_writer(&mut *self.w, s)?
+            }
+            Kind::Bin(len) => {
+                self.r.read_data(len as usize, &mut self.buf)?;
+                self.bin()?
+            }
+            Kind::Ext(-1, len) => {
+                self.r.read_data(len as usize, &mut self.buf)?;
+                let date = from::ext_timestamp_to_nu(&self.buf)
+                    .and_then(|date| Ok(date.as_date()?))
+                    .map_err(|e| invalid_data(format!("at byte {}: {}", header.offset, e.msg)))?;
+                serde_json::to_writer(&mut *self.w, &date.to_rfc3339())?
+            }
+            Kind::Ext(ext_type, len) => {
+                self.r.read_data(len as usize, &mut self.buf)?;
+                self.w.write_all(b"{")?;
+                self.newline(depth + 1)?;
+                write!(self.w, "\"ext_type\":{}{ext_type},", self.space())?;
+                self.newline(depth + 1)?;
+                write!(self.w, "\"data\":{}", self.space())?;
+                self.bin()?;
+                self.newline(depth)?;
+                self.w.write_all(b"}")?
+            }
+            Kind::Array(len) => {
+                self.w.write_all(b"[")?;
+                for i in 0..len {
+                    if i > 0 {
+                        self.w.write_all(b",")?;
+                    }
+                    self.newline(depth + 1)?;
+                    let element = self.next_header()?;
+                    self.value(element, depth + 1)?;
+                }
+                if len > 0 {
+                    self.newline(depth)?;
+                }
+                self.w.write_all(b"]")?
+            }
+            Kind::Map(len) => {
+                self.w.write_all(b"{")?;
+                for i in 0..len {
+                    if i > 0 {
+                        self.w.write_all(b",")?;
+                    }
+                    self.newline(depth + 1)?;
+                    let key = self.next_header()?;
+                    match key.kind {
+                        Kind::Str(_) => self.value(key, depth + 1)?,
+                        Kind::Nil | Kind::Bool(_) | Kind::Uint(_) | Kind::Int(_) => {
+                            self.w.write_all(b"\"")?;
+                            self.value(key, depth + 1)?;
+                            self.w.write_all(b"\"")?
+                        }
+                        _ => {
+                            return Err(invalid_data(format!(
+                                "the map key at byte {} is a {}, which can't be a JSON key",
+                                key.offset,
+                                key.type_name()
+                            )))
+                        }
+                    }
+                    write!(self.w, ":{}", self.space())?;
+                    let value = self.next_header()?;
+                    self.value(value, depth + 1)?;
+                }
+                if len > 0 {
+                    self.newline(depth)?;
+                }
+                self.w.write_all(b"}")?
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the bin in `buf`.
+    fn bin(&mut self) -> io::Result<()> {
+        match self.opts.bins {
+            BinMode::Array => serde_json::to_writer(&mut *self.w, &self.buf)?,
+            BinMode::Base64 => {
+                let text = base64::engine::general_purpose::STANDARD.encode(&self.buf);
+                serde_json::to_writer(&mut *self.w, &text)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Start a new line indented for a value at `depth`, when writing indented JSON.
+    fn newline(&mut self, depth: usize) -> io::Result<()> {
+        if let Some(indent) = self.opts.indent {
+            write!(self.w, "\n{:1$}", "", indent * depth)?;
+        }
+        Ok(())
+    }
+
+    /// Space after colons, when writing indented JSON.
+    fn space(&self) -> &'static str {
+        match self.opts.indent {
+            Some(_) => " ",
+            None => "",
+        }
+    }
+
+    /// Read the header of the next element or entry of a container.
+    fn next_header(&mut self) -> io::Result<Header> {
+        let pos = self.r.pos();
+        self.r.read_header()?.ok_or_else(|| {
+            invalid_data(format!(
+                "unexpected end of input at byte {pos}, expected more values"
+            ))
+        })
+    }
+}
+
+/// Write the single JSON value of the input as msgpack.
+///
+/// Msgpack needs the length of arrays and maps up front, so nested ones are buffered. The
+/// top-level one is written with a placeholder length that's filled in afterwards instead,
+/// which keeps the memory use down for documents that are one huge array.
+pub fn json_to_msgpack<R: Read, W: Write + Seek>(r: R, w: &mut W) -> io::Result<()> {
+    let mut de = serde_json::Deserializer::from_reader(r);
+    Transcode { w, root: true }.deserialize(&mut de)?;
+    de.end()?;
+    Ok(())
+}
+
+/// A writer that [Transcode] can also patch placeholders in.
+trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// Writes the JSON value it deserializes as msgpack.
+struct Transcode<'w> {
+    w: &'w mut dyn WriteSeek,
+    root: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for Transcode<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Transcode<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_bool<E: de::Error>(mut self, v: bool) -> Result<(), E> {
+        encode::write_bool(&mut self.w, v).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(mut self, v: i64) -> Result<(), E> {
+        encode::write_sint(&mut self.w, v)
+            .map(drop)
+            .map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(mut self, v: u64) -> Result<(), E> {
+        encode::write_uint(&mut self.w, v)
+            .map(drop)
+            .map_err(E::custom)
+    }
+
+    fn visit_f64<E: de::Error>(mut self, v: f64) -> Result<(), E> {
+        encode::write_f64(&mut self.w, v).map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(mut self, v: &str) -> Result<(), E> {
+        encode::write_str(&mut self.w, v).map_err(E::custom)
+    }
+
+    fn visit_unit<E: de::Error>(mut self) -> Result<(), E> {
+        encode::write_nil(&mut self.w).map_err(E::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut array = Container::begin(self, rmp::Marker::Array32).map_err(de::Error::custom)?;
+        while seq.next_element_seed(array.next())?.is_some() {
+            array.len += 1;
+        }
+        array.end(encode::write_array_len)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut object = Container::begin(self, rmp::Marker::Map32).map_err(de::Error::custom)?;
+        while map.next_key_seed(object.next())?.is_some() {
+            map.next_value_seed(object.next())?;
+            object.len += 1;
+        }
+        object.end(encode::write_map_len)
+    }
+}
+
+/// An array or map being written, whose length is only known at its end.
+struct Container<'w> {
+    w: &'w mut dyn WriteSeek,
+
+    /// Position of the placeholder header of the top-level container, whose contents are
+    /// written straight to the output.
+    placeholder: Option<u64>,
+
+    /// Contents of nested containers, which are written after their header at the end.
+    buf: Cursor<Vec<u8>>,
+
+    len: u32,
+}
+
+impl<'w> Container<'w> {
+    fn begin(t: Transcode<'w>, marker: rmp::Marker) -> io::Result<Self> {
+        let placeholder = match t.root {
+            true => {
+                let start = t.w.stream_position()?;
+                t.w.write_all(&[marker.to_u8(), 0, 0, 0, 0])?;
+                Some(start)
+            }
+            false => None,
+        };
+        Ok(Container {
+            w: t.w,
+            placeholder,
+            buf: Cursor::new(vec![]),
+            len: 0,
+        })
+    }
+
+    /// Where to write the next element, key or value.
+    fn next(&mut self) -> Transcode<'_> {
+        let w: &mut dyn WriteSeek = match self.placeholder {
+            Some(_) => self.w,
+            None => &mut self.buf,
+        };
+        Transcode { w, root: false }
+    }
+
+    fn end<E: de::Error, F>(mut self, write_len: F) -> Result<(), E>
+    where
+        F: FnOnce(&mut &'w mut dyn WriteSeek, u32) -> Result<rmp::Marker, ValueWriteError>,
+    {
+        match self.placeholder {
+            Some(start) => {
+                self.w.seek(SeekFrom::Start(start + 1)).map_err(E::custom)?;
+                self.w
+                    .write_all(&self.len.to_be_bytes())
+                    .map_err(E::custom)?;
+                self.w.seek(SeekFrom::End(0)).map_err(E::custom)?;
+            }
+            None => {
+                write_len(&mut self.w, self.len).map_err(E::custom)?;
+                self.w.write_all(self.buf.get_ref()).map_err(E::custom)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::into;
+
+    fn from_json(json: &str) -> Vec<u8> {
+        let mut msgpack = Cursor::new(vec![]);
+        json_to_msgpack(json.as_bytes(), &mut msgpack).unwrap();
+        msgpack.into_inner()
+    }
+
+    fn into_json(msgpack: &[u8], opts: &JsonOptions) -> String {
+        let mut json = vec![];
+        msgpack_to_json(msgpack, &mut json, opts).unwrap();
+        String::from_utf8(json).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let json = r#"[{"a":1,"b":[true,null,-2,0.5,"x"],"c":{}},[],"ü"]"#;
+        let msgpack = from_json(json);
+        assert_eq!(
+            rmpv::decode::read_value(&mut &msgpack[..]).unwrap()[0]["b"][2],
+            rmpv::Value::from(-2)
+        );
+        let back: serde_json::Value =
+            serde_json::from_str(&into_json(&msgpack, &JsonOptions::default())).unwrap();
+        assert_eq!(
+            back,
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+    }
+
+    #[test]
+    fn trailing_data() {
+        let mut msgpack = Cursor::new(vec![]);
+        assert!(json_to_msgpack(&b"1 2"[..], &mut msgpack).is_err());
+    }
+
+    #[test]
+    fn options() {
+        // {"a": [bin 01 ff]}
+        let msgpack = b"\x81\xa1a\x91\xc4\x02\x01\xff";
+        assert_eq!(
+            into_json(msgpack, &JsonOptions::default()),
+            r#"{"a":[[1,255]]}"#
+        );
+        let opts = JsonOptions {
+            indent: Some(2),
+            bins: BinMode::Base64,
+        };
+        assert_eq!(
+            into_json(msgpack, &opts),
+            "{\n  \"a\": [\n    \"Af8=\"\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn ext_values() {
+        let timestamp = into::encode(&rmpv::Value::Ext(-1, into::timestamp_data(0, 5)));
+        assert_eq!(
+            into_json(&timestamp, &JsonOptions::default()),
+            r#""1970-01-01T00:00:00.000000005+00:00""#
+        );
+        assert_eq!(
+            into_json(b"\xd4\x05\x07", &JsonOptions::default()),
+            r#"{"ext_type":5,"data":[7]}"#
+        );
+    }
+}
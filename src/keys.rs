@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+
+use crate::path::PathSegment;
+use crate::wire::{Reader, Role, Walker};
+
+/// List the map keys of a msgpack binary without decoding it, skipping over values using their
+/// length headers.
+///
+/// By default these are the keys of the outermost maps, looking through arrays so that a list of
+/// records gives its columns. With `--recursive`, it's the dotted paths of all keys, with list
+/// indices left out, and how often each occurs.
+pub fn keys(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+    let recursive = call.has_flag("recursive")?;
+
+    // keys in the order they were first seen, and their counts
+    let mut keys: Vec<(String, i64)> = vec![];
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    let mut reader = Reader::new(data);
+    while !reader.is_empty() {
+        let mut walker = Walker::new(reader);
+        while let Some(node) = walker.next() {
+            let node = node.map_err(|e| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: e.to_string(),
+                span: Some(input.span()),
+            })?;
+            let Role::Key(_) = node.role else {
+                continue;
+            };
+
+            // the walker's path is that of the map while it yields a key
+            let parent = walker.path().segments();
+            if !recursive && parent.iter().any(|s| matches!(s, PathSegment::Key(_))) {
+                continue;
+            }
+            let mut path: Vec<&str> = parent
+                .iter()
+                .filter_map(|segment| match segment {
+                    PathSegment::Index(_) => None,
+                    PathSegment::Key(key) => Some(key.as_str()),
+                })
+                .collect();
+            let name = node.key_name();
+            path.push(&name);
+
+            let key = path.join(".");
+            match index.get(&key) {
+                Some(&i) => keys[i].1 += 1,
+                None => {
+                    index.insert(key.clone(), keys.len());
+                    keys.push((key, 1));
+                }
+            }
+        }
+        reader = walker.reader().clone();
+    }
+
+    if !recursive {
+        let keys = keys
+            .into_iter()
+            .map(|(key, _)| Value::string(key, span))
+            .collect();
+        return Ok(Value::list(keys, span));
+    }
+
+    let rows = keys
+        .into_iter()
+        .map(|(path, count)| {
+            let mut row = Record::new();
+            row.push("path", Value::string(path, span));
+            row.push("count", Value::int(count, span));
+            Value::record(row, span)
+        })
+        .collect();
+    Ok(Value::list(rows, span))
+}
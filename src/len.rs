@@ -0,0 +1,96 @@
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Value};
+
+use crate::wire::{Header, Kind, Node, Reader, Role, WireError};
+
+/// Report the type, number of elements and encoded size of a msgpack value by reading its
+/// headers only.
+///
+/// With `--keys`, the value must be a map, and the report of each of its values is added as
+/// a `keys` table.
+pub fn len(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+    let invalid = |e: WireError| LabeledError {
+        label: "Invalid msgpack".into(),
+        msg: e.to_string(),
+        span: Some(input.span()),
+    };
+
+    let mut reader = Reader::new(data);
+    let header = reader.skip_value().map_err(invalid)?;
+    if !reader.is_empty() {
+        return Err(LabeledError {
+            label: "Trailing bytes".into(),
+            msg: format!(
+                "Found {} more bytes after the end of the msgpack value at byte {}",
+                reader.remaining(),
+                reader.pos()
+            ),
+            span: Some(input.span()),
+        });
+    }
+    let mut record = report(&header, reader.pos(), span);
+
+    if call.has_flag("keys")? {
+        let Kind::Map(entries) = header.kind else {
+            return Err(LabeledError {
+                label: "Not a map".into(),
+                msg: format!(
+                    "--keys needs a map at the top level, found {}",
+                    header.kind.family()
+                ),
+                span: Some(input.span()),
+            });
+        };
+
+        let mut reader = Reader::new(data);
+        reader.read_header().map_err(invalid)?;
+        let mut keys = Vec::with_capacity(entries as usize);
+        for i in 0..entries {
+            let key = key_name(&mut reader, i).map_err(invalid)?;
+            let start = reader.pos();
+            let value = reader.skip_value().map_err(invalid)?;
+
+            let mut row = Record::new();
+            row.push("key", Value::string(key, span));
+            for (column, val) in report(&value, reader.pos() - start, span) {
+                row.push(column, val);
+            }
+            keys.push(Value::record(row, span));
+        }
+        record.push("keys", Value::list(keys, span));
+    }
+
+    Ok(Value::record(record, span))
+}
+
+/// A `{type, elements, encoded_bytes}` record of a value. `elements` is the number of elements
+/// of an array or entries of a map, and nothing for other values.
+fn report(header: &Header, encoded_bytes: usize, span: Span) -> Record {
+    let elements = match header.kind {
+        Kind::Array(len) | Kind::Map(len) => Value::int(len.into(), span),
+        _ => Value::nothing(span),
+    };
+
+    let mut record = Record::new();
+    record.push("type", Value::string(header.kind.family(), span));
+    record.push("elements", elements);
+    record.push("encoded_bytes", Value::filesize(encoded_bytes as i64, span));
+    record
+}
+
+/// Skip over the key of the `i`th map entry, returning its name.
+pub fn key_name(reader: &mut Reader, i: u32) -> Result<String, WireError> {
+    let start = reader.pos();
+    let header = reader.skip_value()?;
+    let mut key = Reader::at(reader.input(), start);
+    key.read_header()?;
+    let node = Node {
+        header,
+        data: key.read_data(header.data_len())?,
+        depth: 1,
+        role: Role::Key(i),
+    };
+    Ok(node.key_name())
+}
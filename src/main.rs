@@ -1,9 +1,50 @@
+mod append;
+mod bignum;
+mod bundle;
+mod canonical;
+mod cbor;
+mod concat;
+mod config;
+mod convert;
+mod decompress;
+mod dedup;
+mod diff;
+mod doctor;
+mod dump;
+mod estimate;
+mod ext;
+mod flags;
+mod flatten;
 mod from;
+mod fs;
+mod get;
+mod head;
+mod inspect;
 mod into;
+mod json;
+mod keys;
+mod len;
+mod merge;
+mod nu_ext;
+mod pack_args;
+mod path;
+mod preview;
+mod repair;
+mod rpc;
+mod schema;
+mod snapshot;
+mod stats;
+mod text;
+mod trace;
+mod tree;
+mod typed_array;
+mod verify;
+mod wire;
+
+use std::borrow::Cow;
 
 use nu_plugin::{serve_plugin, EvaluatedCall, LabeledError, MsgPackSerializer, Plugin};
-use nu_protocol::{Category, PluginSignature, Span, Value};
-use rmpv::decode::read_value_ref;
+use nu_protocol::{Category, PluginSignature, Span, Spanned, SyntaxShape, Value};
 
 fn main() {
     serve_plugin(&mut FromMsgpack, MsgPackSerializer {});
@@ -13,15 +54,357 @@ pub struct FromMsgpack;
 
 const FROM_MSGPACK: &str = "from msgpack";
 const TO_MSGPACK: &str = "to msgpack";
+const PACK_ARGS: &str = "msgpack pack-args";
+const BUNDLE_PACK: &str = "msgpack bundle pack";
+const BUNDLE_UNPACK: &str = "msgpack bundle unpack";
+const DOCTOR: &str = "msgpack doctor";
+const SNAPSHOT_RECORD: &str = "msgpack snapshot record";
+const SNAPSHOT_CHECK: &str = "msgpack snapshot check";
+const ESTIMATE: &str = "msgpack estimate";
+const INSPECT: &str = "msgpack inspect";
+const STATS: &str = "msgpack stats";
+const GET: &str = "msgpack get";
+const DIFF: &str = "msgpack diff";
+const DUMP: &str = "msgpack dump";
+const RPC_CALL: &str = "msgpack rpc call";
+const RPC_EXEC: &str = "msgpack rpc exec";
+const NVIM_REQUEST: &str = "msgpack nvim request";
+const NVIM_NOTIFY: &str = "msgpack nvim notify";
+const CONVERT: &str = "msgpack convert";
+const TO_JSON: &str = "msgpack to-json";
+const TO_CBOR: &str = "msgpack to-cbor";
+const FROM_CBOR: &str = "msgpack from-cbor";
+const KEYS: &str = "msgpack keys";
+const LEN: &str = "msgpack len";
+const MERGE: &str = "msgpack merge";
+const VERIFY: &str = "msgpack verify";
+const TREE: &str = "msgpack tree";
+const REPAIR: &str = "msgpack repair";
+const HEAD: &str = "msgpack head";
+const APPEND: &str = "msgpack append";
+const CONCAT: &str = "msgpack concat";
 
 impl Plugin for FromMsgpack {
     fn signature(&self) -> Vec<nu_protocol::PluginSignature> {
         vec![
-            PluginSignature::build(FROM_MSGPACK)
+            from::decode_flags(PluginSignature::build(FROM_MSGPACK))
                 .usage("Convert from msgpack to structured data.")
+                .switch(
+                    "rest",
+                    "decode only the first value and return {value, rest} with the bytes after it",
+                    None,
+                )
+                .named(
+                    "path",
+                    SyntaxShape::Filepath,
+                    "read the msgpack from this file instead of the input",
+                    None,
+                )
+                .named(
+                    "canonical-check",
+                    SyntaxShape::String,
+                    "check that the input is in canonical form, and either fail (error) or return {value, findings} (report)",
+                    None,
+                )
+                .switch(
+                    "no-auto-decompress",
+                    "don't decompress input that starts like gzip or zstd",
+                    None,
+                )
+                .switch(
+                    "mmap",
+                    "map the --path file into memory instead of reading it, so that only the parts that are decoded are read (truncating the file while it's decoded crashes the plugin)",
+                    None,
+                )
+                .switch("base64", "the input is base64 text", None)
+                .switch("hex", "the input is hex text", None)
+                .switch(
+                    "framed",
+                    "the input is a stream of values each prefixed with its length as a big-endian u32",
+                    None,
+                )
+                .named(
+                    "schema",
+                    SyntaxShape::Any,
+                    "check that the decoded value matches this schema, e.g. {name: string, age: int}",
+                    None,
+                )
+                .named(
+                    "skip",
+                    SyntaxShape::Int,
+                    "skip this many elements of a top-level array without decoding them",
+                    None,
+                )
+                .named(
+                    "take",
+                    SyntaxShape::Int,
+                    "decode only this many elements of a top-level array, after --skip",
+                    None,
+                )
+                .named(
+                    "columns",
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    "decode only these keys of the maps in a top-level array, skipping the values of all others",
+                    None,
+                )
+                .switch(
+                    "typed",
+                    "decode every value to a {type, value} record of its wire type, e.g. str8 or uint16, and its value",
+                    None,
+                )
+                .switch(
+                    "ignore-errors",
+                    "replace values that fail to decode with {error: {label, reason, offset}} records and keep going",
+                    None,
+                )
+                .switch(
+                    "flatten",
+                    "flatten nested records into dotted keys, e.g. {a: {b: 1}} to {a.b: 1}",
+                    None,
+                )
+                .named(
+                    "separator",
+                    SyntaxShape::String,
+                    "separator of the keys joined by --flatten (default .)",
+                    None,
+                )
+                .named(
+                    "flatten-depth",
+                    SyntaxShape::Int,
+                    "number of levels of nested records that --flatten merges (default all)",
+                    None,
+                )
                 .category(Category::Formats),
-            PluginSignature::build(TO_MSGPACK)
+            into::encode_flags(PluginSignature::build(TO_MSGPACK))
                 .usage("Converts data into msgpack.")
+                .switch("base64", "output base64 text instead of binary", None)
+                .switch("hex", "output hex text instead of binary", None)
+                .named(
+                    "out",
+                    SyntaxShape::Filepath,
+                    "write the msgpack to this file and return {path, bytes}",
+                    None,
+                )
+                .switch("append", "append to the --out file instead of replacing it", None)
+                .switch(
+                    "framed",
+                    "prefix each element of a list, or the value, with its length as a big-endian u32",
+                    None,
+                )
+                .named(
+                    "schema",
+                    SyntaxShape::Any,
+                    "check that the value matches this schema before encoding it, e.g. {name: string, age: int}",
+                    None,
+                )
+                .category(Category::Formats),
+            into::encode_flags(PluginSignature::build(PACK_ARGS))
+                .usage("Pack a list of values into a msgpack array, e.g. rpc params.")
+                .rest("args", SyntaxShape::Any, "the values to pack")
+                .category(Category::Formats),
+            into::encode_flags(PluginSignature::build(BUNDLE_PACK))
+                .usage("Pack a record of named parts into a single msgpack bundle.")
+                .category(Category::Formats),
+            from::decode_flags(PluginSignature::build(BUNDLE_UNPACK))
+                .usage("Unpack a msgpack bundle into a record of parts.")
+                .switch(
+                    "raw",
+                    "don't decode msgpack parts, return them as binary",
+                    None,
+                )
+                .category(Category::Formats),
+            PluginSignature::build(DOCTOR)
+                .usage("Check a msgpack binary for common problems.")
+                .category(Category::Debug),
+            PluginSignature::build(SNAPSHOT_RECORD)
+                .usage("Store the canonical msgpack encoding of each value of a record as a snapshot.")
+                .required("dir", SyntaxShape::Filepath, "directory to store the snapshots in")
+                .category(Category::Formats),
+            PluginSignature::build(SNAPSHOT_CHECK)
+                .usage("Compare the canonical msgpack encoding of each value of a record with its snapshot.")
+                .required("dir", SyntaxShape::Filepath, "directory the snapshots are stored in")
+                .switch("fail", "return an error if any snapshot doesn't match", None)
+                .category(Category::Formats),
+            PluginSignature::build(ESTIMATE)
+                .usage("Estimate how much memory decoding a msgpack binary would take.")
+                .named(
+                    "max-memory",
+                    SyntaxShape::Filesize,
+                    "warn if decoding would likely take more than this (default 1GiB)",
+                    None,
+                )
+                .category(Category::Debug),
+            PluginSignature::build(INSPECT)
+                .usage("Parse a msgpack binary into a tree of its wire types, offsets and lengths.")
+                .category(Category::Debug),
+            PluginSignature::build(STATS)
+                .usage("Report aggregate information about the contents of a msgpack binary.")
+                .category(Category::Debug),
+            from::decode_flags(PluginSignature::build(GET))
+                .usage("Decode only the value at a cell path of a msgpack binary.")
+                .required("cell_path", SyntaxShape::CellPath, "the path to the value to decode")
+                .category(Category::Formats),
+            from::decode_flags(PluginSignature::build(DIFF))
+                .usage("Compare two msgpack binaries structurally.")
+                .required("other", SyntaxShape::Binary, "the msgpack binary to compare the input with")
+                .category(Category::Formats),
+            PluginSignature::build(DUMP)
+                .usage("List the values of a msgpack binary with their offsets, bytes and wire types.")
+                .category(Category::Debug),
+            rpc_flags(from::decode_flags(PluginSignature::build(RPC_CALL)))
+                .usage("Call a method of a msgpack-rpc server over TCP or a Unix socket.")
+                .required(
+                    "address",
+                    SyntaxShape::String,
+                    "host:port or tcp://host:port, or the path or unix://path of a Unix socket",
+                )
+                .required("method", SyntaxShape::String, "the method to call")
+                .rest("params", SyntaxShape::Any, "the params of the call")
+                .category(Category::Network),
+            rpc_flags(from::decode_flags(PluginSignature::build(RPC_EXEC)))
+                .usage("Call a method of a msgpack-rpc server run as a child process, over its stdin and stdout.")
+                .required(
+                    "command",
+                    SyntaxShape::OneOf(vec![
+                        SyntaxShape::String,
+                        SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    ]),
+                    "the program to run, or a list of the program and its arguments",
+                )
+                .required("method", SyntaxShape::String, "the method to call")
+                .rest("params", SyntaxShape::Any, "the params of the call")
+                .category(Category::Network),
+            nvim_flags(from::decode_flags(PluginSignature::build(NVIM_REQUEST)))
+                .usage("Call a Neovim API method and return its result.")
+                .category(Category::Network),
+            nvim_flags(from::decode_flags(PluginSignature::build(NVIM_NOTIFY)))
+                .usage("Send a notification to a Neovim API method, without waiting for it.")
+                .category(Category::Network),
+            PluginSignature::build(CONVERT)
+                .usage("Convert a file between msgpack and JSON without loading it into nu.")
+                .required("input", SyntaxShape::Filepath, "the file to convert")
+                .required("output", SyntaxShape::Filepath, "the file to write")
+                .named(
+                    "from",
+                    SyntaxShape::String,
+                    "format of the input: msgpack or json (default: by its extension)",
+                    None,
+                )
+                .named(
+                    "to",
+                    SyntaxShape::String,
+                    "format of the output: msgpack or json (default: by its extension)",
+                    None,
+                )
+                .category(Category::Formats),
+            PluginSignature::build(TO_JSON)
+                .usage("Convert msgpack to JSON text directly, without decoding it to nu values first.")
+                .switch("raw", "write the JSON on a single line", None)
+                .named(
+                    "indent",
+                    SyntaxShape::Int,
+                    "number of spaces to indent nested values with (default 2)",
+                    None,
+                )
+                .named(
+                    "bins",
+                    SyntaxShape::String,
+                    "how to write bin values: array (default) of bytes, or base64 string",
+                    None,
+                )
+                .category(Category::Formats),
+            PluginSignature::build(TO_CBOR)
+                .usage("Convert msgpack to CBOR, keeping ext types and timestamps as CBOR tags.")
+                .category(Category::Formats),
+            PluginSignature::build(FROM_CBOR)
+                .usage("Convert CBOR to msgpack, turning the tags that to-cbor writes back into ext types.")
+                .category(Category::Formats),
+            PluginSignature::build(KEYS)
+                .usage("List the map keys of a msgpack binary without decoding it.")
+                .switch(
+                    "recursive",
+                    "list the dotted paths of the keys of all nested maps, with how often they occur",
+                    None,
+                )
+                .category(Category::Debug),
+            PluginSignature::build(LEN)
+                .usage("Report the type, number of elements and encoded size of a msgpack binary without decoding it.")
+                .switch(
+                    "keys",
+                    "also report each value of a top-level map, as a keys table",
+                    None,
+                )
+                .category(Category::Debug),
+            PluginSignature::build(MERGE)
+                .usage("Deep-merge msgpack binaries into one document, merging maps and concatenating arrays.")
+                .rest("others", SyntaxShape::Binary, "the msgpack binaries to merge after the input")
+                .named(
+                    "conflict",
+                    SyntaxShape::String,
+                    "which of two different values at the same path to keep: last (default), \
+                     first, or error",
+                    None,
+                )
+                .category(Category::Formats),
+            PluginSignature::build(VERIFY)
+                .usage("Check that decoding and re-encoding a msgpack binary gives the same bytes.")
+                .switch(
+                    "canonical",
+                    "re-encode with to msgpack --canonical, to check that the input is canonical",
+                    None,
+                )
+                .category(Category::Debug),
+            PluginSignature::build(TREE)
+                .usage("Render a msgpack binary as an indented tree annotated with wire types and sizes.")
+                .named(
+                    "depth",
+                    SyntaxShape::Int,
+                    "collapse containers nested deeper than this",
+                    None,
+                )
+                .named(
+                    "width",
+                    SyntaxShape::Int,
+                    "number of characters of strings to show before truncating them (default 40)",
+                    None,
+                )
+                .switch("no-color", "render without ANSI colors", None)
+                .category(Category::Debug),
+            PluginSignature::build(REPAIR)
+                .usage("Find the complete values of a damaged msgpack stream and where the damage begins.")
+                .switch(
+                    "framed",
+                    "the input is a stream of values each prefixed with its length as a big-endian u32",
+                    None,
+                )
+                .named(
+                    "out",
+                    SyntaxShape::Filepath,
+                    "write the complete values before the damage to this file",
+                    None,
+                )
+                .category(Category::Debug),
+            from::decode_flags(PluginSignature::build(HEAD))
+                .usage("Decode only the first values of a msgpack binary, or the first elements or entries of a single document.")
+                .optional("n", SyntaxShape::Int, "the number of values to decode (default 10)")
+                .category(Category::Formats),
+            into::encode_flags(PluginSignature::build(APPEND))
+                .usage("Append values to a file of concatenated msgpack messages, each element of a list as its own message.")
+                .required("path", SyntaxShape::Filepath, "the file to append to, which is created if it doesn't exist")
+                .switch(
+                    "framed",
+                    "the file is a stream of messages each prefixed with its length as a big-endian u32",
+                    None,
+                )
+                .category(Category::Formats),
+            PluginSignature::build(CONCAT)
+                .usage("Combine msgpack binaries into a single array of their values, copying the values without decoding them.")
+                .rest("paths", SyntaxShape::Filepath, "files to read msgpack from, after the input")
+                .switch(
+                    "flatten",
+                    "add the elements of documents that are a single array, instead of the array",
+                    None,
+                )
                 .category(Category::Formats),
         ]
     }
@@ -29,34 +412,99 @@ impl Plugin for FromMsgpack {
     fn run(
         &mut self,
         name: &str,
-        _config: &Option<Value>,
-        _call: &EvaluatedCall,
+        config: &Option<Value>,
+        call: &EvaluatedCall,
         input: &Value,
     ) -> Result<Value, LabeledError> {
         match name {
             FROM_MSGPACK => {
-                let mut bin = input.as_binary()?;
-
-                let v = match read_value_ref(&mut bin) {
-                    Err(e) => {
-                        return Err(LabeledError {
-                            label: "Invalid msgpack".into(),
-                            msg: e.to_string(),
-                            span: None,
-                        })
-                    }
-                    Ok(v) => v,
+                let opts = from::DecodeOptions::from_call(call, config)?;
+                let file = fs::read_path_flag(call, input)?;
+                let mut bin = match &file {
+                    Some(data) => Cow::Borrowed(&data[..]),
+                    None => text::input_binary(call, input)?,
                 };
-
-                from::rmpv_to_nu(v)
+                if !call.has_flag("no-auto-decompress")? {
+                    bin = trace::time(opts.debug, "decode", "decompression", || {
+                        decompress::auto(bin, opts.max_size, opts.debug)
+                    })?;
+                }
+                let findings = canonical::check_flag(call, &bin, input.span())?;
+                let skip: Option<usize> = call.get_flag("skip")?;
+                let take: Option<usize> = call.get_flag("take")?;
+                let window = skip.is_some() || take.is_some() || opts.columns.is_some();
+                let (rest, framed) = (call.has_flag("rest")?, call.has_flag("framed")?);
+                if rest && framed {
+                    return Err(LabeledError {
+                        label: "Conflicting flags".into(),
+                        msg: "--rest and --framed can't be used together".into(),
+                        span: Some(call.head),
+                    });
+                }
+                if (rest || framed) && window {
+                    return Err(LabeledError {
+                        label: "Conflicting flags".into(),
+                        msg: "--skip, --take and --columns can't be used with --rest or --framed"
+                            .into(),
+                        span: Some(call.head),
+                    });
+                }
+                let value =
+                    trace::time(opts.debug, "decode", "decoding", || match (rest, framed) {
+                        (true, _) => from::decode_with_rest(&bin, &opts),
+                        (_, true) => from::decode_frames(&bin, &opts),
+                        _ if window => from::decode_window(&bin, &opts, skip.unwrap_or(0), take),
+                        _ => from::decode(&bin, &opts),
+                    })?;
+                match findings {
+                    Some(findings) => Ok(canonical::report(value, findings, call.head)),
+                    None => Ok(value),
+                }
             }
             TO_MSGPACK => {
-                let msgpack_value = into::nu_to_rmpv(input.clone())?;
-                let mut encoded = vec![];
-                rmpv::encode::write_value(&mut encoded, &msgpack_value)
-                    .expect("encoding to vec can't fail, right?");
-                Ok(Value::binary(encoded, Span::unknown()))
+                let opts = into::EncodeOptions::from_call(call, config)?;
+                match call.get_flag::<Spanned<String>>("out")? {
+                    Some(out) => into::write_file(call, input, &opts, &out),
+                    None if call.has_flag("append")? => Err(LabeledError {
+                        label: "Missing flag".into(),
+                        msg: "--append only applies with --out".into(),
+                        span: Some(call.head),
+                    }),
+                    None => {
+                        let encoded = into::to_msgpack(input, &opts)?;
+                        text::output_binary(call, encoded, Span::unknown())
+                    }
+                }
             }
+            PACK_ARGS => pack_args::pack_args(call, config, input),
+            BUNDLE_PACK => bundle::pack(call, config, input),
+            BUNDLE_UNPACK => bundle::unpack(call, config, input),
+            DOCTOR => doctor::doctor(call, input),
+            SNAPSHOT_RECORD => snapshot::record(call, input),
+            SNAPSHOT_CHECK => snapshot::check(call, input),
+            ESTIMATE => estimate::estimate(call, input),
+            INSPECT => inspect::inspect(call, input),
+            STATS => stats::stats(call, input),
+            GET => get::get(call, config, input),
+            DIFF => diff::compare(call, config, input),
+            DUMP => dump::dump(call, input),
+            RPC_CALL => rpc::call(call, config),
+            RPC_EXEC => rpc::exec(call, config),
+            NVIM_REQUEST => rpc::nvim(call, config, false),
+            NVIM_NOTIFY => rpc::nvim(call, config, true),
+            CONVERT => convert::convert(call),
+            TO_JSON => json::to_json(call, input),
+            TO_CBOR => cbor::to_cbor(call, input),
+            FROM_CBOR => cbor::from_cbor(call, input),
+            KEYS => keys::keys(call, input),
+            LEN => len::len(call, input),
+            MERGE => merge::merge(call, input),
+            VERIFY => verify::verify(call, input),
+            TREE => tree::tree(call, input),
+            REPAIR => repair::repair(call, input),
+            HEAD => head::head(call, config, input),
+            APPEND => append::append(call, config, input),
+            CONCAT => concat::concat(call, input),
             _ => Err(LabeledError {
                 label: "Unknown command".into(),
                 msg: format!("{name:?} is not a command supported by nu_plugin_msgpack"),
@@ -65,3 +513,42 @@ impl Plugin for FromMsgpack {
         }
     }
 }
+
+/// Add the flags of the msgpack-rpc commands to a signature.
+fn rpc_flags(signature: PluginSignature) -> PluginSignature {
+    signature
+        .switch(
+            "notify",
+            "send a notification and don't wait for a response",
+            None,
+        )
+        .named(
+            "timeout",
+            SyntaxShape::Duration,
+            "give up if there's no response within this time",
+            None,
+        )
+}
+
+/// Add the arguments and flags of the Neovim commands to a signature.
+fn nvim_flags(signature: PluginSignature) -> PluginSignature {
+    signature
+        .required(
+            "method",
+            SyntaxShape::String,
+            "the API method, e.g. nvim_command",
+        )
+        .rest("params", SyntaxShape::Any, "the params of the method")
+        .named(
+            "socket",
+            SyntaxShape::String,
+            "address of Neovim, if not the one in $NVIM",
+            None,
+        )
+        .named(
+            "timeout",
+            SyntaxShape::Duration,
+            "give up if there's no response within this time",
+            None,
+        )
+}
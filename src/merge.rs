@@ -0,0 +1,187 @@
+//! Deep merging of msgpack documents.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Spanned, Value};
+
+use crate::diff::key_segment;
+use crate::path::{Path, PathSegment};
+use crate::{flags, into};
+
+/// What to do when documents have different values at the same path, other than two maps or two
+/// arrays.
+#[derive(Clone, Copy, Default)]
+pub enum Conflict {
+    /// Keep the value of the later document.
+    #[default]
+    Last,
+
+    /// Keep the value of the earlier document.
+    First,
+
+    /// Fail the merge.
+    Error,
+}
+
+/// Merge the msgpack binaries of the input, a binary or a list of them, and the arguments into a
+/// single document.
+pub fn merge(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let conflict = flags::choice_flag(
+        call,
+        "conflict",
+        &[
+            ("last", Conflict::Last),
+            ("first", Conflict::First),
+            ("error", Conflict::Error),
+        ],
+    )?
+    .unwrap_or_default();
+
+    let mut docs: Vec<Spanned<&[u8]>> = vec![];
+    match input {
+        Value::Nothing { .. } => {}
+        Value::List { vals, .. } => {
+            for val in vals {
+                docs.push(Spanned {
+                    item: val.as_binary()?,
+                    span: val.span(),
+                });
+            }
+        }
+        input => docs.push(Spanned {
+            item: input.as_binary()?,
+            span: input.span(),
+        }),
+    }
+    let args: Vec<Spanned<Vec<u8>>> = call.rest(0)?;
+    docs.extend(args.iter().map(|arg| Spanned {
+        item: arg.item.as_slice(),
+        span: arg.span,
+    }));
+
+    let mut merged: Option<rmpv::Value> = None;
+    for (i, doc) in docs.into_iter().enumerate() {
+        let mut data = doc.item;
+        let value = rmpv::decode::read_value(&mut data)
+            .map_err(|e| e.to_string())
+            .and_then(|value| match data.len() {
+                0 => Ok(value),
+                n => Err(format!(
+                    "found {n} more bytes after the end of the msgpack value"
+                )),
+            })
+            .map_err(|msg| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: format!("In document {i}: {msg}"),
+                span: Some(doc.span),
+            })?;
+
+        merged = Some(match merged {
+            None => value,
+            Some(merged) => {
+                merge_at(&mut Path::default(), merged, value, conflict).map_err(|path| {
+                    LabeledError {
+                        label: "Merge conflict".into(),
+                        msg: format!("Document {i} has a different value at {path}"),
+                        span: Some(doc.span),
+                    }
+                })?
+            }
+        });
+    }
+
+    let merged = merged.ok_or_else(|| LabeledError {
+        label: "Nothing to merge".into(),
+        msg: "Pass msgpack binaries as input or arguments".into(),
+        span: Some(call.head),
+    })?;
+    Ok(Value::binary(into::encode(&merged), call.head))
+}
+
+/// Merge `new` into `old`: maps are merged by key, arrays are concatenated, and other values are
+/// resolved by the conflict policy. Fails with the path of the conflict for [Conflict::Error].
+fn merge_at(
+    path: &mut Path,
+    old: rmpv::Value,
+    new: rmpv::Value,
+    conflict: Conflict,
+) -> Result<rmpv::Value, Path> {
+    Ok(match (old, new) {
+        (rmpv::Value::Map(mut old), rmpv::Value::Map(new)) => {
+            for (k, new_v) in new {
+                match old.iter().position(|(old_k, _)| *old_k == k) {
+                    Some(i) => {
+                        path.push(PathSegment::Key(key_segment(&k)));
+                        let old_v = std::mem::replace(&mut old[i].1, rmpv::Value::Nil);
+                        old[i].1 = merge_at(path, old_v, new_v, conflict)?;
+                        path.pop();
+                    }
+                    None => old.push((k, new_v)),
+                }
+            }
+            rmpv::Value::Map(old)
+        }
+        (rmpv::Value::Array(mut old), rmpv::Value::Array(new)) => {
+            old.extend(new);
+            rmpv::Value::Array(old)
+        }
+        (old, new) if old == new => old,
+        (old, new) => match conflict {
+            Conflict::Last => new,
+            Conflict::First => old,
+            Conflict::Error => return Err(path.clone()),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: Vec<(&str, rmpv::Value)>) -> rmpv::Value {
+        rmpv::Value::Map(entries.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    fn merge(
+        old: rmpv::Value,
+        new: rmpv::Value,
+        conflict: Conflict,
+    ) -> Result<rmpv::Value, String> {
+        merge_at(&mut Path::default(), old, new, conflict).map_err(|path| path.to_string())
+    }
+
+    #[test]
+    fn maps_and_arrays() {
+        let old = map(vec![
+            ("a", map(vec![("x", 1.into())])),
+            ("list", rmpv::Value::Array(vec![1.into()])),
+        ]);
+        let new = map(vec![
+            ("a", map(vec![("y", 2.into())])),
+            ("list", rmpv::Value::Array(vec![2.into()])),
+            ("b", true.into()),
+        ]);
+        let expected = map(vec![
+            ("a", map(vec![("x", 1.into()), ("y", 2.into())])),
+            ("list", rmpv::Value::Array(vec![1.into(), 2.into()])),
+            ("b", true.into()),
+        ]);
+        assert_eq!(merge(old, new, Conflict::Error), Ok(expected));
+    }
+
+    #[test]
+    fn conflicts() {
+        let old = map(vec![("a", map(vec![("x", 1.into())]))]);
+        let new = map(vec![("a", map(vec![("x", 2.into())]))]);
+        assert_eq!(
+            merge(old.clone(), new.clone(), Conflict::Last),
+            Ok(new.clone())
+        );
+        assert_eq!(
+            merge(old.clone(), new.clone(), Conflict::First),
+            Ok(old.clone())
+        );
+        assert_eq!(merge(old.clone(), new, Conflict::Error), Err("a.x".into()));
+        // equal values don't conflict
+        assert_eq!(merge(old.clone(), old.clone(), Conflict::Error), Ok(old));
+    }
+}
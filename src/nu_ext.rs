@@ -0,0 +1,165 @@
+//! Nu ext values: nu types that msgpack has no equivalent of, tagged so that they survive a
+//! round trip through `to msgpack --nu-ext` and `from msgpack --nu-ext`.
+//!
+//! The ext payload is a single kind byte followed by the value:
+//!
+//! | byte | type      | value                                                      |
+//! |------|-----------|------------------------------------------------------------|
+//! | `d`  | duration  | nanoseconds as a big-endian i64                            |
+//! | `f`  | filesize  | bytes as a big-endian i64                                  |
+//! | `c`  | cell path | msgpack array of `[member, optional]` arrays               |
+//! | `r`  | range     | msgpack array `[from, incr, to, inclusive]`, bounds or nil |
+
+use nu_plugin::LabeledError;
+use nu_protocol::ast::{CellPath, PathMember, RangeInclusion};
+use nu_protocol::{Range, Span, Value};
+
+use crate::into;
+
+/// Encode a nu value as a nu ext payload, or `None` if msgpack can represent it itself.
+pub fn encode(value: &Value) -> Option<Vec<u8>> {
+    let (kind, data) = match value {
+        Value::Duration { val, .. } => (b'd', val.to_be_bytes().to_vec()),
+        Value::Filesize { val, .. } => (b'f', val.to_be_bytes().to_vec()),
+        Value::CellPath { val, .. } => {
+            let members = val
+                .members
+                .iter()
+                .map(|member| {
+                    let (member, optional) = match member {
+                        PathMember::String { val, optional, .. } => (val.as_str().into(), optional),
+                        PathMember::Int { val, optional, .. } => ((*val as u64).into(), optional),
+                    };
+                    rmpv::Value::Array(vec![member, (*optional).into()])
+                })
+                .collect();
+            (b'c', into::encode(&rmpv::Value::Array(members)))
+        }
+        Value::Range { val, .. } => {
+            let range = vec![
+                bound(&val.from)?,
+                bound(&val.incr)?,
+                bound(&val.to)?,
+                (val.inclusion == RangeInclusion::Inclusive).into(),
+            ];
+            (b'r', into::encode(&rmpv::Value::Array(range)))
+        }
+        _ => return None,
+    };
+
+    let mut payload = vec![kind];
+    payload.extend_from_slice(&data);
+    Some(payload)
+}
+
+fn bound(value: &Value) -> Option<rmpv::Value> {
+    match value {
+        Value::Int { val, .. } => Some((*val).into()),
+        Value::Float { val, .. } => Some((*val).into()),
+        Value::Nothing { .. } => Some(rmpv::Value::Nil),
+        _ => None,
+    }
+}
+
+/// Restore the nu value of a nu ext payload.
+pub fn decode(data: &[u8]) -> Result<Value, LabeledError> {
+    let span = Span::unknown();
+    let Some((&kind, data)) = data.split_first() else {
+        return Err(invalid_nu_ext("missing kind".into()));
+    };
+
+    Ok(match kind {
+        b'd' => Value::duration(i64::from_be_bytes(int_bytes(data)?), span),
+        b'f' => Value::filesize(i64::from_be_bytes(int_bytes(data)?), span),
+        b'c' => {
+            let members = array(data)?
+                .into_iter()
+                .map(|member| match member {
+                    rmpv::Value::Array(member) => match member.as_slice() {
+                        [rmpv::Value::String(val), rmpv::Value::Boolean(optional)] => {
+                            let val = val.as_str().ok_or_else(|| {
+                                invalid_nu_ext("cell path member is not valid UTF-8".into())
+                            })?;
+                            Ok(PathMember::String {
+                                val: val.into(),
+                                span,
+                                optional: *optional,
+                            })
+                        }
+                        [rmpv::Value::Integer(val), rmpv::Value::Boolean(optional)] => {
+                            Ok(PathMember::Int {
+                                val: val.as_u64().ok_or_else(|| {
+                                    invalid_nu_ext(format!("invalid cell path index {val}"))
+                                })? as usize,
+                                span,
+                                optional: *optional,
+                            })
+                        }
+                        _ => Err(invalid_nu_ext(format!(
+                            "invalid cell path member {member:?}"
+                        ))),
+                    },
+                    member => Err(invalid_nu_ext(format!("invalid cell path member {member}"))),
+                })
+                .collect::<Result<_, _>>()?;
+            Value::cell_path(CellPath { members }, span)
+        }
+        b'r' => match array(data)?.as_slice() {
+            [from, incr, to, rmpv::Value::Boolean(inclusive)] => {
+                let range = Range {
+                    from: to_bound(from)?,
+                    incr: to_bound(incr)?,
+                    to: to_bound(to)?,
+                    inclusion: if *inclusive {
+                        RangeInclusion::Inclusive
+                    } else {
+                        RangeInclusion::RightExclusive
+                    },
+                };
+                Value::range(range, span)
+            }
+            range => return Err(invalid_nu_ext(format!("invalid range {range:?}"))),
+        },
+        kind => {
+            return Err(invalid_nu_ext(format!(
+                "unknown kind {:?}",
+                char::from(kind)
+            )))
+        }
+    })
+}
+
+fn int_bytes(data: &[u8]) -> Result<[u8; 8], LabeledError> {
+    <[u8; 8]>::try_from(data)
+        .map_err(|_| invalid_nu_ext(format!("expected 8 bytes, got {}", data.len())))
+}
+
+fn array(data: &[u8]) -> Result<Vec<rmpv::Value>, LabeledError> {
+    let mut reader = data;
+    match rmpv::decode::read_value(&mut reader) {
+        Ok(rmpv::Value::Array(vals)) if reader.is_empty() => Ok(vals),
+        Ok(_) => Err(invalid_nu_ext("expected a single msgpack array".into())),
+        Err(e) => Err(invalid_nu_ext(e.to_string())),
+    }
+}
+
+fn to_bound(value: &rmpv::Value) -> Result<Value, LabeledError> {
+    let span = Span::unknown();
+    match value {
+        rmpv::Value::Nil => Ok(Value::nothing(span)),
+        rmpv::Value::Integer(i) => i
+            .as_i64()
+            .map(|i| Value::int(i, span))
+            .ok_or_else(|| invalid_nu_ext(format!("range bound {i} is too big"))),
+        rmpv::Value::F64(f) => Ok(Value::float(*f, span)),
+        value => Err(invalid_nu_ext(format!("invalid range bound {value}"))),
+    }
+}
+
+fn invalid_nu_ext(msg: String) -> LabeledError {
+    LabeledError {
+        label: "Invalid nu ext".into(),
+        msg: format!("Failed to decode nu ext value: {msg}"),
+        span: None,
+    }
+}
@@ -0,0 +1,39 @@
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::Value;
+
+use crate::into;
+
+/// Pack a list of nu values into a single msgpack array, e.g. the params of an rpc request.
+///
+/// The values are taken from the rest arguments of the call, or from the piped input if no
+/// arguments were given.
+pub fn pack_args(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    input: &Value,
+) -> Result<Value, LabeledError> {
+    let args: Vec<Value> = call.rest(0)?;
+
+    let params = match input {
+        Value::Nothing { .. } => args,
+        Value::List { vals, .. } if args.is_empty() => vals.clone(),
+        Value::List { .. } => {
+            return Err(LabeledError {
+                label: "Ambiguous params".into(),
+                msg: "Pass the params either as arguments or as a piped list, not both".into(),
+                span: Some(call.head),
+            })
+        }
+        _ => {
+            return Err(LabeledError {
+                label: "Invalid input".into(),
+                msg: format!("Expected a list of params, got {}", input.get_type()),
+                span: Some(input.span()),
+            })
+        }
+    };
+
+    let opts = into::EncodeOptions::from_call(call, config)?;
+    let params = into::to_msgpack(&Value::list(params, call.head), &opts)?;
+    Ok(Value::binary(params, call.head))
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+
+use nu_protocol::ast::PathMember;
+
+/// A path to a value inside a document, used to point out values in errors.
+#[derive(Clone, Debug, Default)]
+pub struct Path(Vec<PathSegment>);
+
+#[derive(Clone, Debug)]
+pub enum PathSegment {
+    /// Index into a list.
+    Index(usize),
+
+    /// Key of a record.
+    Key(String),
+}
+
+impl Path {
+    pub fn push(&mut self, segment: PathSegment) {
+        self.0.push(segment);
+    }
+
+    pub fn pop(&mut self) {
+        self.0.pop();
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if the path points at the value at a cell path. List indices the cell path skips
+    /// match any element, like `get` does for table columns: `users.name` matches `users.3.name`.
+    /// Indices after the end of the cell path don't, so `users` doesn't match `users.3`.
+    pub fn matches(&self, members: &[PathMember]) -> bool {
+        matches(&self.0, members)
+    }
+
+    /// Check if the path points at the value at a cell path or, if that's a list, at one of its
+    /// elements, at any depth: `tags` matches `tags` and `tags.2`.
+    pub fn matches_or_element_of(&self, members: &[PathMember]) -> bool {
+        let mut segments = self.0.as_slice();
+        loop {
+            if matches(segments, members) {
+                return true;
+            }
+            match segments {
+                [rest @ .., PathSegment::Index(_)] => segments = rest,
+                _ => return false,
+            }
+        }
+    }
+
+    /// If the path points at a table cell, i.e. ends with a list index followed by a record
+    /// key, return the row index and column name.
+    pub fn table_cell(&self) -> Option<(usize, &str)> {
+        match self.0.as_slice() {
+            [.., PathSegment::Index(row), PathSegment::Key(column)] => Some((*row, column)),
+            _ => None,
+        }
+    }
+}
+
+fn matches(segments: &[PathSegment], members: &[PathMember]) -> bool {
+    match (segments, members) {
+        ([], []) => true,
+        ([PathSegment::Index(i), segments @ ..], [PathMember::Int { val, .. }, rest @ ..])
+            if i == val =>
+        {
+            matches(segments, rest)
+        }
+        ([PathSegment::Key(k), segments @ ..], [PathMember::String { val, .. }, rest @ ..])
+            if k == val =>
+        {
+            matches(segments, rest)
+        }
+        // an index that doesn't match is only skipped if the cell path has more members, and
+        // doesn't have an index itself here
+        ([PathSegment::Index(_), segments @ ..], [member, ..])
+            if !matches!(member, PathMember::Int { .. }) =>
+        {
+            matches(segments, members)
+        }
+        _ => false,
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "$");
+        }
+
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            match segment {
+                PathSegment::Index(index) => write!(f, "{index}")?,
+                PathSegment::Key(key) => write!(f, "{key}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    fn members(members: &[&str]) -> Vec<PathMember> {
+        let span = Span::unknown();
+        members
+            .iter()
+            .map(|m| match m.parse() {
+                Ok(i) => PathMember::int(i, false, span),
+                Err(_) => PathMember::string(m.to_string(), false, span),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches() {
+        use PathSegment::{Index, Key};
+
+        let users_name = Path(vec![Key("users".into()), Index(3), Key("name".into())]);
+        assert!(users_name.matches(&members(&["users", "name"])));
+        assert!(users_name.matches(&members(&["users", "3", "name"])));
+        assert!(!users_name.matches(&members(&["users", "2", "name"])));
+
+        // an index of the cell path doesn't match other indices further down
+        assert!(Path(vec![Index(1)]).matches(&members(&["1"])));
+        assert!(!Path(vec![Index(0), Index(1)]).matches(&members(&["1"])));
+        assert!(!Path(vec![Index(2), Index(1)]).matches(&members(&["1"])));
+
+        // nor indices after the end of the cell path
+        let a = members(&["a"]);
+        assert!(!Path(vec![Key("a".into()), Index(0)]).matches(&a));
+        assert!(Path(vec![Key("a".into()), Index(0)]).matches_or_element_of(&a));
+        assert!(Path(vec![Key("a".into()), Index(0), Index(1)]).matches_or_element_of(&a));
+        assert!(!Path(vec![Key("a".into()), Key("b".into())]).matches_or_element_of(&a));
+    }
+}
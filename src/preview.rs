@@ -0,0 +1,92 @@
+//! Recognizing well-known formats of binary payloads, to annotate bin values in `msgpack inspect`
+//! and `msgpack tree`.
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const JPEG_MAGIC: &[u8] = &[0xff, 0xd8, 0xff];
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Describe the format of a binary payload and its dimensions, e.g. `PNG image, 640×480` or
+/// `npy array, <f4, shape (3, 224, 224)`, or `None` if it isn't a known format.
+pub fn describe(data: &[u8]) -> Option<String> {
+    if data.starts_with(PNG_MAGIC) {
+        Some(png(data))
+    } else if data.starts_with(JPEG_MAGIC) {
+        Some(jpeg(data))
+    } else if data.starts_with(NPY_MAGIC) {
+        npy(data)
+    } else {
+        None
+    }
+}
+
+/// The size of a PNG is in its first chunk, which must be IHDR.
+fn png(data: &[u8]) -> String {
+    match (data.get(12..16), data.get(16..20), data.get(20..24)) {
+        (Some(b"IHDR"), Some(width), Some(height)) => format!(
+            "PNG image, {}×{}",
+            u32::from_be_bytes(width.try_into().expect("4 bytes")),
+            u32::from_be_bytes(height.try_into().expect("4 bytes"))
+        ),
+        _ => "PNG image".into(),
+    }
+}
+
+/// The size of a JPEG is in its start of frame segment, which follows the tables.
+fn jpeg(data: &[u8]) -> String {
+    let mut offset = 2;
+    while let (Some(0xff), Some(&marker)) = (data.get(offset), data.get(offset + 1)) {
+        let Some(len) = data.get(offset + 2..offset + 4) else {
+            break;
+        };
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        // SOF0 to SOF15, except DHT, JPG and DAC, which share the range
+        if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            if let Some(size) = data.get(offset + 5..offset + 9) {
+                let height = u16::from_be_bytes([size[0], size[1]]);
+                let width = u16::from_be_bytes([size[2], size[3]]);
+                return format!("JPEG image, {width}×{height}");
+            }
+            break;
+        }
+        // the scan data after SOS isn't made of segments
+        if marker == 0xda {
+            break;
+        }
+        offset += 2 + len;
+    }
+    "JPEG image".into()
+}
+
+/// An npy header is a Python dict literal like
+/// `{'descr': '<f4', 'fortran_order': False, 'shape': (3, 224, 224), }`.
+fn npy(data: &[u8]) -> Option<String> {
+    let major = *data.get(6)?;
+    let (header_len, start) = match major {
+        1 => (
+            u16::from_le_bytes(data.get(8..10)?.try_into().ok()?) as usize,
+            10,
+        ),
+        _ => (
+            u32::from_le_bytes(data.get(8..12)?.try_into().ok()?) as usize,
+            12,
+        ),
+    };
+    let header = std::str::from_utf8(data.get(start..start + header_len)?).ok()?;
+
+    let descr = header
+        .split_once("'descr':")
+        .and_then(|(_, rest)| rest.trim_start().strip_prefix('\''))
+        .and_then(|rest| rest.split_once('\''))
+        .map(|(descr, _)| descr);
+    let shape = header
+        .split_once("'shape':")
+        .and_then(|(_, rest)| rest.split_once(')'))
+        .map(|(shape, _)| format!("{})", shape.trim()));
+
+    Some(match (descr, shape) {
+        (Some(descr), Some(shape)) => format!("npy array, {descr}, shape {shape}"),
+        _ => "npy array".into(),
+    })
+}
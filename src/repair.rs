@@ -0,0 +1,161 @@
+//! Salvaging the complete values of damaged msgpack streams.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Spanned, Value};
+
+use crate::fs;
+use crate::wire::{Reader, WireError, WireErrorKind};
+
+/// Scan a stream of concatenated msgpack values, or of length-prefixed frames with `--framed`,
+/// for the complete values before the first damaged one.
+///
+/// Reports how many values and bytes are intact and where and why the damage begins. With
+/// `--out`, the intact prefix is written to a file.
+pub fn repair(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+    let framed = call.has_flag("framed")?;
+
+    let (values, valid, damage) = match framed {
+        true => scan_frames(data),
+        false => scan(data),
+    };
+
+    let mut record = Record::new();
+    record.push("values", Value::int(values as i64, span));
+    record.push("valid_bytes", Value::filesize(valid as i64, span));
+    record.push(
+        "damaged_bytes",
+        Value::filesize((data.len() - valid) as i64, span),
+    );
+    record.push(
+        "damage",
+        match damage {
+            Some(damage) => damage.into_value(valid, span),
+            None => Value::nothing(span),
+        },
+    );
+
+    if let Some(out) = call.get_flag::<Spanned<String>>("out")? {
+        let resolved = fs::resolve(&out.item);
+        std::fs::write(&resolved, &data[..valid]).map_err(|e| fs::io_error("write", &out, e))?;
+        record.push("path", Value::string(resolved.to_string_lossy(), span));
+    }
+
+    Ok(Value::record(record, span))
+}
+
+/// Why the first damaged value couldn't be read.
+pub struct Damage {
+    /// Offset at which reading failed, which is in or after the damaged value.
+    pub offset: usize,
+
+    pub kind: &'static str,
+
+    pub reason: String,
+}
+
+impl Damage {
+    fn from_wire(e: WireError) -> Self {
+        let kind = match e.kind {
+            WireErrorKind::Truncated { .. } => "truncated",
+            WireErrorKind::ReservedMarker => "invalid",
+        };
+        Damage {
+            offset: e.offset,
+            kind,
+            reason: e.to_string(),
+        }
+    }
+
+    /// A `{start, offset, kind, reason}` record, where `start` is where the damaged value begins.
+    fn into_value(self, start: usize, span: Span) -> Value {
+        let mut record = Record::new();
+        record.push("start", Value::int(start as i64, span));
+        record.push("offset", Value::int(self.offset as i64, span));
+        record.push("kind", Value::string(self.kind, span));
+        record.push("reason", Value::string(self.reason, span));
+        Value::record(record, span)
+    }
+}
+
+/// Skip over concatenated values until one can't be read. Returns the number of complete values,
+/// the number of bytes they take up, and the damage, if any.
+pub fn scan(data: &[u8]) -> (usize, usize, Option<Damage>) {
+    let mut reader = Reader::new(data);
+    let mut values = 0;
+    while !reader.is_empty() {
+        let start = reader.pos();
+        if let Err(e) = reader.skip_value() {
+            return (values, start, Some(Damage::from_wire(e)));
+        }
+        values += 1;
+    }
+    (values, reader.pos(), None)
+}
+
+/// Like [scan], for frames each prefixed with their length as a big-endian u32. A frame is only
+/// complete if it holds exactly one value.
+pub fn scan_frames(data: &[u8]) -> (usize, usize, Option<Damage>) {
+    let mut offset = 0;
+    let mut values = 0;
+    while offset < data.len() {
+        let damage = |offset: usize, kind: &'static str, reason: String| Damage {
+            offset,
+            kind,
+            reason,
+        };
+        let Some(prefix) = data.get(offset..offset + 4) else {
+            let reason = format!(
+                "frame at byte {offset} has only {} of the 4 bytes of its length",
+                data.len() - offset
+            );
+            return (
+                values,
+                offset,
+                Some(damage(data.len(), "truncated", reason)),
+            );
+        };
+        let len = u32::from_be_bytes(prefix.try_into().expect("4 bytes")) as usize;
+        let start = offset + 4;
+        let Some(frame) = data.get(start..start + len) else {
+            let reason = format!(
+                "frame at byte {offset} claims {len} bytes, but only {} bytes remain",
+                data.len() - start
+            );
+            return (
+                values,
+                offset,
+                Some(damage(data.len(), "truncated", reason)),
+            );
+        };
+
+        let mut reader = Reader::new(frame);
+        match reader.skip_value() {
+            Err(mut e) => {
+                // offsets within the frame are relative to its start
+                e.offset += start;
+                let mut damage = Damage::from_wire(e);
+                damage.reason = format!("in the frame at byte {offset}: {}", damage.reason);
+                // a value that ends early within its frame means the frame is corrupt
+                damage.kind = "invalid";
+                return (values, offset, Some(damage));
+            }
+            Ok(_) if !reader.is_empty() => {
+                let reason = format!(
+                    "frame at byte {offset} has {} more bytes after the end of its value",
+                    reader.remaining()
+                );
+                return (
+                    values,
+                    offset,
+                    Some(damage(start + reader.pos(), "invalid", reason)),
+                );
+            }
+            Ok(_) => {}
+        }
+        values += 1;
+        offset = start + len;
+    }
+    (values, offset, None)
+}
@@ -0,0 +1,435 @@
+//! A msgpack-rpc client, see <https://github.com/msgpack-rpc/msgpack-rpc/blob/master/spec.md>.
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Span, Spanned, Value};
+use rmp::encode;
+
+use crate::from::{self, DecodeOptions};
+use crate::into::{self, EncodeOptions};
+use crate::{flags, fs};
+
+const REQUEST: u64 = 0;
+const RESPONSE: u64 = 1;
+const NOTIFICATION: u64 = 2;
+
+/// Only one request is sent per connection, so the id doesn't need to change.
+const MSGID: u64 = 0;
+
+/// How long `msgpack rpc exec` waits for a process to exit after a notification when there's no
+/// `--timeout`.
+const NOTIFY_GRACE: Duration = Duration::from_secs(1);
+
+/// The read and write halves of a connection.
+pub type Connection = (Box<dyn Read>, Box<dyn Write>);
+
+/// A request or notification, with the options to encode its params and decode the response.
+pub struct Rpc {
+    pub method: Spanned<String>,
+    pub params: Vec<Value>,
+    pub notify: bool,
+    pub timeout: Option<Duration>,
+    pub encode: EncodeOptions,
+    pub decode: DecodeOptions,
+    pub head: Span,
+}
+
+impl Rpc {
+    /// Read the method from the positional argument at `method`, and the params from the rest
+    /// arguments after it.
+    pub fn from_call(
+        call: &EvaluatedCall,
+        config: &Option<Value>,
+        method: usize,
+    ) -> Result<Self, LabeledError> {
+        let timeout = match call.get_flag::<Spanned<i64>>("timeout")? {
+            Some(Spanned { item, span }) if item <= 0 => {
+                return Err(LabeledError {
+                    label: "Invalid timeout".into(),
+                    msg: "The timeout must be positive".into(),
+                    span: Some(span),
+                })
+            }
+            Some(timeout) => Some(Duration::from_nanos(timeout.item as u64)),
+            None => None,
+        };
+
+        // peers speak plain msgpack-rpc, so the messages can't be deduplicated or framed like
+        // the config may ask for other commands
+        let encode = EncodeOptions {
+            dedup: None,
+            framed: false,
+            ..EncodeOptions::from_call(call, config)?
+        };
+        let decode = DecodeOptions {
+            dedup: flags::ext_id_flag(call, "dedup")?,
+            ..DecodeOptions::from_call(call, config)?
+        };
+
+        Ok(Rpc {
+            method: call.req(method)?,
+            params: call.rest(method + 1)?,
+            notify: call.has_flag("notify")?,
+            timeout,
+            encode,
+            decode,
+            head: call.head,
+        })
+    }
+
+    /// Send the request, and wait for its response unless it's a notification. The writer is
+    /// dropped once the request is sent.
+    ///
+    /// Notifications and requests from the other side that arrive in the meantime are skipped.
+    pub fn send(&self, reader: impl Read, mut writer: impl Write) -> Result<Value, LabeledError> {
+        let mut message = vec![];
+        if self.notify {
+            encode::write_array_len(&mut message, 3).expect("writing to vec can't fail");
+            encode::write_uint(&mut message, NOTIFICATION).expect("writing to vec can't fail");
+        } else {
+            encode::write_array_len(&mut message, 4).expect("writing to vec can't fail");
+            encode::write_uint(&mut message, REQUEST).expect("writing to vec can't fail");
+            encode::write_uint(&mut message, MSGID).expect("writing to vec can't fail");
+        }
+        encode::write_str(&mut message, &self.method.item).expect("writing to vec can't fail");
+        let params = Value::list(self.params.clone(), self.head);
+        message.extend(into::to_msgpack(&params, &self.encode)?);
+
+        writer
+            .write_all(&message)
+            .and_then(|()| writer.flush())
+            .map_err(|e| self.io_error("send the request", e))?;
+        // closes the stdin of a child process, while a socket stays open through the reader
+        drop(writer);
+        if self.notify {
+            return Ok(Value::nothing(self.head));
+        }
+
+        let mut reader = BufReader::new(reader);
+        loop {
+            let message = rmpv::decode::read_value(&mut reader).map_err(|e| self.read_error(e))?;
+            let rmpv::Value::Array(message) = message else {
+                return Err(self.invalid("a message isn't an array"));
+            };
+            match message.first().and_then(|kind| kind.as_u64()) {
+                Some(RESPONSE) => {}
+                Some(REQUEST | NOTIFICATION) => continue,
+                _ => return Err(self.invalid("a message has an unknown type")),
+            }
+
+            let [_, msgid, error, result] = <[rmpv::Value; 4]>::try_from(message)
+                .map_err(|_| self.invalid("a response doesn't have 4 elements"))?;
+            if msgid.as_u64() != Some(MSGID) {
+                continue;
+            }
+            if !error.is_nil() {
+                return Err(LabeledError {
+                    label: "RPC error".into(),
+                    msg: format!("{} failed: {}", self.method.item, error_message(&error)),
+                    span: Some(self.method.span),
+                });
+            }
+            return from::decode(&into::encode(&result), &self.decode);
+        }
+    }
+
+    fn read_error(&self, e: rmpv::decode::Error) -> LabeledError {
+        match e {
+            rmpv::decode::Error::InvalidMarkerRead(e) | rmpv::decode::Error::InvalidDataRead(e) => {
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => LabeledError {
+                        label: "RPC timed out".into(),
+                        msg: format!("No response to {} within the timeout", self.method.item),
+                        span: Some(self.head),
+                    },
+                    io::ErrorKind::UnexpectedEof => LabeledError {
+                        label: "RPC failed".into(),
+                        msg: "The connection was closed before the response arrived".into(),
+                        span: Some(self.head),
+                    },
+                    _ => self.io_error("read the response", e),
+                }
+            }
+            e => self.invalid(&e.to_string()),
+        }
+    }
+
+    fn io_error(&self, action: &str, e: io::Error) -> LabeledError {
+        LabeledError {
+            label: "RPC failed".into(),
+            msg: format!("Failed to {action}: {e}"),
+            span: Some(self.head),
+        }
+    }
+
+    fn invalid(&self, msg: &str) -> LabeledError {
+        LabeledError {
+            label: "Invalid msgpack-rpc response".into(),
+            msg: format!("The response to {} is invalid: {msg}", self.method.item),
+            span: Some(self.head),
+        }
+    }
+}
+
+/// Call a method of the msgpack-rpc server at the address given as first argument.
+pub fn call(call: &EvaluatedCall, config: &Option<Value>) -> Result<Value, LabeledError> {
+    let address: Spanned<String> = call.req(0)?;
+    let rpc = Rpc::from_call(call, config, 1)?;
+    let (reader, writer) = connect(&address, rpc.timeout)?;
+    rpc.send(reader, writer)
+}
+
+/// Send a request or notification to Neovim, at the address given with `--socket` or else the
+/// `$NVIM` address that Neovim sets for its child processes.
+pub fn nvim(
+    call: &EvaluatedCall,
+    config: &Option<Value>,
+    notify: bool,
+) -> Result<Value, LabeledError> {
+    let mut rpc = Rpc::from_call(call, config, 0)?;
+    rpc.notify = notify;
+
+    let address = match call.get_flag::<Spanned<String>>("socket")? {
+        Some(socket) => socket,
+        None => match std::env::var("NVIM") {
+            Ok(address) if !address.is_empty() => Spanned {
+                item: address,
+                span: call.head,
+            },
+            _ => {
+                return Err(LabeledError {
+                    label: "No Neovim to talk to".into(),
+                    msg: "$NVIM isn't set, so pass the address of Neovim with --socket".into(),
+                    span: Some(call.head),
+                })
+            }
+        },
+    };
+
+    let (reader, writer) = connect(&address, rpc.timeout)?;
+    rpc.send(reader, writer)
+}
+
+/// Call a method of a msgpack-rpc server run as a child process, talking to it over its stdin
+/// and stdout.
+///
+/// Stdin is closed once the request is sent. After a response, the process is killed, and after
+/// a notification it's given until the timeout, or a second without one, to exit on its own
+/// before it's killed. Only not exiting within an explicit timeout is an error.
+pub fn exec(call: &EvaluatedCall, config: &Option<Value>) -> Result<Value, LabeledError> {
+    let command: Value = call.req(0)?;
+    let rpc = Rpc::from_call(call, config, 1)?;
+
+    let argv = match &command {
+        Value::List { vals, .. } => vals.iter().map(|v| v.as_string()).collect(),
+        command => command.as_string().map(|program| vec![program]),
+    }?;
+    let Some((program, args)) = argv.split_first() else {
+        return Err(LabeledError {
+            label: "Invalid command".into(),
+            msg: "The command is empty".into(),
+            span: Some(command.span()),
+        });
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .current_dir(fs::resolve("."))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| LabeledError {
+            label: "Failed to start process".into(),
+            msg: format!("{program}: {e}"),
+            span: Some(command.span()),
+        })?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    // Pipes can't time out, so talk to the process on another thread and kill it if that takes
+    // too long, which ends the response with EOF.
+    let deadline = rpc.timeout.map(|timeout| Instant::now() + timeout);
+    let (method, notify) = (rpc.method.item.clone(), rpc.notify);
+    let worker = thread::spawn(move || rpc.send(stdout, stdin));
+    let mut in_time = wait_until(deadline, || worker.is_finished());
+    if in_time && notify {
+        let exit_deadline = deadline.unwrap_or_else(|| Instant::now() + NOTIFY_GRACE);
+        let exited = wait_until(Some(exit_deadline), || {
+            !matches!(child.try_wait(), Ok(None))
+        });
+        in_time = exited || deadline.is_none();
+    }
+    let status = child.try_wait().ok().flatten();
+    if status.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    let response = worker.join().expect("rpc thread panicked");
+
+    match response {
+        _ if !in_time => Err(LabeledError {
+            label: "RPC timed out".into(),
+            msg: format!("No response to {method} within the timeout"),
+            span: Some(call.head),
+        }),
+        Err(mut e) => {
+            if let Some(status) = status.filter(|status| !status.success()) {
+                e.msg = format!("{} ({program} exited with {status})", e.msg);
+            }
+            Err(e)
+        }
+        response => response,
+    }
+}
+
+/// Poll `done` until it returns true, or the deadline passes, in which case return false.
+fn wait_until(deadline: Option<Instant>, mut done: impl FnMut() -> bool) -> bool {
+    while !done() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// Connect to a `host:port` or `tcp://host:port` TCP address, or a `unix://path` or plain path
+/// Unix socket, and return the read and write halves of the connection.
+pub fn connect(
+    address: &Spanned<String>,
+    timeout: Option<Duration>,
+) -> Result<Connection, LabeledError> {
+    let connect_error = |e: io::Error| LabeledError {
+        label: "Failed to connect".into(),
+        msg: format!("{}: {e}", address.item),
+        span: Some(address.span),
+    };
+
+    let tcp = address.item.strip_prefix("tcp://");
+    if let Some(host) = tcp.or_else(|| is_host_port(&address.item).then_some(&address.item)) {
+        let stream = match timeout {
+            Some(timeout) => {
+                let addr = host
+                    .to_socket_addrs()
+                    .map_err(connect_error)?
+                    .next()
+                    .ok_or_else(|| connect_error(io::ErrorKind::NotFound.into()))?;
+                TcpStream::connect_timeout(&addr, timeout)
+            }
+            None => TcpStream::connect(host),
+        }
+        .map_err(connect_error)?;
+        stream.set_read_timeout(timeout).map_err(connect_error)?;
+        let reader = stream.try_clone().map_err(connect_error)?;
+        return Ok((Box::new(reader), Box::new(stream)));
+    }
+
+    let path = address
+        .item
+        .strip_prefix("unix://")
+        .unwrap_or(&address.item);
+    connect_unix(&fs::resolve(path), timeout).map_err(connect_error)
+}
+
+/// The message of an error in a response.
+fn error_message(error: &rmpv::Value) -> String {
+    match error {
+        rmpv::Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        // Neovim sends `[type, message]`.
+        rmpv::Value::Array(error)
+            if matches!(
+                error.as_slice(),
+                [rmpv::Value::Integer(_), rmpv::Value::String(_)]
+            ) =>
+        {
+            error_message(&error[1])
+        }
+        error => error.to_string(),
+    }
+}
+
+/// Check if an address looks like `host:port` rather than a path.
+fn is_host_port(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => !host.contains(['/', '\\']) && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &std::path::Path, timeout: Option<Duration>) -> io::Result<Connection> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(timeout)?;
+    let reader = stream.try_clone()?;
+    Ok((Box::new(reader), Box::new(stream)))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &std::path::Path, _timeout: Option<Duration>) -> io::Result<Connection> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets aren't supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::Record;
+
+    use super::*;
+
+    #[test]
+    fn config_defaults_dont_apply_to_messages() {
+        let span = Span::unknown();
+        let call = EvaluatedCall {
+            head: span,
+            positional: vec![Value::string("ping", span), Value::string("x", span)],
+            named: vec![(
+                Spanned {
+                    item: "notify".into(),
+                    span,
+                },
+                None,
+            )],
+        };
+        let mut config = Record::new();
+        config.push("dedup", Value::int(7, span));
+        let config = Some(Value::record(config, span));
+
+        let rpc = Rpc::from_call(&call, &config, 0).unwrap();
+        let mut message = vec![];
+        rpc.send(io::empty(), &mut message).unwrap();
+        // [2, "ping", ["x"]]
+        assert_eq!(message, b"\x93\x02\xa4ping\x91\xa1x");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_notify_without_timeout() {
+        let span = Span::unknown();
+        let command = ["sleep", "30"].map(|arg| Value::string(arg, span));
+        let call = EvaluatedCall {
+            head: span,
+            positional: vec![
+                Value::list(command.to_vec(), span),
+                Value::string("ping", span),
+            ],
+            named: vec![(
+                Spanned {
+                    item: "notify".into(),
+                    span,
+                },
+                None,
+            )],
+        };
+
+        let start = Instant::now();
+        assert_eq!(exec(&call, &None).unwrap(), Value::nothing(span));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,306 @@
+//! Schemas describing the expected shape of documents, checked with `--schema`.
+//!
+//! A schema is a record mapping field names to the type of their values: a type name like
+//! `int` or `date`, a nested record, or a list with the schema of its elements, e.g.
+//! `{name: string, tags: [string], address: {city: string, zip: int?}}`.
+//! Type names ending in `?` also allow nothing, or a missing field.
+
+use chrono::{TimeZone, Utc};
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Span, Value};
+
+use crate::path::{Path, PathSegment};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Any,
+    Nothing,
+    Bool,
+    Int,
+    Float,
+    Number,
+    String,
+    Binary,
+    Date,
+    Duration,
+    Filesize,
+    List,
+    Record,
+}
+
+const TYPES: &[(&str, Type)] = &[
+    ("any", Type::Any),
+    ("nothing", Type::Nothing),
+    ("bool", Type::Bool),
+    ("int", Type::Int),
+    ("float", Type::Float),
+    ("number", Type::Number),
+    ("string", Type::String),
+    ("binary", Type::Binary),
+    ("date", Type::Date),
+    ("duration", Type::Duration),
+    ("filesize", Type::Filesize),
+    ("list", Type::List),
+    ("record", Type::Record),
+];
+
+impl Type {
+    fn name(self) -> &'static str {
+        TYPES
+            .iter()
+            .find(|(_, ty)| *ty == self)
+            .map(|(name, _)| *name)
+            .expect("every type is in TYPES")
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Type::Any, _)
+                | (Type::Nothing, Value::Nothing { .. })
+                | (Type::Bool, Value::Bool { .. })
+                | (Type::Int, Value::Int { .. })
+                | (Type::Float, Value::Float { .. })
+                | (Type::Number, Value::Int { .. } | Value::Float { .. })
+                | (
+                    Type::String,
+                    Value::String { .. } | Value::QuotedString { .. }
+                )
+                | (Type::Binary, Value::Binary { .. })
+                | (Type::Date, Value::Date { .. })
+                | (Type::Duration, Value::Duration { .. })
+                | (Type::Filesize, Value::Filesize { .. })
+                | (Type::List, Value::List { .. })
+                | (Type::Record, Value::Record { .. })
+        )
+    }
+
+    /// Convert a value that msgpack can't represent as this type directly, e.g. a timestamp
+    /// integer to a date.
+    fn coerce(self, value: &Value) -> Option<Value> {
+        let span = value.span();
+        Some(match (self, value) {
+            (Type::Float, Value::Int { val, .. }) => Value::float(*val as f64, span),
+            // Nanoseconds since the epoch, like `into datetime` does.
+            (Type::Date, Value::Int { val, .. }) => {
+                Value::date(Utc.timestamp_nanos(*val).fixed_offset(), span)
+            }
+            (Type::Date, Value::String { val, .. }) => {
+                Value::date(chrono::DateTime::parse_from_rfc3339(val).ok()?, span)
+            }
+            (Type::Duration, Value::Int { val, .. }) => Value::duration(*val, span),
+            (Type::Filesize, Value::Int { val, .. }) => Value::filesize(*val, span),
+            (Type::Filesize, Value::String { val, .. }) => {
+                Value::filesize(parse_filesize(val)?, span)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Parse a filesize like `10MB`, `1.5 GiB` or `512`.
+fn parse_filesize(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let unit: i64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000_i64.pow(2),
+        "gb" => 1000_i64.pow(3),
+        "tb" => 1000_i64.pow(4),
+        "pb" => 1000_i64.pow(5),
+        "eb" => 1000_i64.pow(6),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        "pib" => 1 << 50,
+        "eib" => 1 << 60,
+        _ => return None,
+    };
+    Some((number * unit as f64) as i64)
+}
+
+pub enum Schema {
+    Type { ty: Type, nullable: bool },
+    List(Box<Schema>),
+    Record(Vec<(String, Schema)>),
+}
+
+impl Schema {
+    /// Read the schema given with `--schema`.
+    pub fn from_flag(call: &EvaluatedCall) -> Result<Option<Schema>, LabeledError> {
+        call.get_flag::<Value>("schema")?
+            .map(|schema| Schema::parse(&schema))
+            .transpose()
+    }
+
+    fn parse(schema: &Value) -> Result<Self, LabeledError> {
+        let invalid = |msg: String| LabeledError {
+            label: "Invalid schema".into(),
+            msg,
+            span: Some(schema.span()),
+        };
+
+        match schema {
+            Value::String { val, .. } => {
+                let (name, nullable) = match val.strip_suffix('?') {
+                    Some(name) => (name, true),
+                    None => (val.as_str(), false),
+                };
+                let ty = TYPES
+                    .iter()
+                    .find(|(ty, _)| *ty == name)
+                    .map(|(_, ty)| *ty)
+                    .ok_or_else(|| {
+                        let names: Vec<_> = TYPES.iter().map(|(name, _)| *name).collect();
+                        invalid(format!(
+                            "Unknown type {name:?}, expected one of: {}",
+                            names.join(", ")
+                        ))
+                    })?;
+                Ok(Schema::Type { ty, nullable })
+            }
+            Value::List { vals, .. } => match vals.as_slice() {
+                [element] => Ok(Schema::List(Box::new(Schema::parse(element)?))),
+                _ => Err(invalid(
+                    "A list in a schema must hold exactly one schema, for its elements".into(),
+                )),
+            },
+            Value::Record { val, .. } => val
+                .iter()
+                .map(|(key, field)| Ok((key.clone(), Schema::parse(field)?)))
+                .collect::<Result<_, _>>()
+                .map(Schema::Record),
+            schema => Err(invalid(format!(
+                "Expected a type name, record or list in the schema, found {}",
+                schema.get_type()
+            ))),
+        }
+    }
+
+    fn is_nullable(&self) -> bool {
+        matches!(self, Schema::Type { nullable: true, .. })
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Schema::Type { ty, .. } => ty.name(),
+            Schema::List(_) => "list",
+            Schema::Record(_) => "record",
+        }
+    }
+
+    /// Check a decoded value against the schema, converting values where that makes sense,
+    /// e.g. integer timestamps of `date` fields.
+    pub fn conform(&self, mut value: Value) -> Result<Value, LabeledError> {
+        let mut coercions = vec![];
+        self.check(&value, &mut Path::default(), Some(&mut coercions))?;
+        for (path, coerced) in coercions {
+            *value_at(&mut value, path.segments()) = coerced;
+        }
+        Ok(value)
+    }
+
+    /// Check a value before encoding it. Values must match exactly, nothing is converted.
+    pub fn validate(&self, value: &Value) -> Result<(), LabeledError> {
+        self.check(value, &mut Path::default(), None)
+    }
+
+    /// Check `value`, collecting the conversions it needs in `coercions`. Without `coercions`,
+    /// values must match exactly.
+    fn check(
+        &self,
+        value: &Value,
+        path: &mut Path,
+        mut coercions: Option<&mut Vec<(Path, Value)>>,
+    ) -> Result<(), LabeledError> {
+        match (self, value) {
+            (schema, Value::Nothing { .. }) if schema.is_nullable() => Ok(()),
+            (Schema::Type { ty, .. }, value) if ty.matches(value) => Ok(()),
+            (Schema::Type { ty, .. }, value) => match (&mut coercions, ty.coerce(value)) {
+                (Some(coercions), Some(coerced)) => {
+                    coercions.push((path.clone(), coerced));
+                    Ok(())
+                }
+                _ => Err(violation(
+                    value,
+                    format!(
+                        "Expected {} at {path}, found {}",
+                        ty.name(),
+                        value.get_type()
+                    ),
+                )),
+            },
+            (Schema::List(element), Value::List { vals, .. }) => {
+                for (i, v) in vals.iter().enumerate() {
+                    path.push(PathSegment::Index(i));
+                    element.check(v, path, coercions.as_deref_mut())?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            (Schema::Record(fields), Value::Record { val: record, .. }) => {
+                for (key, field) in fields {
+                    match record.get(key) {
+                        Some(v) => {
+                            path.push(PathSegment::Key(key.clone()));
+                            field.check(v, path, coercions.as_deref_mut())?;
+                            path.pop();
+                        }
+                        None if field.is_nullable() => {}
+                        None => {
+                            return Err(violation(
+                                value,
+                                format!("Missing field {key:?} in the record at {path}"),
+                            ))
+                        }
+                    }
+                }
+                match record
+                    .columns()
+                    .find(|k| !fields.iter().any(|(f, _)| f == *k))
+                {
+                    Some(key) => Err(violation(
+                        value,
+                        format!("Unexpected field {key:?} in the record at {path}"),
+                    )),
+                    None => Ok(()),
+                }
+            }
+            (schema, value) => Err(violation(
+                value,
+                format!(
+                    "Expected {} at {path}, found {}",
+                    schema.describe(),
+                    value.get_type()
+                ),
+            )),
+        }
+    }
+}
+
+fn violation(value: &Value, msg: String) -> LabeledError {
+    LabeledError {
+        label: "Schema violation".into(),
+        msg,
+        span: Some(value.span()).filter(|span| *span != Span::unknown()),
+    }
+}
+
+/// The value at a path that [Schema::check] has walked, so it exists.
+fn value_at<'v>(value: &'v mut Value, segments: &[PathSegment]) -> &'v mut Value {
+    segments
+        .iter()
+        .fold(value, |value, segment| match (value, segment) {
+            (Value::List { vals, .. }, PathSegment::Index(i)) => &mut vals[*i],
+            (Value::Record { val, .. }, PathSegment::Key(key)) => {
+                val.get_mut(key).expect("checked by Schema::check")
+            }
+            _ => unreachable!("checked by Schema::check"),
+        })
+}
@@ -0,0 +1,112 @@
+//! Golden-file snapshots of canonically encoded values.
+//!
+//! Each snapshot is stored as `<dir>/<name>.msgpack`.
+
+use std::path::PathBuf;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Spanned, Value};
+
+use crate::from::DecodeOptions;
+use crate::into::{self, EncodeOptions};
+use crate::{diff, fs};
+
+/// Store the canonical encoding of every value of the input record as a snapshot.
+pub fn record(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let dir: Spanned<String> = call.req(0)?;
+    let dir_path = fs::resolve(&dir.item);
+    std::fs::create_dir_all(&dir_path).map_err(|e| fs::io_error("create", &dir, e))?;
+
+    let mut rows = vec![];
+    for (name, value) in input.as_record()? {
+        let path = snapshot_path(&dir_path, name, call)?;
+        let encoded = encode(value)?;
+        std::fs::write(&path, &encoded)
+            .map_err(|e| fs::io_error_at("write", &path.to_string_lossy(), None, e))?;
+
+        let mut row = Record::new();
+        row.push("name", Value::string(name, call.head));
+        row.push("path", Value::string(path.to_string_lossy(), call.head));
+        row.push("bytes", Value::filesize(encoded.len() as i64, call.head));
+        rows.push(Value::record(row, call.head));
+    }
+
+    Ok(Value::list(rows, call.head))
+}
+
+/// Compare the canonical encoding of every value of the input record with its snapshot.
+pub fn check(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let dir: Spanned<String> = call.req(0)?;
+    let dir_path = fs::resolve(&dir.item);
+
+    let mut rows = vec![];
+    let mut failed = vec![];
+    for (name, value) in input.as_record()? {
+        let path = snapshot_path(&dir_path, name, call)?;
+        let encoded = encode(value)?;
+
+        let (status, diffs) = match std::fs::read(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ("missing", vec![]),
+            Err(e) => return Err(fs::io_error_at("read", &path.to_string_lossy(), None, e)),
+            Ok(stored) if stored == encoded => ("ok", vec![]),
+            Ok(stored) => {
+                let stored =
+                    rmpv::decode::read_value(&mut &stored[..]).map_err(|e| LabeledError {
+                        label: "Invalid snapshot".into(),
+                        msg: format!("{}: {e}", path.to_string_lossy()),
+                        span: None,
+                    })?;
+                let current = rmpv::decode::read_value(&mut &encoded[..])
+                    .expect("we just encoded this value");
+                ("changed", diff::diff(&stored, &current))
+            }
+        };
+
+        if status != "ok" {
+            failed.push(name.as_str());
+        }
+
+        let mut row = Record::new();
+        row.push("name", Value::string(name, call.head));
+        row.push("status", Value::string(status, call.head));
+        row.push(
+            "diff",
+            diff::to_nu(diffs, &DecodeOptions::default(), call.head),
+        );
+        rows.push(Value::record(row, call.head));
+    }
+
+    if call.has_flag("fail")? && !failed.is_empty() {
+        return Err(LabeledError {
+            label: "Snapshot mismatch".into(),
+            msg: format!("Snapshots don't match: {}", failed.join(", ")),
+            span: Some(call.head),
+        });
+    }
+
+    Ok(Value::list(rows, call.head))
+}
+
+fn encode(value: &Value) -> Result<Vec<u8>, LabeledError> {
+    let opts = EncodeOptions {
+        canonical: true,
+        ..Default::default()
+    };
+    into::to_msgpack(value, &opts)
+}
+
+fn snapshot_path(
+    dir: &std::path::Path,
+    name: &str,
+    call: &EvaluatedCall,
+) -> Result<PathBuf, LabeledError> {
+    if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+        return Err(LabeledError {
+            label: "Invalid snapshot name".into(),
+            msg: format!("{name:?} can't be used as a file name"),
+            span: Some(call.head),
+        });
+    }
+
+    Ok(dir.join(format!("{name}.msgpack")))
+}
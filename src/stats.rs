@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Span, Value};
+
+use crate::wire::{Kind, Reader, Role, Walker};
+
+/// Counts of sizes in power of two buckets: bucket 0 holds size 0, bucket `i` sizes in
+/// `2^(i-1)..2^i`.
+#[derive(Default)]
+struct Histogram(Vec<i64>);
+
+impl Histogram {
+    fn add(&mut self, size: usize) {
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if self.0.len() <= bucket {
+            self.0.resize(bucket + 1, 0);
+        }
+        self.0[bucket] += 1;
+    }
+
+    /// A table of the non-empty buckets, with their bounds as `min` and `max`.
+    fn to_nu(&self, span: Span) -> Value {
+        let rows = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| {
+                let (min, max) = match bucket {
+                    0 => (0, 0),
+                    _ => (1 << (bucket - 1), (1 << bucket) - 1),
+                };
+                let mut row = Record::new();
+                row.push("min", Value::filesize(min, span));
+                row.push("max", Value::filesize(max, span));
+                row.push("count", Value::int(*count, span));
+                Value::record(row, span)
+            })
+            .collect();
+        Value::list(rows, span)
+    }
+}
+
+/// Scan a msgpack binary and report aggregate information about its contents.
+pub fn stats(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+
+    let mut values = 0;
+    let mut types: HashMap<&'static str, i64> = HashMap::new();
+    let mut key_bytes: HashMap<String, i64> = HashMap::new();
+    let mut max_depth = 0;
+    let mut exts = 0;
+    let mut strings = Histogram::default();
+    let mut binaries = Histogram::default();
+
+    let mut reader = Reader::new(data);
+    while !reader.is_empty() {
+        let mut walker = Walker::new(reader);
+
+        // top-level map entry being walked, with the offset of its key
+        let mut entry: Option<(String, usize)> = None;
+
+        for node in &mut walker {
+            let node = node.map_err(|e| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: e.to_string(),
+                span: Some(input.span()),
+            })?;
+
+            if let (1, Role::Key(_)) = (node.depth, node.role) {
+                if let Some((key, start)) = entry.take() {
+                    *key_bytes.entry(key).or_default() += (node.header.offset - start) as i64;
+                }
+                entry = Some((node.key_name(), node.header.offset));
+            }
+
+            *types.entry(node.header.type_name()).or_default() += 1;
+            max_depth = max_depth.max(node.depth);
+            match node.header.kind {
+                Kind::Str(len) => strings.add(len as usize),
+                Kind::Bin(len) => binaries.add(len as usize),
+                Kind::Ext(..) => exts += 1,
+                _ => {}
+            }
+        }
+
+        if let Some((key, start)) = entry {
+            *key_bytes.entry(key).or_default() += (walker.reader().pos() - start) as i64;
+        }
+        values += 1;
+        reader = walker.reader().clone();
+    }
+
+    let mut types: Vec<_> = types.into_iter().collect();
+    types.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    let types = types
+        .into_iter()
+        .map(|(name, count)| {
+            let mut row = Record::new();
+            row.push("type", Value::string(name, span));
+            row.push("count", Value::int(count, span));
+            Value::record(row, span)
+        })
+        .collect();
+
+    let mut keys: Vec<_> = key_bytes.into_iter().collect();
+    keys.sort_by(|(a, a_bytes), (b, b_bytes)| b_bytes.cmp(a_bytes).then(a.cmp(b)));
+    let keys = keys
+        .into_iter()
+        .map(|(key, bytes)| {
+            let mut row = Record::new();
+            row.push("key", Value::string(key, span));
+            row.push("bytes", Value::filesize(bytes, span));
+            Value::record(row, span)
+        })
+        .collect();
+
+    let mut record = Record::new();
+    record.push("values", Value::int(values, span));
+    record.push("bytes", Value::filesize(data.len() as i64, span));
+    record.push("max_depth", Value::int(max_depth as i64, span));
+    record.push("ext", Value::int(exts, span));
+    record.push("types", Value::list(types, span));
+    record.push("keys", Value::list(keys, span));
+    record.push("strings", strings.to_nu(span));
+    record.push("binaries", binaries.to_nu(span));
+    Ok(Value::record(record, span))
+}
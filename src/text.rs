@@ -0,0 +1,135 @@
+//! Text encodings of msgpack binaries, for payloads embedded in JSON, URLs and the like.
+
+use std::borrow::Cow;
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::{DecodePaddingMode, Engine};
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Span, Value};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Base64,
+    Hex,
+}
+
+impl TextEncoding {
+    fn name(self) -> &'static str {
+        match self {
+            TextEncoding::Base64 => "base64",
+            TextEncoding::Hex => "hex",
+        }
+    }
+
+    /// Guess the encoding of a text: hex if it only has an even number of hex digits, base64
+    /// otherwise. Most such texts are valid base64 too, so those are ambiguous and give `None`.
+    fn detect(text: &str) -> Option<Self> {
+        if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Some(TextEncoding::Base64)
+        } else if TextEncoding::Base64.decode(text).is_ok() {
+            None
+        } else {
+            Some(TextEncoding::Hex)
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            TextEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+            TextEncoding::Hex => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+        }
+    }
+
+    fn decode(self, text: &str) -> Result<Vec<u8>, String> {
+        let text: String = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        match self {
+            TextEncoding::Base64 => {
+                let config = GeneralPurposeConfig::new()
+                    .with_decode_padding_mode(DecodePaddingMode::Indifferent);
+                let standard = GeneralPurpose::new(&alphabet::STANDARD, config);
+                let url_safe = GeneralPurpose::new(&alphabet::URL_SAFE, config);
+                standard
+                    .decode(&text)
+                    .or_else(|_| url_safe.decode(&text))
+                    .map_err(|e| e.to_string())
+            }
+            TextEncoding::Hex => {
+                if !text.len().is_multiple_of(2) {
+                    return Err("odd number of hex digits".into());
+                }
+                (0..text.len())
+                    .step_by(2)
+                    .map(|i| {
+                        text.get(i..i + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                            .ok_or_else(|| format!("invalid hex digits at offset {i}"))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The encoding selected by the `--base64` and `--hex` switches.
+fn encoding_flag(call: &EvaluatedCall) -> Result<Option<TextEncoding>, LabeledError> {
+    match (call.has_flag("base64")?, call.has_flag("hex")?) {
+        (false, false) => Ok(None),
+        (true, false) => Ok(Some(TextEncoding::Base64)),
+        (false, true) => Ok(Some(TextEncoding::Hex)),
+        (true, true) => Err(LabeledError {
+            label: "Conflicting flags".into(),
+            msg: "--base64 and --hex can't be used together".into(),
+            span: Some(call.head),
+        }),
+    }
+}
+
+/// The msgpack bytes of the input, which is either binary or, with `--base64` or `--hex`, text
+/// in that encoding. String input is decoded even without either switch, guessing the encoding
+/// unless the guess is ambiguous.
+pub fn input_binary<'a>(
+    call: &EvaluatedCall,
+    input: &'a Value,
+) -> Result<Cow<'a, [u8]>, LabeledError> {
+    let encoding = encoding_flag(call)?;
+    let text = match (input, encoding) {
+        (Value::Binary { val, .. }, None) => return Ok(Cow::Borrowed(val)),
+        (Value::Binary { val, .. }, Some(_)) => {
+            std::str::from_utf8(val).map_err(|e| LabeledError {
+                label: "Invalid text".into(),
+                msg: format!("The input isn't text in the given encoding: {e}"),
+                span: Some(input.span()),
+            })?
+        }
+        (Value::String { val, .. }, _) => val.as_str(),
+        (input, _) => return Ok(Cow::Borrowed(input.as_binary()?)),
+    };
+
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => TextEncoding::detect(text.trim()).ok_or_else(|| LabeledError {
+            label: "Ambiguous text".into(),
+            msg: "The input is valid as both hex and base64, pass --hex or --base64".into(),
+            span: Some(input.span()),
+        })?,
+    };
+    let bytes = encoding.decode(text).map_err(|msg| LabeledError {
+        label: format!("Invalid {}", encoding.name()),
+        msg: format!("Failed to decode the input as {}: {msg}", encoding.name()),
+        span: Some(input.span()),
+    })?;
+    Ok(Cow::Owned(bytes))
+}
+
+/// Output encoded msgpack as binary or, with `--base64` or `--hex`, as text in that encoding.
+pub fn output_binary(
+    call: &EvaluatedCall,
+    bytes: Vec<u8>,
+    span: Span,
+) -> Result<Value, LabeledError> {
+    Ok(match encoding_flag(call)? {
+        None => Value::binary(bytes, span),
+        Some(encoding) => Value::string(encoding.encode(&bytes), span),
+    })
+}
@@ -0,0 +1,23 @@
+//! Tracing of the decisions `from msgpack` and `to msgpack` make, with `--debug`.
+//!
+//! Trace lines go to the plugin's stderr, which nushell shows in the terminal, so that the output
+//! of the command stays the same.
+
+use std::time::Instant;
+
+/// Write a trace line for `command` if tracing is on. The message is only built if it is.
+pub fn log(enabled: bool, command: &str, msg: impl FnOnce() -> String) {
+    if enabled {
+        eprintln!("{command}: {}", msg());
+    }
+}
+
+/// Run a phase of `command`, tracing how long it took.
+pub fn time<T>(enabled: bool, command: &str, phase: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    log(enabled, command, || {
+        format!("{phase} took {:.3?}", start.elapsed())
+    });
+    result
+}
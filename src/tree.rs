@@ -0,0 +1,154 @@
+use nu_ansi_term::{Color, Style};
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::Value;
+
+use crate::wire::{self, Header, Kind, Reader, WireError};
+use crate::{from, len, preview};
+
+/// Render a msgpack binary as an indented tree, one line per value, annotated with its wire type
+/// and encoded size.
+///
+/// Containers nested deeper than `--depth`, or than [from::DEFAULT_MAX_DEPTH] so that the
+/// recursion is bounded, are shown collapsed, and strings longer than `--width` characters are
+/// truncated.
+pub fn tree(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let color = !call.has_flag("no-color")?;
+    let mut renderer = Renderer {
+        lines: vec![],
+        max_depth: call
+            .get_flag("depth")?
+            .unwrap_or(usize::MAX)
+            .min(from::DEFAULT_MAX_DEPTH),
+        width: call.get_flag("width")?.unwrap_or(40),
+        color,
+    };
+
+    let mut reader = Reader::new(data);
+    while !reader.is_empty() {
+        let offset = reader.pos();
+        renderer
+            .value(&mut reader, 0, None)
+            .map_err(|e| LabeledError {
+                label: "Invalid msgpack".into(),
+                msg: format!("{e}\n{}", wire::hex_window(data, offset, 8)),
+                span: Some(input.span()),
+            })?;
+    }
+
+    Ok(Value::string(renderer.lines.join("\n"), call.head))
+}
+
+struct Renderer {
+    lines: Vec<String>,
+
+    /// Depth below which containers are collapsed, where top-level values have depth 0.
+    max_depth: usize,
+
+    /// Maximum number of characters of a string to show.
+    width: usize,
+
+    color: bool,
+}
+
+impl Renderer {
+    /// Render the value at the reader and its children, labelled with its key or index.
+    fn value(
+        &mut self,
+        reader: &mut Reader,
+        depth: usize,
+        label: Option<String>,
+    ) -> Result<(), WireError> {
+        let start = reader.pos();
+        let header = reader.read_header()?;
+        let data = reader.read_data(header.data_len())?;
+
+        // the line of a container goes before its children, but its size is only known after them
+        let line = self.lines.len();
+        self.lines.push(String::new());
+
+        let summary = match header.kind {
+            Kind::Array(len) | Kind::Map(len) => {
+                let noun = match header.kind {
+                    Kind::Map(_) => "entries",
+                    _ => "elements",
+                };
+                if depth < self.max_depth {
+                    for i in 0..len {
+                        let label = match header.kind {
+                            Kind::Map(_) => len::key_name(reader, i)?,
+                            _ => format!("[{i}]"),
+                        };
+                        self.value(reader, depth + 1, Some(label))?;
+                    }
+                    format!("{len} {noun}")
+                } else {
+                    for _ in 0..header.child_count() {
+                        reader.skip_value()?;
+                    }
+                    format!("{len} {noun} …")
+                }
+            }
+            _ => self.scalar(&header, data, reader.input()),
+        };
+
+        let mut text = "  ".repeat(depth);
+        if let Some(label) = label {
+            text.push_str(&self.paint(Color::Green.bold(), &label));
+            text.push_str(": ");
+        }
+        text.push_str(&summary);
+        text.push_str("  ");
+        let annotation = format!("{}, {} bytes", header.type_name(), reader.pos() - start);
+        text.push_str(&self.paint(Style::new().dimmed(), &annotation));
+        self.lines[line] = text;
+        Ok(())
+    }
+
+    /// The colored value of a scalar, or the size of a binary or ext value.
+    fn scalar(&self, header: &Header, data: &[u8], input: &[u8]) -> String {
+        let float = |len: usize| &input[header.offset + 1..header.offset + 1 + len];
+        match header.kind {
+            Kind::Nil => self.paint(Color::DarkGray.normal(), "nil"),
+            Kind::Bool(b) => self.paint(Color::Cyan.normal(), &b.to_string()),
+            Kind::Uint(u) => self.paint(Color::Purple.normal(), &u.to_string()),
+            Kind::Int(i) => self.paint(Color::Purple.normal(), &i.to_string()),
+            Kind::F32 => {
+                let float = f32::from_be_bytes(float(4).try_into().expect("f32 has 4 bytes"));
+                self.paint(Color::Purple.normal(), &float.to_string())
+            }
+            Kind::F64 => {
+                let float = f64::from_be_bytes(float(8).try_into().expect("f64 has 8 bytes"));
+                self.paint(Color::Purple.normal(), &float.to_string())
+            }
+            Kind::Str(_) => {
+                let s = String::from_utf8_lossy(data);
+                let mut quoted = format!("{:?}", s.chars().take(self.width).collect::<String>());
+                if s.chars().count() > self.width {
+                    quoted.push('…');
+                }
+                self.paint(Color::Yellow.normal(), &quoted)
+            }
+            Kind::Bin(len) => {
+                let text = match preview::describe(data) {
+                    Some(format) => format!("<{len} bytes, {format}>"),
+                    None => format!("<{len} bytes>"),
+                };
+                self.paint(Color::Blue.normal(), &text)
+            }
+            Kind::Ext(ext_type, len) => self.paint(
+                Color::Blue.normal(),
+                &format!("<ext type {ext_type}, {len} bytes>"),
+            ),
+            Kind::Array(_) | Kind::Map(_) => unreachable!("containers are rendered by value()"),
+        }
+    }
+
+    fn paint(&self, style: Style, text: &str) -> String {
+        if self.color {
+            style.paint(text).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+}
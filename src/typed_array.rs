@@ -0,0 +1,122 @@
+//! Typed arrays: homogeneous numeric lists packed into a single ext value.
+//!
+//! The ext payload is a single element type byte followed by the packed elements in
+//! little-endian byte order. The element type bytes are borrowed from python's `struct` module:
+//!
+//! | byte | element |
+//! |------|---------|
+//! | `b`  | i8      |
+//! | `B`  | u8      |
+//! | `h`  | i16     |
+//! | `H`  | u16     |
+//! | `i`  | i32     |
+//! | `I`  | u32     |
+//! | `q`  | i64     |
+//! | `Q`  | u64     |
+//! | `f`  | f32     |
+//! | `d`  | f64     |
+
+use nu_plugin::LabeledError;
+use nu_protocol::{Span, Value};
+
+/// Expand a typed array ext payload to a nu list.
+pub fn unpack(data: &[u8]) -> Result<Value, LabeledError> {
+    let span = Span::unknown();
+    let Some((&element_type, data)) = data.split_first() else {
+        return Err(invalid_typed_array("missing element type".into()));
+    };
+
+    macro_rules! unpack_as {
+        ($t:ty, $to_value:expr) => {{
+            const SIZE: usize = std::mem::size_of::<$t>();
+            if !data.len().is_multiple_of(SIZE) {
+                return Err(invalid_typed_array(format!(
+                    "payload length {} is not a multiple of the element size {SIZE}",
+                    data.len()
+                )));
+            }
+            data.chunks_exact(SIZE)
+                .map(|chunk| {
+                    let chunk = <[u8; SIZE]>::try_from(chunk).expect("chunk has correct len");
+                    $to_value(<$t>::from_le_bytes(chunk))
+                })
+                .collect::<Result<Vec<Value>, LabeledError>>()?
+        }};
+    }
+
+    let int = |i: i64| Ok(Value::int(i, span));
+    let float = |f: f64| Ok(Value::float(f, span));
+    let vals = match element_type {
+        b'b' => unpack_as!(i8, |i: i8| int(i.into())),
+        b'B' => unpack_as!(u8, |i: u8| int(i.into())),
+        b'h' => unpack_as!(i16, |i: i16| int(i.into())),
+        b'H' => unpack_as!(u16, |i: u16| int(i.into())),
+        b'i' => unpack_as!(i32, |i: i32| int(i.into())),
+        b'I' => unpack_as!(u32, |i: u32| int(i.into())),
+        b'q' => unpack_as!(i64, int),
+        b'Q' => unpack_as!(u64, |i: u64| {
+            i64::try_from(i)
+                .map(|i| Value::int(i, span))
+                .map_err(|_| invalid_typed_array(format!("element {i} is bigger than i64::MAX")))
+        }),
+        b'f' => unpack_as!(f32, |f: f32| float(f.into())),
+        b'd' => unpack_as!(f64, float),
+        t => {
+            return Err(invalid_typed_array(format!(
+                "unknown element type {:?}",
+                char::from(t)
+            )))
+        }
+    };
+
+    Ok(Value::list(vals, span))
+}
+
+/// Pack a homogeneous list of ints or floats into a typed array ext payload.
+///
+/// Returns `None` if the list has fewer than `min_len` elements, if it isn't homogeneous, or if it
+/// has a non-finite float, which is left to the encoder's `--non-finite` handling.
+pub fn pack(vals: &[Value], min_len: usize) -> Option<Vec<u8>> {
+    if vals.is_empty() || vals.len() < min_len {
+        return None;
+    }
+
+    if let Some(ints) = vals
+        .iter()
+        .map(|v| match v {
+            Value::Int { val, .. } => Some(*val),
+            _ => None,
+        })
+        .collect::<Option<Vec<i64>>>()
+    {
+        // use 32-bit elements if the values allow it
+        let narrow: Option<Vec<i32>> = ints.iter().map(|&i| i32::try_from(i).ok()).collect();
+        let data = match narrow {
+            Some(ints) => pack_as(b'i', ints.iter().map(|i| i.to_le_bytes())),
+            None => pack_as(b'q', ints.iter().map(|i| i.to_le_bytes())),
+        };
+        return Some(data);
+    }
+
+    vals.iter()
+        .map(|v| match v {
+            Value::Float { val, .. } if val.is_finite() => Some(val.to_le_bytes()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|floats| pack_as(b'd', floats.into_iter()))
+}
+
+fn pack_as<const N: usize>(element_type: u8, elements: impl Iterator<Item = [u8; N]>) -> Vec<u8> {
+    let mut data = vec![element_type];
+    elements.for_each(|e| data.extend_from_slice(&e));
+    data
+}
+
+fn invalid_typed_array(msg: String) -> LabeledError {
+    LabeledError {
+        label: "Invalid typed array".into(),
+        msg: format!("Failed to unpack typed array: {msg}"),
+        span: None,
+    }
+}
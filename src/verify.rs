@@ -0,0 +1,119 @@
+//! Round-trip checks of msgpack binaries, e.g. to audit producers for canonical encoding.
+
+use nu_plugin::{EvaluatedCall, LabeledError};
+use nu_protocol::{Record, Value};
+
+use crate::from::{self, DecodeOptions};
+use crate::into::{self, EncodeOptions};
+use crate::wire::{Header, Kind, Node, Reader, Role, Walker};
+
+/// Decode a msgpack binary, encode the result again, and report whether that gives the same bytes.
+///
+/// If it doesn't, the report names the offset and path of the first value that came out
+/// differently, and why.
+pub fn verify(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let data = input.as_binary()?;
+    let span = call.head;
+    let canonical = call.has_flag("canonical")?;
+
+    let value = from::decode(data, &DecodeOptions::default())?;
+    let opts = EncodeOptions {
+        canonical,
+        ..Default::default()
+    };
+    let encoded = into::to_msgpack(&value, &opts)?;
+
+    let mut record = Record::new();
+    record.push("matches", Value::bool(encoded == data, span));
+    record.push("bytes", Value::filesize(data.len() as i64, span));
+    record.push(
+        "reencoded_bytes",
+        Value::filesize(encoded.len() as i64, span),
+    );
+
+    let mismatch = data
+        .iter()
+        .zip(&encoded)
+        .position(|(a, b)| a != b)
+        .or_else(|| (data.len() != encoded.len()).then(|| data.len().min(encoded.len())))
+        .map(|offset| first_difference(data, &encoded, offset, canonical));
+    match mismatch {
+        Some(mismatch) => {
+            record.push("offset", Value::int(mismatch.offset as i64, span));
+            record.push("path", Value::string(mismatch.path, span));
+            record.push("reason", Value::string(mismatch.reason, span));
+        }
+        None => {
+            record.push("offset", Value::nothing(span));
+            record.push("path", Value::nothing(span));
+            record.push("reason", Value::nothing(span));
+        }
+    }
+
+    Ok(Value::record(record, span))
+}
+
+struct Mismatch {
+    /// Offset of the value that came out differently.
+    offset: usize,
+    path: String,
+    reason: String,
+}
+
+/// Explain why the re-encoded bytes differ from the original ones from byte `diff` on.
+fn first_difference(original: &[u8], encoded: &[u8], diff: usize, canonical: bool) -> Mismatch {
+    // The value containing the first differing byte is the last one that starts at or before it.
+    // Everything before it is the same in both, so its re-encoded header starts at the same offset.
+    let mut found: Option<(Node, String)> = None;
+    let mut walker = Walker::new(Reader::new(original));
+    while let Some(Ok(node)) = walker.next() {
+        if node.header.offset > diff {
+            break;
+        }
+        let mut path = walker.path().to_string();
+        if let Role::Key(_) = node.role {
+            path = format!("key {} of {path}", node.key_name());
+        }
+        found = Some((node, path));
+    }
+
+    let (node, path) = found.expect("the top-level value starts at byte 0");
+
+    let old = node.header;
+    let new = Reader::at(encoded, old.offset).read_header().ok();
+    let reason = match new {
+        _ if old.minimal_marker().is_some() => format!(
+            "non-minimal encoding: {} used where {} suffices",
+            old.type_name(),
+            old.minimal_marker().expect("checked by the guard")
+        ),
+        _ if canonical && matches!(node.role, Role::Key(_)) => {
+            "map keys are not sorted by their encoded bytes".into()
+        }
+        Some(new) => describe(&old, &new),
+        None => format!("{} was dropped by the round trip", old.type_name()),
+    };
+
+    Mismatch {
+        offset: old.offset,
+        path,
+        reason,
+    }
+}
+
+/// Describe how a value's header changed in the round trip.
+fn describe(old: &Header, new: &Header) -> String {
+    match (old.kind, new.kind) {
+        (Kind::Map(old_len), Kind::Map(new_len)) if old_len != new_len => {
+            format!("map of {old_len} entries came back with {new_len}, it has duplicate keys")
+        }
+        (Kind::F32, Kind::F64) => "float32 is always re-encoded as float64".into(),
+        (Kind::F32 | Kind::F64, Kind::F32 | Kind::F64) => {
+            "NaN is re-encoded with different bits".into()
+        }
+        _ if old.type_name() != new.type_name() => {
+            format!("{} is re-encoded as {}", old.type_name(), new.type_name())
+        }
+        _ => format!("{} is re-encoded with different contents", old.type_name()),
+    }
+}
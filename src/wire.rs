@@ -0,0 +1,701 @@
+//! Wire-level msgpack reading.
+//!
+//! These types read the headers of msgpack values (marker, lengths and scalars) without decoding
+//! the values themselves, which lets commands inspect and skip over parts of a document cheaply.
+
+use std::fmt;
+use std::io::{self, Read};
+
+use rmp::Marker;
+
+use crate::path::{Path, PathSegment};
+
+/// The header of a msgpack value.
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    /// Byte offset of the marker.
+    pub offset: usize,
+
+    pub marker: Marker,
+
+    pub kind: Kind,
+}
+
+/// What a [Header] says about its value.
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Nil,
+    Bool(bool),
+    Uint(u64),
+    Int(i64),
+    F32,
+    F64,
+
+    /// A string of the given length in bytes.
+    Str(u32),
+
+    /// A binary of the given length in bytes.
+    Bin(u32),
+
+    /// An array of the given number of elements.
+    Array(u32),
+
+    /// A map of the given number of entries.
+    Map(u32),
+
+    /// An ext value of the given type and length in bytes.
+    Ext(i8, u32),
+}
+
+/// An error while reading msgpack.
+#[derive(Clone, Copy, Debug)]
+pub struct WireError {
+    /// Byte offset at which the error occurred.
+    pub offset: usize,
+
+    pub kind: WireErrorKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum WireErrorKind {
+    /// The input ended while `needed` more bytes were expected.
+    Truncated { needed: usize },
+
+    /// Encountered the marker 0xc1, which the spec reserves as never used.
+    ReservedMarker,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            WireErrorKind::Truncated { needed } => write!(
+                f,
+                "unexpected end of input at byte {}, expected {needed} more bytes",
+                self.offset
+            ),
+            WireErrorKind::ReservedMarker => {
+                write!(f, "invalid marker 0xc1 at byte {}", self.offset)
+            }
+        }
+    }
+}
+
+impl Kind {
+    /// Name of the msgpack type family, regardless of the marker, e.g. `str` for a str8.
+    pub fn family(&self) -> &'static str {
+        match self {
+            Kind::Nil => "nil",
+            Kind::Bool(_) => "bool",
+            Kind::Uint(_) | Kind::Int(_) => "int",
+            Kind::F32 | Kind::F64 => "float",
+            Kind::Str(_) => "str",
+            Kind::Bin(_) => "bin",
+            Kind::Array(_) => "array",
+            Kind::Map(_) => "map",
+            Kind::Ext(..) => "ext",
+        }
+    }
+}
+
+impl Header {
+    /// Number of payload bytes following the header, i.e. the length of a str, bin or ext.
+    pub fn data_len(&self) -> usize {
+        match self.kind {
+            Kind::Str(len) | Kind::Bin(len) | Kind::Ext(_, len) => len as usize,
+            _ => 0,
+        }
+    }
+
+    /// Number of values nested directly in this value, counting both keys and values of maps.
+    pub fn child_count(&self) -> u64 {
+        match self.kind {
+            Kind::Array(len) => len.into(),
+            Kind::Map(len) => u64::from(len) * 2,
+            _ => 0,
+        }
+    }
+
+    /// Wire type name of the marker, as used in the msgpack spec.
+    pub fn type_name(&self) -> &'static str {
+        marker_name(self.marker)
+    }
+
+    /// The name of the smallest marker able to encode this value, if the header uses a
+    /// bigger one than that.
+    pub fn minimal_marker(&self) -> Option<&'static str> {
+        let minimal = match self.kind {
+            Kind::Nil | Kind::Bool(_) | Kind::F32 | Kind::F64 => return None,
+            Kind::Uint(u) => uint_marker(u),
+            Kind::Int(i) if i >= 0 => uint_marker(i as u64),
+            Kind::Int(i) if i >= -32 => Marker::FixNeg(i as i8),
+            Kind::Int(i) if i >= i8::MIN.into() => Marker::I8,
+            Kind::Int(i) if i >= i16::MIN.into() => Marker::I16,
+            Kind::Int(i) if i >= i32::MIN.into() => Marker::I32,
+            Kind::Int(_) => Marker::I64,
+            Kind::Str(len) if len < 32 => Marker::FixStr(len as u8),
+            Kind::Str(len) => sized_marker(len, [Marker::Str8, Marker::Str16, Marker::Str32]),
+            Kind::Bin(len) => sized_marker(len, [Marker::Bin8, Marker::Bin16, Marker::Bin32]),
+            Kind::Array(len) if len < 16 => Marker::FixArray(len as u8),
+            Kind::Array(len) => {
+                sized_marker(len, [Marker::Array16, Marker::Array16, Marker::Array32])
+            }
+            Kind::Map(len) if len < 16 => Marker::FixMap(len as u8),
+            Kind::Map(len) => sized_marker(len, [Marker::Map16, Marker::Map16, Marker::Map32]),
+            Kind::Ext(_, 1) => Marker::FixExt1,
+            Kind::Ext(_, 2) => Marker::FixExt2,
+            Kind::Ext(_, 4) => Marker::FixExt4,
+            Kind::Ext(_, 8) => Marker::FixExt8,
+            Kind::Ext(_, 16) => Marker::FixExt16,
+            Kind::Ext(_, len) => sized_marker(len, [Marker::Ext8, Marker::Ext16, Marker::Ext32]),
+        };
+
+        (marker_name(minimal) != marker_name(self.marker)).then_some(marker_name(minimal))
+    }
+}
+
+fn uint_marker(u: u64) -> Marker {
+    match u {
+        0..=0x7f => Marker::FixPos(u as u8),
+        0x80..=0xff => Marker::U8,
+        0x100..=0xffff => Marker::U16,
+        0x1_0000..=0xffff_ffff => Marker::U32,
+        _ => Marker::U64,
+    }
+}
+
+/// Pick the 8, 16 or 32 bit length variant of a marker.
+fn sized_marker(len: u32, [m8, m16, m32]: [Marker; 3]) -> Marker {
+    match len {
+        0..=0xff => m8,
+        0x100..=0xffff => m16,
+        _ => m32,
+    }
+}
+
+/// Wire type name of a marker, as used in the msgpack spec.
+pub fn marker_name(marker: Marker) -> &'static str {
+    match marker {
+        Marker::FixPos(_) => "positive fixint",
+        Marker::FixNeg(_) => "negative fixint",
+        Marker::Null => "nil",
+        Marker::True => "true",
+        Marker::False => "false",
+        Marker::U8 => "uint8",
+        Marker::U16 => "uint16",
+        Marker::U32 => "uint32",
+        Marker::U64 => "uint64",
+        Marker::I8 => "int8",
+        Marker::I16 => "int16",
+        Marker::I32 => "int32",
+        Marker::I64 => "int64",
+        Marker::F32 => "float32",
+        Marker::F64 => "float64",
+        Marker::FixStr(_) => "fixstr",
+        Marker::Str8 => "str8",
+        Marker::Str16 => "str16",
+        Marker::Str32 => "str32",
+        Marker::Bin8 => "bin8",
+        Marker::Bin16 => "bin16",
+        Marker::Bin32 => "bin32",
+        Marker::FixArray(_) => "fixarray",
+        Marker::Array16 => "array16",
+        Marker::Array32 => "array32",
+        Marker::FixMap(_) => "fixmap",
+        Marker::Map16 => "map16",
+        Marker::Map32 => "map32",
+        Marker::FixExt1 => "fixext1",
+        Marker::FixExt2 => "fixext2",
+        Marker::FixExt4 => "fixext4",
+        Marker::FixExt8 => "fixext8",
+        Marker::FixExt16 => "fixext16",
+        Marker::Ext8 => "ext8",
+        Marker::Ext16 => "ext16",
+        Marker::Ext32 => "ext32",
+        Marker::Reserved => "reserved",
+    }
+}
+
+/// Hex dump of up to `context` bytes on either side of `offset`, with the byte at `offset`
+/// in brackets, e.g. `bytes 8..13: 92 a3 [c1] 6f 01`.
+pub fn hex_window(data: &[u8], offset: usize, context: usize) -> String {
+    let start = offset.saturating_sub(context).min(data.len());
+    let end = offset.saturating_add(context + 1).min(data.len());
+    let bytes: Vec<String> = (start..end)
+        .map(|i| match i == offset {
+            true => format!("[{:02x}]", data[i]),
+            false => format!("{:02x}", data[i]),
+        })
+        .collect();
+    format!("bytes {start}..{end}: {}", bytes.join(" "))
+}
+
+/// Reads msgpack headers and payloads from a byte slice.
+#[derive(Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    /// Create a reader positioned at byte offset `pos` of `data`.
+    pub fn at(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    /// Current byte offset into the input.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Number of unread bytes.
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// The whole input, including the bytes that have already been read.
+    pub fn input(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Read the next `len` bytes.
+    pub fn read_data(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        if len > self.remaining() {
+            return Err(WireError {
+                offset: self.pos,
+                kind: WireErrorKind::Truncated {
+                    needed: len - self.remaining(),
+                },
+            });
+        }
+
+        let data = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        let data = self.read_data(N)?;
+        Ok(data.try_into().expect("slice has correct len"))
+    }
+
+    /// Read the header of the next value.
+    ///
+    /// On error, the reader is left at the start of the header.
+    pub fn read_header(&mut self) -> Result<Header, WireError> {
+        let offset = self.pos;
+        let header = self.read_header_inner();
+        if header.is_err() {
+            self.pos = offset;
+        }
+        header
+    }
+
+    fn read_header_inner(&mut self) -> Result<Header, WireError> {
+        let offset = self.pos;
+        let [byte] = self.read_array()?;
+        let marker = Marker::from_u8(byte);
+
+        let kind = match marker {
+            Marker::FixPos(u) => Kind::Uint(u.into()),
+            Marker::FixNeg(i) => Kind::Int(i.into()),
+            Marker::Null => Kind::Nil,
+            Marker::True => Kind::Bool(true),
+            Marker::False => Kind::Bool(false),
+            Marker::U8 => Kind::Uint(u8::from_be_bytes(self.read_array()?).into()),
+            Marker::U16 => Kind::Uint(u16::from_be_bytes(self.read_array()?).into()),
+            Marker::U32 => Kind::Uint(u32::from_be_bytes(self.read_array()?).into()),
+            Marker::U64 => Kind::Uint(u64::from_be_bytes(self.read_array()?)),
+            Marker::I8 => Kind::Int(i8::from_be_bytes(self.read_array()?).into()),
+            Marker::I16 => Kind::Int(i16::from_be_bytes(self.read_array()?).into()),
+            Marker::I32 => Kind::Int(i32::from_be_bytes(self.read_array()?).into()),
+            Marker::I64 => Kind::Int(i64::from_be_bytes(self.read_array()?)),
+            Marker::F32 => {
+                self.read_array::<4>()?;
+                Kind::F32
+            }
+            Marker::F64 => {
+                self.read_array::<8>()?;
+                Kind::F64
+            }
+            Marker::FixStr(len) => Kind::Str(len.into()),
+            Marker::Str8 => Kind::Str(self.read_len8()?),
+            Marker::Str16 => Kind::Str(self.read_len16()?),
+            Marker::Str32 => Kind::Str(self.read_len32()?),
+            Marker::Bin8 => Kind::Bin(self.read_len8()?),
+            Marker::Bin16 => Kind::Bin(self.read_len16()?),
+            Marker::Bin32 => Kind::Bin(self.read_len32()?),
+            Marker::FixArray(len) => Kind::Array(len.into()),
+            Marker::Array16 => Kind::Array(self.read_len16()?),
+            Marker::Array32 => Kind::Array(self.read_len32()?),
+            Marker::FixMap(len) => Kind::Map(len.into()),
+            Marker::Map16 => Kind::Map(self.read_len16()?),
+            Marker::Map32 => Kind::Map(self.read_len32()?),
+            Marker::FixExt1 => Kind::Ext(self.read_ext_type()?, 1),
+            Marker::FixExt2 => Kind::Ext(self.read_ext_type()?, 2),
+            Marker::FixExt4 => Kind::Ext(self.read_ext_type()?, 4),
+            Marker::FixExt8 => Kind::Ext(self.read_ext_type()?, 8),
+            Marker::FixExt16 => Kind::Ext(self.read_ext_type()?, 16),
+            Marker::Ext8 => {
+                let len = self.read_len8()?;
+                Kind::Ext(self.read_ext_type()?, len)
+            }
+            Marker::Ext16 => {
+                let len = self.read_len16()?;
+                Kind::Ext(self.read_ext_type()?, len)
+            }
+            Marker::Ext32 => {
+                let len = self.read_len32()?;
+                Kind::Ext(self.read_ext_type()?, len)
+            }
+            Marker::Reserved => {
+                return Err(WireError {
+                    offset,
+                    kind: WireErrorKind::ReservedMarker,
+                })
+            }
+        };
+
+        Ok(Header {
+            offset,
+            marker,
+            kind,
+        })
+    }
+
+    fn read_len8(&mut self) -> Result<u32, WireError> {
+        Ok(u8::from_be_bytes(self.read_array()?).into())
+    }
+
+    fn read_len16(&mut self) -> Result<u32, WireError> {
+        Ok(u16::from_be_bytes(self.read_array()?).into())
+    }
+
+    fn read_len32(&mut self) -> Result<u32, WireError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_ext_type(&mut self) -> Result<i8, WireError> {
+        Ok(i8::from_be_bytes(self.read_array()?))
+    }
+
+    /// Skip over the next value, including any nested values.
+    ///
+    /// Returns the header of the skipped value.
+    pub fn skip_value(&mut self) -> Result<Header, WireError> {
+        let start = self.pos;
+        let result = self.skip_value_inner();
+        if result.is_err() {
+            self.pos = start;
+        }
+        result
+    }
+
+    fn skip_value_inner(&mut self) -> Result<Header, WireError> {
+        let header = self.read_header()?;
+        self.read_data(header.data_len())?;
+
+        let mut pending = header.child_count();
+        while pending > 0 {
+            let child = self.read_header()?;
+            self.read_data(child.data_len())?;
+            pending += child.child_count();
+            pending -= 1;
+        }
+
+        Ok(header)
+    }
+}
+
+/// Reads msgpack headers and payloads from an [io::Read], for inputs too big to hold in memory.
+///
+/// Invalid and truncated msgpack is reported as [io::ErrorKind::InvalidData] errors.
+pub struct StreamReader<R> {
+    inner: R,
+    pos: usize,
+
+    /// The bytes of the last header read, marker included.
+    header: [u8; 9],
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(inner: R) -> Self {
+        StreamReader {
+            inner,
+            pos: 0,
+            header: [0; 9],
+        }
+    }
+
+    /// Current byte offset into the input.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Read the header of the next value, or `None` at the end of the input.
+    pub fn read_header(&mut self) -> io::Result<Option<Header>> {
+        let offset = self.pos;
+        let mut marker = [0];
+        if self.inner.read(&mut marker)? == 0 {
+            return Ok(None);
+        }
+        self.pos += 1;
+
+        let len = 1 + header_extra_len(Marker::from_u8(marker[0]));
+        self.header[0] = marker[0];
+        self.fill(1..len)?;
+
+        let mut header = Reader::new(&self.header[..len])
+            .read_header()
+            .map_err(|mut e| {
+                e.offset += offset;
+                invalid_data(e)
+            })?;
+        header.offset = offset;
+        Ok(Some(header))
+    }
+
+    /// The value of the float whose header was read last.
+    pub fn float(&self, header: &Header) -> f64 {
+        match header.kind {
+            Kind::F32 => f32::from_be_bytes(self.header[1..5].try_into().expect("4 bytes")).into(),
+            _ => f64::from_be_bytes(self.header[1..9].try_into().expect("8 bytes")),
+        }
+    }
+
+    /// Read the next `len` bytes into `buf`, replacing its contents.
+    pub fn read_data(&mut self, len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.clear();
+        let read = (&mut self.inner).take(len as u64).read_to_end(buf)?;
+        self.pos += read;
+        if read < len {
+            return Err(self.truncated(len - read));
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, range: std::ops::Range<usize>) -> io::Result<()> {
+        let len = range.len();
+        match self.inner.read_exact(&mut self.header[range]) {
+            Ok(()) => {
+                self.pos += len;
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(self.truncated(len)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn truncated(&self, needed: usize) -> io::Error {
+        invalid_data(WireError {
+            offset: self.pos,
+            kind: WireErrorKind::Truncated { needed },
+        })
+    }
+}
+
+/// Number of header bytes that follow a marker, e.g. the length of a str16.
+fn header_extra_len(marker: Marker) -> usize {
+    match marker {
+        Marker::U8 | Marker::I8 | Marker::Str8 | Marker::Bin8 => 1,
+        Marker::FixExt1 | Marker::FixExt2 | Marker::FixExt4 | Marker::FixExt8 => 1,
+        Marker::FixExt16 => 1,
+        Marker::U16 | Marker::I16 | Marker::Str16 | Marker::Bin16 => 2,
+        Marker::Array16 | Marker::Map16 | Marker::Ext8 => 2,
+        Marker::Ext16 => 3,
+        Marker::U32 | Marker::I32 | Marker::F32 | Marker::Str32 | Marker::Bin32 => 4,
+        Marker::Array32 | Marker::Map32 => 4,
+        Marker::Ext32 => 5,
+        Marker::U64 | Marker::I64 | Marker::F64 => 8,
+        _ => 0,
+    }
+}
+
+fn invalid_data(e: WireError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Where a [Node] sits in its parent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// A top-level value.
+    Root,
+
+    /// The element at the given index of an array.
+    Element(u32),
+
+    /// The key of the map entry at the given index.
+    Key(u32),
+
+    /// The value of the map entry at the given index.
+    Value(u32),
+}
+
+/// A value encountered by a [Walker].
+#[derive(Clone, Copy, Debug)]
+pub struct Node<'a> {
+    pub header: Header,
+
+    /// Payload of a str, bin or ext value, empty for other values.
+    pub data: &'a [u8],
+
+    /// Nesting depth, where top-level values have depth 0.
+    pub depth: usize,
+
+    pub role: Role,
+}
+
+impl Node<'_> {
+    /// The node as a map key in a path, for keys that aren't strings e.g. `5` or `<fixarray>`.
+    pub fn key_name(&self) -> String {
+        match self.header.kind {
+            Kind::Str(_) => String::from_utf8_lossy(self.data).into_owned(),
+            Kind::Uint(u) => u.to_string(),
+            Kind::Int(i) => i.to_string(),
+            Kind::Bool(b) => b.to_string(),
+            _ => format!("<{}>", self.header.type_name()),
+        }
+    }
+}
+
+struct Frame {
+    is_map: bool,
+
+    /// Number of child values, counting both keys and values of maps.
+    total: u64,
+
+    /// Index of the next child value.
+    next: u64,
+
+    /// Whether a path segment has been pushed for the current child.
+    pushed: bool,
+
+    /// Path segment for the value of the current map entry, taken from its key.
+    key: Option<String>,
+}
+
+/// Iterates over all values of a single top-level msgpack value in document order, keeping
+/// track of their [Path]. Containers are yielded before their children.
+pub struct Walker<'a> {
+    reader: Reader<'a>,
+    stack: Vec<Frame>,
+    path: Path,
+    started: bool,
+    failed: bool,
+}
+
+impl<'a> Walker<'a> {
+    /// Walk the value that `reader` is positioned at.
+    pub fn new(reader: Reader<'a>) -> Self {
+        Walker {
+            reader,
+            stack: vec![],
+            path: Path::default(),
+            started: false,
+            failed: false,
+        }
+    }
+
+    /// The underlying reader, positioned after the last yielded header and payload.
+    pub fn reader(&self) -> &Reader<'a> {
+        &self.reader
+    }
+
+    /// Path of the last yielded node.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<'a> Iterator for Walker<'a> {
+    type Item = Result<Node<'a>, WireError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        // leave containers whose children have all been visited
+        while let Some(frame) = self.stack.last() {
+            if frame.next < frame.total {
+                break;
+            }
+            if frame.pushed {
+                self.path.pop();
+            }
+            self.stack.pop();
+        }
+
+        if self.stack.is_empty() && self.started {
+            return None;
+        }
+        self.started = true;
+
+        let depth = self.stack.len();
+        let role = match self.stack.last_mut() {
+            None => Role::Root,
+            Some(frame) => {
+                if frame.pushed {
+                    self.path.pop();
+                    frame.pushed = false;
+                }
+
+                let i = frame.next;
+                frame.next += 1;
+                if !frame.is_map {
+                    self.path.push(PathSegment::Index(i as usize));
+                    frame.pushed = true;
+                    Role::Element(i as u32)
+                } else if i % 2 == 0 {
+                    Role::Key((i / 2) as u32)
+                } else {
+                    let key = frame.key.take().unwrap_or_default();
+                    self.path.push(PathSegment::Key(key));
+                    frame.pushed = true;
+                    Role::Value((i / 2) as u32)
+                }
+            }
+        };
+
+        let node = self.reader.read_header().and_then(|header| {
+            let data = self.reader.read_data(header.data_len())?;
+            Ok(Node {
+                header,
+                data,
+                depth,
+                role,
+            })
+        });
+
+        let node = match node {
+            Ok(node) => node,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        };
+
+        if let (Role::Key(_), Some(frame)) = (role, self.stack.last_mut()) {
+            frame.key = Some(node.key_name());
+        }
+
+        if node.header.child_count() > 0 {
+            self.stack.push(Frame {
+                is_map: matches!(node.header.kind, Kind::Map(_)),
+                total: node.header.child_count(),
+                next: 0,
+                pushed: false,
+                key: None,
+            });
+        }
+
+        Some(Ok(node))
+    }
+}